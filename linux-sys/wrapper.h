@@ -3,14 +3,26 @@
 
 #include <stddef.h>
 
+#include <linux/auxvec.h>
+#include <linux/elf.h>
 #include <linux/errno.h>
+#include <linux/eventpoll.h>
 #include <linux/fcntl.h>
 #include <linux/fs.h>
+#include <linux/futex.h>
 #include <linux/in.h>
+#include <linux/io_uring.h>
+#include <linux/memfd.h>
+#include <linux/mempolicy.h>
+#include <linux/mman.h>
 #include <linux/net.h>
+#include <linux/poll.h>
 #include <linux/random.h>
 #include <linux/signal.h>
+#include <linux/signalfd.h>
 #include <linux/socket.h>
 #include <linux/time.h>
+#include <linux/timerfd.h>
+#include <linux/userfaultfd.h>
 
 #endif // SYSCALLS_RS_WRAPPER_H
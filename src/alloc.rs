@@ -0,0 +1,336 @@
+//! A `GlobalAlloc` built directly on `mmap(2)`, so binaries that don't link libc can still use `Vec` and `Box`.
+//!
+//! Small allocations are served from per size class free lists, carved out of slabs that are never returned to the kernel.
+//! Everything bigger than [`MAX_SMALL`](constant.MAX_SMALL.html) gets its own mapping, so it can be resized with `mremap(2)`
+//! without copying. `brk(2)` isn't used as it can't be shared safely with anyone else that moves the break (e.g. libc's malloc).
+//!
+//! ```rust
+//! use syscalls_rs::alloc::MmapAlloc;
+//!
+//! #[global_allocator]
+//! static GLOBAL: MmapAlloc = MmapAlloc::new();
+//!
+//! let v: Vec<u32> = (0..1000).collect();
+//! assert_eq!(v[999], 999);
+//! ```
+use crate::arch::PAGE_SIZE;
+use crate::mman::{mmap, mremap, munmap, page_align, MapFlags, ProtFlags, RemapFlags};
+use crate::static_assert;
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::UnsafeCell;
+use std::hint::spin_loop;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The smallest block, big enough for the free list pointer.
+const MIN_SMALL: usize = 16;
+/// Allocations bigger than this get their own mapping.
+pub const MAX_SMALL: usize = 2048;
+const NUM_CLASSES: usize = 8; // 16, 32, .., 2048.
+/// Size classes are refilled a slab at a time.
+const SLAB_SIZE: usize = 64 * 1024;
+
+static_assert!(MIN_SMALL << (NUM_CLASSES - 1) == MAX_SMALL);
+static_assert!(SLAB_SIZE & (PAGE_SIZE - 1) == 0);
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+// A spin lock is enough as it's only held for a couple of pointer writes (and a `mmap(2)` on refill).
+struct SizeClass {
+    locked: AtomicBool,
+    head: UnsafeCell<*mut FreeBlock>,
+}
+
+impl SizeClass {
+    const fn new() -> Self {
+        SizeClass {
+            locked: AtomicBool::new(false),
+            head: UnsafeCell::new(ptr::null_mut()),
+        }
+    }
+
+    fn lock(&self) -> ClassGuard<'_> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        ClassGuard(self)
+    }
+}
+
+struct ClassGuard<'a>(&'a SizeClass);
+
+impl ClassGuard<'_> {
+    unsafe fn pop(&mut self, block_size: usize) -> *mut u8 {
+        let head = self.0.head.get();
+        if (*head).is_null() && !self.refill(block_size) {
+            return ptr::null_mut();
+        }
+        let block = *head;
+        *head = (*block).next;
+        block as *mut u8
+    }
+
+    unsafe fn push(&mut self, block: *mut u8) {
+        let head = self.0.head.get();
+        let block = block as *mut FreeBlock;
+        (*block).next = *head;
+        *head = block;
+    }
+
+    // Every block is aligned to its size as the slab is page aligned.
+    unsafe fn refill(&mut self, block_size: usize) -> bool {
+        let slab = match map_pages(SLAB_SIZE) {
+            Some(slab) => slab,
+            None => return false,
+        };
+        for offset in (0..SLAB_SIZE).step_by(block_size).rev() {
+            self.push(slab.add(offset));
+        }
+        true
+    }
+}
+
+impl Drop for ClassGuard<'_> {
+    fn drop(&mut self) {
+        self.0.locked.store(false, Ordering::Release);
+    }
+}
+
+unsafe fn map_pages(len: usize) -> Option<*mut u8> {
+    let prot = ProtFlags::new().read().write();
+    let flags = MapFlags::new().private().anonymous();
+    mmap::<i32>(ptr::null_mut(), len, prot, flags, None, 0).ok()
+}
+
+// The index of the smallest size class that fits both the size and the alignment.
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_SMALL);
+    if size > MAX_SMALL {
+        return None;
+    }
+    let class = size.next_power_of_two();
+    Some((class.trailing_zeros() - MIN_SMALL.trailing_zeros()) as usize)
+}
+
+fn class_size(class: usize) -> usize {
+    MIN_SMALL << class
+}
+
+/// A thread safe allocator that only uses `mmap(2)`, `munmap(2)` and `mremap(2)`.
+pub struct MmapAlloc {
+    classes: [SizeClass; NUM_CLASSES],
+}
+
+unsafe impl Sync for MmapAlloc {}
+
+impl MmapAlloc {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: SizeClass = SizeClass::new();
+        MmapAlloc {
+            classes: [EMPTY; NUM_CLASSES],
+        }
+    }
+
+    unsafe fn alloc_large(&self, layout: Layout) -> *mut u8 {
+        let len = page_align(layout.size());
+        if layout.align() <= PAGE_SIZE {
+            return map_pages(len).unwrap_or(ptr::null_mut());
+        }
+        // Map enough to find an aligned address, then unmap the excess on both sides.
+        let padded = match len.checked_add(layout.align() - PAGE_SIZE) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let base = match map_pages(padded) {
+            Some(base) => base,
+            None => return ptr::null_mut(),
+        };
+        let head = base.align_offset(layout.align());
+        let tail = padded - head - len;
+        if head > 0 {
+            munmap(base, head).ok();
+        }
+        if tail > 0 {
+            munmap(base.add(head + len), tail).ok();
+        }
+        base.add(head)
+    }
+}
+
+impl Default for MmapAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for MmapAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match size_class(&layout) {
+            Some(class) => self.classes[class].lock().pop(class_size(class)),
+            None => self.alloc_large(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(&layout) {
+            Some(class) => self.classes[class].lock().push(ptr),
+            None => {
+                munmap(ptr, page_align(layout.size())).ok();
+            }
+        }
+    }
+
+    // Fresh mappings are already zeroed.
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() && size_class(&layout).is_some() {
+            ptr::write_bytes(ptr, 0, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (size_class(&layout), size_class(&new_layout)) {
+            (Some(old), Some(new)) if old == new => return ptr,
+            // `mremap(2)` only keeps page alignment.
+            (None, None) if layout.align() <= PAGE_SIZE => {
+                let old_len = page_align(layout.size());
+                let new_len = page_align(new_size);
+                if old_len == new_len {
+                    return ptr;
+                }
+                return mremap(ptr, old_len, new_len, RemapFlags::MayMove)
+                    .unwrap_or(ptr::null_mut());
+            }
+            _ => (),
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    static ALLOC: MmapAlloc = MmapAlloc::new();
+
+    #[test]
+    fn test_size_classes() {
+        let class = |size, align| size_class(&Layout::from_size_align(size, align).unwrap());
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(16, 8), Some(0));
+        assert_eq!(class(17, 1), Some(1));
+        assert_eq!(class(8, 64), Some(2));
+        assert_eq!(class(2048, 8), Some(NUM_CLASSES - 1));
+        assert_eq!(class(2049, 8), None);
+        assert_eq!(class(8, 4096), None);
+    }
+
+    #[test]
+    fn test_alloc_alignment() {
+        for &size in &[1, 24, 100, 2048, 5000, 1 << 20] {
+            for &align in &[1, 8, 64, 4096, 1 << 16] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                unsafe {
+                    let ptr = ALLOC.alloc(layout);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0);
+                    ptr::write_bytes(ptr, 0xaa, size);
+                    ALLOC.dealloc(ptr, layout);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_alloc_zeroed() {
+        for &size in &[32, 1 << 16] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            unsafe {
+                // Dirty a block so it's reused dirty.
+                let ptr = ALLOC.alloc(layout);
+                ptr::write_bytes(ptr, 0xff, size);
+                ALLOC.dealloc(ptr, layout);
+                let ptr = ALLOC.alloc_zeroed(layout);
+                assert!((0..size).all(|i| *ptr.add(i) == 0));
+                ALLOC.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_realloc() {
+        unsafe {
+            let layout = Layout::from_size_align(10, 1).unwrap();
+            let ptr = ALLOC.alloc(layout);
+            ptr::copy_nonoverlapping(b"0123456789".as_ptr(), ptr, 10);
+            // Same size class.
+            assert_eq!(ALLOC.realloc(ptr, layout, 16), ptr);
+
+            // Small to large.
+            let layout = Layout::from_size_align(16, 1).unwrap();
+            let ptr = ALLOC.realloc(ptr, layout, 3 * PAGE_SIZE);
+            assert_eq!(ptr as usize % PAGE_SIZE, 0);
+            *ptr.add(3 * PAGE_SIZE - 1) = 7;
+
+            // Large to large through `mremap(2)`.
+            let layout = Layout::from_size_align(3 * PAGE_SIZE, 1).unwrap();
+            let ptr = ALLOC.realloc(ptr, layout, 1 << 20);
+            *ptr.add((1 << 20) - 1) = 8;
+            assert_eq!(*ptr.add(3 * PAGE_SIZE - 1), 7);
+
+            // And back to small.
+            let layout = Layout::from_size_align(1 << 20, 1).unwrap();
+            let ptr = ALLOC.realloc(ptr, layout, 10);
+            assert_eq!(std::slice::from_raw_parts(ptr, 10), b"0123456789");
+            ALLOC.dealloc(ptr, Layout::from_size_align(10, 1).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        let barrier = Arc::new(std::sync::Barrier::new(4));
+        let handles: Vec<_> = (0..4usize)
+            .map(|t| {
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    let layout = Layout::from_size_align(48, 8).unwrap();
+                    let ptrs: Vec<_> = (0..10_000)
+                        .map(|i| unsafe {
+                            let ptr = ALLOC.alloc(layout) as *mut usize;
+                            ptr.write(t * 10_000 + i);
+                            ptr
+                        })
+                        .collect();
+                    for (i, &ptr) in ptrs.iter().enumerate() {
+                        unsafe {
+                            assert_eq!(ptr.read(), t * 10_000 + i);
+                            ALLOC.dealloc(ptr as *mut u8, layout);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
@@ -28,8 +28,8 @@ macro_rules! syscall {
     ($n:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr, $a5:expr$(,)?) => {
         crate::arch::syscall5($n.into(), $a1, $a2, $a3, $a4, $a5)
     };
-    ($n:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr, $a6:expr$(,)?) => {
-        crate::arch::syscall6($n.into(), $a1, $a2, $a3, $a4, $a6)
+    ($n:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr, $a5:expr, $a6:expr$(,)?) => {
+        crate::arch::syscall6($n.into(), $a1, $a2, $a3, $a4, $a5, $a6)
     };
 }
 
@@ -1,5 +1,5 @@
 #![allow(dead_code)]
-use std::arch::asm;
+use std::arch::{asm, global_asm};
 
 #[inline]
 #[rustfmt::skip]
@@ -57,6 +57,69 @@ pub unsafe fn syscall6(mut n: isize, a1: isize, a2: isize, a3: isize, a4: isize,
     n
 }
 
+// The kernel "returns" from a signal handler into `sa_restorer`, which has to call `rt_sigreturn`
+// with the stack exactly as the kernel left it. Any Rust prologue would touch the stack, so it's a bare symbol.
+// See `SA_RESTORER` in the kernel's arch/x86/kernel/signal.c.
+global_asm! {
+    ".pushsection .text.__syscalls_rs_restore_rt,\"ax\",@progbits",
+    ".globl __syscalls_rs_restore_rt",
+    ".hidden __syscalls_rs_restore_rt",
+    ".type __syscalls_rs_restore_rt,@function",
+    "__syscalls_rs_restore_rt:",
+    "    mov rax, 15", // Syscalls::RtSigreturn
+    "    syscall",
+    ".size __syscalls_rs_restore_rt, . - __syscalls_rs_restore_rt",
+    ".popsection",
+}
+
+extern "C" {
+    /// Restorer trampoline for `rt_sigaction(2)`, never call this directly.
+    #[link_name = "__syscalls_rs_restore_rt"]
+    pub fn restore_rt();
+}
+
+/// The size of a page, x86 always has 4KiB base pages.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Returns the thread pointer of the current thread, the TLS ABI keeps it at `fs:0`.
+#[inline]
+pub fn thread_pointer() -> usize {
+    let tp: usize;
+    unsafe {
+        asm!("mov {}, qword ptr fs:[0]", out(reg) tp, options(nostack, readonly, preserves_flags))
+    };
+    tp
+}
+
+/// Returns `true` if the CPU supports memory protection keys and the kernel enabled them (`CPUID.7.0:ECX.OSPKE`).
+/// Without it `rdpkru`/`wrpkru` raise `#UD`.
+#[inline]
+#[allow(unused_unsafe)] // The cpuid intrinsics are safe in newer toolchains.
+pub fn has_pku() -> bool {
+    use core::arch::x86_64::{__cpuid_count, __get_cpuid_max};
+    unsafe {
+        if __get_cpuid_max(0).0 < 7 {
+            return false;
+        }
+        __cpuid_count(7, 0).ecx & (1 << 4) != 0
+    }
+}
+
+/// Read the protection key rights register of the current thread, requires [`has_pku`](fn.has_pku.html).
+#[inline]
+pub unsafe fn rdpkru() -> u32 {
+    let pkru: u32;
+    asm!("rdpkru", in("ecx") 0, out("eax") pkru, out("edx") _, options(nomem, nostack, preserves_flags));
+    pkru
+}
+
+/// Write the protection key rights register of the current thread, requires [`has_pku`](fn.has_pku.html).
+// Not `nomem`, this changes which memory can be accessed.
+#[inline]
+pub unsafe fn wrpkru(pkru: u32) {
+    asm!("wrpkru", in("eax") pkru, in("ecx") 0, in("edx") 0, options(nostack, preserves_flags));
+}
+
 #[allow(dead_code)]
 pub enum Syscalls {
     Read = 0,
@@ -406,4 +469,7 @@ pub enum Syscalls {
     Fspick = 433,
     PidfdOpen = 434,
     Clone3 = 435,
+    ProcessMadvise = 440,
+    EpollPwait2 = 441,
+    FutexWaitv = 449,
 }
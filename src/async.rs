@@ -0,0 +1,465 @@
+//! Async I/O on non-blocking descriptors, with readiness driven by an epoll reactor thread.
+//!
+//! An [`AsyncFd`](struct.AsyncFd.html) registers its descriptor edge-triggered with a shared [`Epoll`](../epoll/struct.Epoll.html),
+//! which a background thread waits on to wake the tasks waiting for the descriptor.
+//! Any executor works, [`block_on`](fn.block_on.html) is a minimal one that runs a single future on the current thread.
+use crate::epoll::{Epoll, EpollEvents, EpollFlags, Events};
+use crate::poll::{poll, PollEvents, PollFd};
+use crate::socket::{accept4, connect, take_error, SockFlags};
+use crate::{read, write};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use linux_sys::EINPROGRESS;
+
+// The readiness bits, above them is a tick that's incremented on every event.
+const READABLE: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const TICK_SHIFT: u32 = 2;
+
+// The readiness of a registered descriptor, and the tasks waiting for it.
+#[derive(Default)]
+struct ScheduledIo {
+    readiness: AtomicU64,
+    readers: Mutex<Option<Waker>>,
+    writers: Mutex<Option<Waker>>,
+}
+
+impl ScheduledIo {
+    fn waiters(&self, interest: u64) -> &Mutex<Option<Waker>> {
+        if interest == READABLE {
+            &self.readers
+        } else {
+            &self.writers
+        }
+    }
+
+    fn set_ready(&self, ready: u64) {
+        let tick = 1 << TICK_SHIFT;
+        self.readiness
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |curr| {
+                Some(curr.wrapping_add(tick) | ready)
+            })
+            .ok();
+        // Setting the readiness before taking a waker pairs with storing the waker before checking it again.
+        for &interest in &[READABLE, WRITABLE] {
+            if ready & interest != 0 {
+                if let Some(waker) = self.waiters(interest).lock().unwrap().take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    // Returns the tick of the readiness if `interest` is ready, otherwise `cx` is woken when it is.
+    fn poll_ready(&self, interest: u64, cx: &mut Context<'_>) -> Poll<u64> {
+        let curr = self.readiness.load(Ordering::Acquire);
+        if curr & interest != 0 {
+            return Poll::Ready(curr >> TICK_SHIFT);
+        }
+        {
+            let mut waiter = self.waiters(interest).lock().unwrap();
+            match &*waiter {
+                Some(waker) if waker.will_wake(cx.waker()) => (),
+                _ => *waiter = Some(cx.waker().clone()),
+            }
+        }
+        let curr = self.readiness.load(Ordering::Acquire);
+        if curr & interest != 0 {
+            Poll::Ready(curr >> TICK_SHIFT)
+        } else {
+            Poll::Pending
+        }
+    }
+
+    // Clear `interest` after an operation would block, unless an event came in since `tick` was observed.
+    fn clear_ready(&self, interest: u64, tick: u64) {
+        self.readiness
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |curr| {
+                if curr >> TICK_SHIFT == tick {
+                    Some(curr & !interest)
+                } else {
+                    None
+                }
+            })
+            .ok();
+    }
+}
+
+struct Reactor {
+    epoll: Epoll,
+    ios: Mutex<HashMap<u64, Arc<ScheduledIo>>>,
+    next_token: AtomicU64,
+}
+
+impl Reactor {
+    // The reactor is started on first use, and runs until the process exits.
+    fn get() -> io::Result<&'static Reactor> {
+        static REACTOR: OnceLock<Option<Reactor>> = OnceLock::new();
+        let mut error = None;
+        let reactor = REACTOR.get_or_init(|| match Self::start() {
+            Ok(reactor) => Some(reactor),
+            Err(e) => {
+                error = Some(e);
+                None
+            }
+        });
+        match (reactor, error) {
+            (Some(reactor), _) => Ok(reactor),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(io::Error::other("the reactor failed to start")),
+        }
+    }
+
+    fn start() -> io::Result<Reactor> {
+        let epoll = Epoll::new(EpollFlags::new().cloexec())?;
+        thread::Builder::new()
+            .name("syscalls-rs reactor".into())
+            .spawn(|| {
+                // `get` returns only after the `OnceLock` is initialized.
+                let reactor = Self::get().expect("the reactor is running");
+                reactor.run();
+            })?;
+        Ok(Reactor {
+            epoll,
+            ios: Mutex::new(HashMap::new()),
+            next_token: AtomicU64::new(0),
+        })
+    }
+
+    fn run(&self) {
+        let mut events = Events::with_capacity(256);
+        loop {
+            let ready: Vec<_> = match self.epoll.wait(&mut events, None) {
+                Ok(ready) => ready.collect(),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => panic!("epoll_wait failed in the reactor: {}", e),
+            };
+            let ios = self.ios.lock().unwrap();
+            for (token, events) in ready {
+                // The descriptor could have been deregistered since the event.
+                if let Some(io) = ios.get(&token) {
+                    io.set_ready(readiness(events));
+                }
+            }
+        }
+    }
+
+    fn register<F: AsRawFd>(&self, fd: &F) -> io::Result<(u64, Arc<ScheduledIo>)> {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let io = Arc::new(ScheduledIo::default());
+        self.ios.lock().unwrap().insert(token, Arc::clone(&io));
+        let events = EpollEvents::IN | EpollEvents::OUT | EpollEvents::RDHUP | EpollEvents::ET;
+        if let Err(e) = self.epoll.add(fd, events, token) {
+            self.ios.lock().unwrap().remove(&token);
+            return Err(e);
+        }
+        Ok((token, io))
+    }
+
+    fn deregister<F: AsRawFd>(&self, fd: &F, token: u64) {
+        self.epoll.delete(fd).ok();
+        self.ios.lock().unwrap().remove(&token);
+    }
+}
+
+fn readiness(events: EpollEvents) -> u64 {
+    let mut ready = 0;
+    // Errors and hangups are reported to both sides, so the operation can return them.
+    if events.contains(EpollEvents::ERR) || events.contains(EpollEvents::HUP) {
+        ready |= READABLE | WRITABLE;
+    }
+    if events.contains(EpollEvents::IN) || events.contains(EpollEvents::RDHUP) {
+        ready |= READABLE;
+    }
+    if events.contains(EpollEvents::OUT) {
+        ready |= WRITABLE;
+    }
+    ready
+}
+
+// A future that's ready with the readiness tick when the descriptor of an `AsyncFd` is.
+struct Ready<'a, T: AsRawFd> {
+    fd: &'a AsyncFd<T>,
+    interest: u64,
+}
+
+impl<T: AsRawFd> Future for Ready<'_, T> {
+    type Output = u64;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u64> {
+        self.fd.io.poll_ready(self.interest, cx)
+    }
+}
+
+/// A non-blocking descriptor registered with the reactor, deregistered on drop.
+///
+/// Readiness is tracked per direction, so at most one task should wait for reading and one for writing at a time.
+pub struct AsyncFd<T: AsRawFd> {
+    inner: Option<T>,
+    token: u64,
+    io: Arc<ScheduledIo>,
+}
+
+impl<T: AsRawFd> AsyncFd<T> {
+    /// Register `inner` with the reactor, starting it if needed. `inner` has to be in non-blocking mode.
+    pub fn new(inner: T) -> io::Result<Self> {
+        let (token, io) = Reactor::get()?.register(&inner)?;
+        Ok(AsyncFd {
+            inner: Some(inner),
+            token,
+            io,
+        })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.inner.as_ref().unwrap()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Deregister the descriptor, and return it.
+    pub fn into_inner(mut self) -> T {
+        let inner = self.inner.take().unwrap();
+        if let Ok(reactor) = Reactor::get() {
+            reactor.deregister(&inner, self.token);
+        }
+        inner
+    }
+
+    fn ready(&self, interest: u64) -> Ready<'_, T> {
+        Ready { fd: self, interest }
+    }
+
+    /// Wait until the descriptor is readable, or it was and no operation failed with `WouldBlock` since.
+    pub async fn readable(&self) {
+        self.ready(READABLE).await;
+    }
+
+    /// Wait until the descriptor is writable, or it was and no operation failed with `WouldBlock` since.
+    pub async fn writable(&self) {
+        self.ready(WRITABLE).await;
+    }
+
+    /// Call `f` when the descriptor is readable, until it doesn't fail with `WouldBlock`.
+    pub async fn read_with<R>(&self, mut f: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            let tick = self.ready(READABLE).await;
+            match f(self.get_ref()) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.io.clear_ready(READABLE, tick)
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Call `f` when the descriptor is writable, until it doesn't fail with `WouldBlock`.
+    pub async fn write_with<R>(&self, mut f: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            let tick = self.ready(WRITABLE).await;
+            match f(self.get_ref()) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.io.clear_ready(WRITABLE, tick)
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Read into `buf`, returns the number of bytes read.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_with(|fd| unsafe { read(fd, &mut *buf) }).await
+    }
+
+    /// Write `buf`, returns the number of bytes written.
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write_with(|fd| {
+            let mut fd = fd.as_raw_fd();
+            unsafe { write(&mut fd, buf) }
+        })
+        .await
+    }
+
+    /// Accept a connection on a listening socket, and return the new socket with `flags`
+    /// (which should be `nonblock` to wrap it in an `AsyncFd`).
+    pub async fn accept(&self, flags: SockFlags) -> io::Result<RawFd> {
+        let fd = self.read_with(|fd| unsafe { accept4(fd, flags) }).await?;
+        Ok(fd as RawFd)
+    }
+
+    /// Connect the socket to `addr`.
+    pub async fn connect(&self, addr: &SocketAddr) -> io::Result<()> {
+        match unsafe { connect(self.get_ref(), addr) } {
+            Err(ref e) if e.raw_os_error() == Some(EINPROGRESS as i32) => (),
+            res => return res,
+        }
+        loop {
+            let tick = self.ready(WRITABLE).await;
+            // An unconnected socket is already writable, so check that the connection is done.
+            let mut fds = [PollFd::from_fd(self.get_ref(), PollEvents::OUT)];
+            poll(&mut fds, Some(Duration::ZERO))?;
+            if fds[0].revents().is_empty() {
+                self.io.clear_ready(WRITABLE, tick);
+                continue;
+            }
+            return match unsafe { take_error(self.get_ref()) }? {
+                Some(e) => Err(e),
+                None => Ok(()),
+            };
+        }
+    }
+}
+
+impl<T: AsRawFd + core::fmt::Debug> core::fmt::Debug for AsyncFd<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AsyncFd")
+            .field("inner", self.get_ref())
+            .finish()
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for AsyncFd<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.get_ref().as_raw_fd()
+    }
+}
+
+impl<T: AsRawFd> Drop for AsyncFd<T> {
+    fn drop(&mut self) {
+        if let Some(inner) = &self.inner {
+            if let Ok(reactor) = Reactor::get() {
+                reactor.deregister(inner, self.token);
+            }
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` to completion on the current thread, parking it while the future is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventfd::{EfdFlags, EventFd};
+    use crate::socket::{socket, AddressFamily, SockType};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::FromRawFd;
+
+    #[test]
+    fn test_async_eventfd() {
+        let fd =
+            Arc::new(AsyncFd::new(EventFd::new(0, EfdFlags::new().nonblock()).unwrap()).unwrap());
+        let notifier = {
+            let fd = Arc::clone(&fd);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                fd.get_ref().add(3).unwrap();
+            })
+        };
+        let value = block_on(fd.read_with(|fd| match fd.take() {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(e) => Err(e),
+        }));
+        assert_eq!(value.unwrap(), 3);
+        notifier.join().unwrap();
+
+        // Reading through the descriptor works the same.
+        fd.get_ref().add(1).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(block_on(fd.read(&mut buf)).unwrap(), 8);
+        assert_eq!(u64::from_ne_bytes(buf), 1);
+    }
+
+    #[test]
+    fn test_async_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = AsyncFd::new(listener).unwrap();
+
+        let flags = SockFlags::new().nonblock().cloexec();
+        let client = unsafe { socket(AddressFamily::Inet, SockType::Stream, flags, None) }.unwrap();
+        let client = AsyncFd::new(unsafe { TcpStream::from_raw_fd(client as RawFd) }).unwrap();
+
+        // Connecting to a listening socket doesn't wait for it to be accepted.
+        block_on(client.connect(&addr)).unwrap();
+        let server = block_on(listener.accept(flags)).unwrap();
+        let server = AsyncFd::new(unsafe { TcpStream::from_raw_fd(server) }).unwrap();
+        let client_addr = client.get_ref().local_addr().unwrap();
+        assert_eq!(server.get_ref().peer_addr().unwrap(), client_addr);
+
+        // Enough to fill the socket buffers, so the writer has to wait for the reader.
+        let message = vec![7u8; 8 << 20];
+        let writer = {
+            let message = message.clone();
+            thread::spawn(move || {
+                block_on(async {
+                    let mut written = 0;
+                    while written < message.len() {
+                        written += client.write(&message[written..]).await?;
+                    }
+                    Ok::<_, io::Error>(())
+                })
+            })
+        };
+        let received = block_on(async {
+            let mut received = Vec::new();
+            let mut buf = [0u8; 1 << 16];
+            loop {
+                match server.read(&mut buf).await? {
+                    0 => return Ok::<_, io::Error>(received),
+                    len => received.extend_from_slice(&buf[..len]),
+                }
+            }
+        })
+        .unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(received == message);
+
+        let server = server.into_inner();
+        assert!(server.peer_addr().is_ok());
+    }
+
+    #[test]
+    fn test_async_connect_refused() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let flags = SockFlags::new().nonblock().cloexec();
+        let client = unsafe { socket(AddressFamily::Inet, SockType::Stream, flags, None) }.unwrap();
+        let client = AsyncFd::new(unsafe { TcpStream::from_raw_fd(client as RawFd) }).unwrap();
+        let err = block_on(client.connect(&addr)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
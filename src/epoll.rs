@@ -0,0 +1,411 @@
+//! I/O event notification, see `epoll(7)`.
+use crate::arch::Syscalls;
+use crate::signal::SigSet;
+use crate::utils::{timeout_ms, timespec_from_duration};
+use crate::{close, result, result_none, syscall};
+use std::io;
+use std::mem::size_of;
+use std::ops::{BitOr, BitOrAssign};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::time::Duration;
+
+use linux_sys::{
+    __kernel_timespec, epoll_event, EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD,
+};
+
+// `linux/eventpoll.h` casts the events to `__poll_t`, which bindgen can't evaluate.
+const EPOLLIN: u32 = 0x0000_0001;
+const EPOLLPRI: u32 = 0x0000_0002;
+const EPOLLOUT: u32 = 0x0000_0004;
+const EPOLLERR: u32 = 0x0000_0008;
+const EPOLLHUP: u32 = 0x0000_0010;
+const EPOLLRDHUP: u32 = 0x0000_2000;
+const EPOLLEXCLUSIVE: u32 = 1 << 28;
+const EPOLLWAKEUP: u32 = 1 << 29;
+const EPOLLONESHOT: u32 = 1 << 30;
+const EPOLLET: u32 = 1 << 31;
+
+/// A set of epoll events, what to wait for in [`epoll_ctl`](fn.epoll_ctl.html) and what happened in the wait results.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct EpollEvents(u32);
+
+impl EpollEvents {
+    /// The file is readable.
+    pub const IN: EpollEvents = EpollEvents(EPOLLIN);
+    /// There's urgent data to read, e.g. TCP out of band data.
+    pub const PRI: EpollEvents = EpollEvents(EPOLLPRI);
+    /// The file is writable.
+    pub const OUT: EpollEvents = EpollEvents(EPOLLOUT);
+    /// An error happened, always reported.
+    pub const ERR: EpollEvents = EpollEvents(EPOLLERR);
+    /// The file was hung up, always reported.
+    pub const HUP: EpollEvents = EpollEvents(EPOLLHUP);
+    /// The peer of a stream socket shut down its writing half.
+    pub const RDHUP: EpollEvents = EpollEvents(EPOLLRDHUP);
+    /// Only wake one of the epoll instances that wait for this file with `EXCLUSIVE`, to avoid thundering herds.
+    /// Only valid with [`EpollOp::Add`](enum.EpollOp.html) (Linux 4.5+).
+    pub const EXCLUSIVE: EpollEvents = EpollEvents(EPOLLEXCLUSIVE);
+    /// Prevent suspend while the event is being handled, requires `CAP_BLOCK_SUSPEND`.
+    pub const WAKEUP: EpollEvents = EpollEvents(EPOLLWAKEUP);
+    /// Disable the file after one event, until it's rearmed with [`EpollOp::Mod`](enum.EpollOp.html).
+    pub const ONESHOT: EpollEvents = EpollEvents(EPOLLONESHOT);
+    /// Edge triggered: only report changes, instead of the state on every wait.
+    pub const ET: EpollEvents = EpollEvents(EPOLLET);
+
+    /// An empty set of events.
+    pub const fn empty() -> Self {
+        EpollEvents(0)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        EpollEvents(bits)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if every event in `other` is also in `self`.
+    pub const fn contains(self, other: EpollEvents) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for EpollEvents {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        EpollEvents(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for EpollEvents {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl core::fmt::Debug for EpollEvents {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EpollEvents")
+            .field("IN", &self.contains(EpollEvents::IN))
+            .field("PRI", &self.contains(EpollEvents::PRI))
+            .field("OUT", &self.contains(EpollEvents::OUT))
+            .field("ERR", &self.contains(EpollEvents::ERR))
+            .field("HUP", &self.contains(EpollEvents::HUP))
+            .field("RDHUP", &self.contains(EpollEvents::RDHUP))
+            .field("EXCLUSIVE", &self.contains(EpollEvents::EXCLUSIVE))
+            .field("WAKEUP", &self.contains(EpollEvents::WAKEUP))
+            .field("ONESHOT", &self.contains(EpollEvents::ONESHOT))
+            .field("ET", &self.contains(EpollEvents::ET))
+            .finish()
+    }
+}
+
+/// Additional options for [`epoll_create1`](fn.epoll_create1.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct EpollFlags(isize);
+
+impl EpollFlags {
+    /// Creates new `EpollFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set close-on-exec on the new descriptor.
+    pub fn cloexec(mut self) -> Self {
+        self.0 |= EPOLL_CLOEXEC as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for EpollFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EpollFlags")
+            .field("CLOEXEC", &(self.0 & EPOLL_CLOEXEC as isize > 0))
+            .finish()
+    }
+}
+
+/// An operation of [`epoll_ctl`](fn.epoll_ctl.html).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum EpollOp {
+    /// Start watching a file.
+    Add = EPOLL_CTL_ADD as isize,
+    /// Change the events and data of a watched file.
+    Mod = EPOLL_CTL_MOD as isize,
+    /// Stop watching a file, the event is ignored.
+    Del = EPOLL_CTL_DEL as isize,
+}
+
+/// Create a new epoll instance.
+#[inline]
+pub unsafe fn epoll_create1(flags: EpollFlags) -> io::Result<usize> {
+    let res = syscall!(Syscalls::EpollCreate1, flags.0);
+    result!(res)
+}
+
+/// Add, change or remove `fd` in the interest list of `epfd`, `event` is only optional for `Del`.
+#[inline]
+pub unsafe fn epoll_ctl<E: AsRawFd, F: AsRawFd>(
+    epfd: &E,
+    op: EpollOp,
+    fd: &F,
+    event: Option<&epoll_event>,
+) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::EpollCtl,
+        epfd.as_raw_fd() as isize,
+        op as isize,
+        fd.as_raw_fd() as isize,
+        event
+            .map(|event| event as *const epoll_event)
+            .unwrap_or(ptr::null()) as isize,
+    );
+    result_none!(res)
+}
+
+/// Wait for events on `epfd` for up to `timeout` (rounded up to milliseconds), `None` waits forever.
+/// Returns the number of events written to the start of `events`, 0 if the timeout expired.
+#[inline]
+pub unsafe fn epoll_wait<E: AsRawFd>(
+    epfd: &E,
+    events: &mut [epoll_event],
+    timeout: Option<Duration>,
+) -> io::Result<usize> {
+    epoll_pwait(epfd, events, timeout, None)
+}
+
+/// Like [`epoll_wait`](fn.epoll_wait.html), but atomically replaces the signal mask with `sigmask` while waiting.
+#[inline]
+pub unsafe fn epoll_pwait<E: AsRawFd>(
+    epfd: &E,
+    events: &mut [epoll_event],
+    timeout: Option<Duration>,
+    sigmask: Option<&SigSet>,
+) -> io::Result<usize> {
+    let res = syscall!(
+        Syscalls::EpollPwait,
+        epfd.as_raw_fd() as isize,
+        events.as_mut_ptr() as isize,
+        events.len() as isize,
+        timeout_ms(timeout) as isize,
+        sigmask
+            .map(|set| set as *const SigSet)
+            .unwrap_or(ptr::null()) as isize,
+        size_of::<SigSet>() as isize,
+    );
+    result!(res)
+}
+
+/// Like [`epoll_pwait`](fn.epoll_pwait.html), but with a nanosecond `timeout` (Linux 5.11+).
+#[inline]
+pub unsafe fn epoll_pwait2<E: AsRawFd>(
+    epfd: &E,
+    events: &mut [epoll_event],
+    timeout: Option<Duration>,
+    sigmask: Option<&SigSet>,
+) -> io::Result<usize> {
+    let timeout = timeout.map(timespec_from_duration);
+    let res = syscall!(
+        Syscalls::EpollPwait2,
+        epfd.as_raw_fd() as isize,
+        events.as_mut_ptr() as isize,
+        events.len() as isize,
+        timeout
+            .as_ref()
+            .map(|t| t as *const __kernel_timespec)
+            .unwrap_or(ptr::null()) as isize,
+        sigmask
+            .map(|set| set as *const SigSet)
+            .unwrap_or(ptr::null()) as isize,
+        size_of::<SigSet>() as isize,
+    );
+    result!(res)
+}
+
+/// A buffer for the events returned by [`Epoll::wait`](struct.Epoll.html#method.wait).
+pub struct Events(Vec<epoll_event>);
+
+impl Events {
+    /// A buffer for up to `capacity` events per wait, which has to be at least 1.
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0, "epoll needs room for at least one event");
+        Events(vec![epoll_event { events: 0, data: 0 }; capacity])
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl core::fmt::Debug for Events {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Events")
+            .field("capacity", &self.capacity())
+            .finish()
+    }
+}
+
+/// An epoll instance, every watched file has a `u64` token which is returned with its events.
+/// The descriptor is closed on drop.
+#[derive(Debug)]
+pub struct Epoll(RawFd);
+
+impl Epoll {
+    /// Create a new epoll instance.
+    pub fn new(flags: EpollFlags) -> io::Result<Self> {
+        let fd = unsafe { epoll_create1(flags) }?;
+        Ok(Epoll(fd as RawFd))
+    }
+
+    fn ctl<F: AsRawFd>(
+        &self,
+        op: EpollOp,
+        fd: &F,
+        events: EpollEvents,
+        token: u64,
+    ) -> io::Result<()> {
+        let event = epoll_event {
+            events: events.0,
+            data: token,
+        };
+        unsafe { epoll_ctl(&self.0, op, fd, Some(&event)) }
+    }
+
+    /// Start watching `fd` for `events`, which are returned with `token`.
+    /// `fd` has to stay open until it's deleted, closing it only removes it once all its duplicates are closed.
+    pub fn add<F: AsRawFd>(&self, fd: &F, events: EpollEvents, token: u64) -> io::Result<()> {
+        self.ctl(EpollOp::Add, fd, events, token)
+    }
+
+    /// Change the events and token of `fd`, this also rearms a `ONESHOT` file.
+    pub fn modify<F: AsRawFd>(&self, fd: &F, events: EpollEvents, token: u64) -> io::Result<()> {
+        self.ctl(EpollOp::Mod, fd, events, token)
+    }
+
+    /// Stop watching `fd`.
+    pub fn delete<F: AsRawFd>(&self, fd: &F) -> io::Result<()> {
+        unsafe { epoll_ctl(&self.0, EpollOp::Del, fd, None) }
+    }
+
+    /// Wait for events for up to `timeout`, `None` waits forever.
+    /// Returns the `(token, events)` of the ready files, which is empty if the timeout expired.
+    /// Uses `epoll_pwait2` for nanosecond timeouts if the kernel supports it.
+    pub fn wait<'a>(
+        &self,
+        events: &'a mut Events,
+        timeout: Option<Duration>,
+    ) -> io::Result<impl ExactSizeIterator<Item = (u64, EpollEvents)> + 'a> {
+        let len = match unsafe { epoll_pwait2(&self.0, &mut events.0, timeout, None) } {
+            Err(ref e) if e.kind() == io::ErrorKind::Unsupported => unsafe {
+                epoll_pwait(&self.0, &mut events.0, timeout, None)
+            },
+            res => res,
+        }?;
+        Ok(events.0[..len]
+            .iter()
+            .map(|event| (event.data, EpollEvents(event.events))))
+    }
+}
+
+impl AsRawFd for Epoll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            close(&self.0).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventfd::{EfdFlags, EventFd};
+    use std::time::Instant;
+
+    #[test]
+    fn test_epoll_wait() {
+        let epoll = Epoll::new(EpollFlags::new().cloexec()).unwrap();
+        let mut events = Events::with_capacity(4);
+        let first = EventFd::new(0, EfdFlags::new().nonblock()).unwrap();
+        let second = EventFd::new(0, EfdFlags::new().nonblock()).unwrap();
+        epoll.add(&first, EpollEvents::IN, 1).unwrap();
+        epoll
+            .add(&second, EpollEvents::IN | EpollEvents::OUT, 2)
+            .unwrap();
+
+        // An eventfd is always writable.
+        let ready: Vec<_> = epoll
+            .wait(&mut events, Some(Duration::ZERO))
+            .unwrap()
+            .collect();
+        assert_eq!(ready, [(2, EpollEvents::OUT)]);
+
+        first.add(1).unwrap();
+        epoll.modify(&second, EpollEvents::IN, 2).unwrap();
+        let ready: Vec<_> = epoll.wait(&mut events, None).unwrap().collect();
+        assert_eq!(ready, [(1, EpollEvents::IN)]);
+
+        epoll.delete(&first).unwrap();
+        let start = Instant::now();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(epoll.wait(&mut events, Some(timeout)).unwrap().len(), 0);
+        assert!(start.elapsed() >= timeout);
+
+        let err = epoll.delete(&first).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_epoll_edge_triggered() {
+        let epoll = Epoll::new(EpollFlags::new()).unwrap();
+        let mut events = Events::with_capacity(1);
+        let fd = EventFd::new(1, EfdFlags::new().nonblock()).unwrap();
+        epoll
+            .add(
+                &fd,
+                EpollEvents::IN | EpollEvents::ET | EpollEvents::ONESHOT,
+                7,
+            )
+            .unwrap();
+        let ready: Vec<_> = epoll
+            .wait(&mut events, Some(Duration::ZERO))
+            .unwrap()
+            .collect();
+        assert_eq!(ready, [(7, EpollEvents::IN)]);
+        // Disabled by `ONESHOT` until it's rearmed, even though it's still readable.
+        assert_eq!(
+            epoll.wait(&mut events, Some(Duration::ZERO)).unwrap().len(),
+            0
+        );
+        epoll
+            .modify(&fd, EpollEvents::IN | EpollEvents::ET, 8)
+            .unwrap();
+        let ready: Vec<_> = epoll
+            .wait(&mut events, Some(Duration::ZERO))
+            .unwrap()
+            .collect();
+        assert_eq!(ready, [(8, EpollEvents::IN)]);
+        // Edge triggered, there was no new event.
+        assert_eq!(
+            epoll.wait(&mut events, Some(Duration::ZERO)).unwrap().len(),
+            0
+        );
+
+        let mut raw = [epoll_event { events: 0, data: 0 }; 2];
+        fd.add(1).unwrap();
+        let len = unsafe { epoll_wait(&epoll, &mut raw, Some(Duration::ZERO)) }.unwrap();
+        assert_eq!(len, 1);
+        assert_eq!({ raw[0].data }, 8);
+    }
+}
@@ -0,0 +1,156 @@
+//! A counter to notify through a file descriptor, see `eventfd(2)`.
+use crate::arch::Syscalls;
+use crate::{close, read, result, syscall, write};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use linux_sys::{EINVAL, O_CLOEXEC, O_NONBLOCK};
+
+// The kernel's `linux/eventfd.h` isn't exported to userspace.
+const EFD_SEMAPHORE: u32 = 1;
+const EFD_CLOEXEC: u32 = O_CLOEXEC;
+const EFD_NONBLOCK: u32 = O_NONBLOCK;
+
+/// Additional options for [`eventfd2`](fn.eventfd2.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct EfdFlags(isize);
+
+impl EfdFlags {
+    /// Creates new `EfdFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set close-on-exec on the new descriptor.
+    pub fn cloexec(mut self) -> Self {
+        self.0 |= EFD_CLOEXEC as isize;
+        self
+    }
+
+    /// Set non-blocking mode on the new descriptor.
+    pub fn nonblock(mut self) -> Self {
+        self.0 |= EFD_NONBLOCK as isize;
+        self
+    }
+
+    /// Every read decrements the counter by 1, instead of resetting it to 0.
+    pub fn semaphore(mut self) -> Self {
+        self.0 |= EFD_SEMAPHORE as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for EfdFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EfdFlags")
+            .field("CLOEXEC", &(self.0 & EFD_CLOEXEC as isize > 0))
+            .field("NONBLOCK", &(self.0 & EFD_NONBLOCK as isize > 0))
+            .field("SEMAPHORE", &(self.0 & EFD_SEMAPHORE as isize > 0))
+            .finish()
+    }
+}
+
+/// Create an eventfd with its counter set to `initval`.
+#[inline]
+pub unsafe fn eventfd2(initval: u32, flags: EfdFlags) -> io::Result<usize> {
+    let res = syscall!(Syscalls::Eventfd2, initval as isize, flags.0);
+    result!(res)
+}
+
+/// An eventfd, readable while its counter isn't 0 and writable while it's below `u64::MAX - 1`.
+/// The descriptor is closed on drop.
+#[derive(Debug)]
+pub struct EventFd(RawFd);
+
+impl EventFd {
+    /// Create a new eventfd with its counter set to `initval`.
+    pub fn new(initval: u32, flags: EfdFlags) -> io::Result<Self> {
+        let fd = unsafe { eventfd2(initval, flags) }?;
+        Ok(EventFd(fd as RawFd))
+    }
+
+    /// Add `value` to the counter, blocking while that would overflow it.
+    /// If the descriptor is non-blocking this fails with `EAGAIN` (`io::ErrorKind::WouldBlock`) instead.
+    pub fn add(&self, value: u64) -> io::Result<()> {
+        // The counter can't reach `u64::MAX`, so this could never succeed.
+        if value == u64::MAX {
+            return Err(io::Error::from_raw_os_error(EINVAL as i32));
+        }
+        // Adding is shared, like `Write` for `&File`.
+        let mut fd = self.0;
+        let len = unsafe { write(&mut fd, &value.to_ne_bytes()) }?;
+        debug_assert_eq!(len, size_of::<u64>());
+        Ok(())
+    }
+
+    /// Take the counter and reset it to 0, or only take 1 in semaphore mode, blocking while it's 0.
+    /// Returns `None` if the descriptor is non-blocking and the counter is 0.
+    pub fn take(&self) -> io::Result<Option<u64>> {
+        let mut buf = [0u8; size_of::<u64>()];
+        match unsafe { read(&self.0, &mut buf) } {
+            Ok(len) => {
+                debug_assert_eq!(len, buf.len());
+                Ok(Some(u64::from_ne_bytes(buf)))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            close(&self.0).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_eventfd_counter() {
+        let fd = EventFd::new(5, EfdFlags::new().cloexec().nonblock()).unwrap();
+        fd.add(3).unwrap();
+        assert_eq!(fd.take().unwrap(), Some(8));
+        assert_eq!(fd.take().unwrap(), None);
+
+        // The counter is full at `u64::MAX - 1`.
+        fd.add(u64::MAX - 1).unwrap();
+        let err = fd.add(1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let err = fd.add(u64::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fd.take().unwrap(), Some(u64::MAX - 1));
+    }
+
+    #[test]
+    fn test_eventfd_semaphore() {
+        let fd = EventFd::new(2, EfdFlags::new().semaphore().nonblock()).unwrap();
+        assert_eq!(fd.take().unwrap(), Some(1));
+        assert_eq!(fd.take().unwrap(), Some(1));
+        assert_eq!(fd.take().unwrap(), None);
+    }
+
+    #[test]
+    fn test_eventfd_wakeup() {
+        let fd = Arc::new(EventFd::new(0, EfdFlags::new()).unwrap());
+        let waiter = {
+            let fd = Arc::clone(&fd);
+            thread::spawn(move || fd.take().unwrap())
+        };
+        fd.add(1).unwrap();
+        assert_eq!(waiter.join().unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,518 @@
+//! Fast userspace locking, waiting on and waking up the waiters of a 32 bit word, see `futex(2)`.
+//!
+//! A futex is only a kernel wait queue keyed by an address, the locking itself is done with atomics in userspace,
+//! see [`sync`](../sync/index.html) for primitives built on it.
+use crate::arch::Syscalls;
+use crate::time::{clock_gettime, ClockId};
+use crate::utils::timespec_from_duration;
+use crate::{result, syscall};
+use std::cmp;
+use std::io;
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use linux_sys::{
+    __kernel_timespec, EAGAIN, ETIMEDOUT, FUTEX_32, FUTEX_BITSET_MATCH_ANY, FUTEX_CLOCK_REALTIME,
+    FUTEX_CMP_REQUEUE, FUTEX_LOCK_PI, FUTEX_PRIVATE_FLAG, FUTEX_REQUEUE, FUTEX_TRYLOCK_PI,
+    FUTEX_UNLOCK_PI, FUTEX_WAIT, FUTEX_WAITV_MAX, FUTEX_WAIT_BITSET, FUTEX_WAKE, FUTEX_WAKE_BITSET,
+};
+
+/// A bitset that matches every waiter, for [`futex_wait_bitset`](fn.futex_wait_bitset.html)
+/// and [`futex_wake_bitset`](fn.futex_wake_bitset.html).
+pub const BITSET_MATCH_ANY: u32 = FUTEX_BITSET_MATCH_ANY;
+
+/// A futex operation, used in [`futex`](fn.futex.html).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum FutexOp {
+    /// Wait while the futex is `val`, with a relative timeout.
+    Wait = FUTEX_WAIT as isize,
+    /// Wake up to `val` waiters.
+    Wake = FUTEX_WAKE as isize,
+    /// Wake up to `val` waiters, and move up to `val2` of the rest to the futex at `uaddr2`.
+    Requeue = FUTEX_REQUEUE as isize,
+    /// Like `Requeue`, but fails with `EAGAIN` if the futex isn't `val3`.
+    CmpRequeue = FUTEX_CMP_REQUEUE as isize,
+    /// Lock a priority inheritance futex, with an absolute `Realtime` timeout.
+    LockPi = FUTEX_LOCK_PI as isize,
+    /// Unlock a priority inheritance futex, waking up the highest priority waiter.
+    UnlockPi = FUTEX_UNLOCK_PI as isize,
+    /// Lock a priority inheritance futex if it isn't locked.
+    TrylockPi = FUTEX_TRYLOCK_PI as isize,
+    /// Like `Wait` with an absolute timeout, and only woken by wakes whose bitset intersects `val3`.
+    WaitBitset = FUTEX_WAIT_BITSET as isize,
+    /// Like `Wake`, only waking waiters whose bitset intersects `val3`.
+    WakeBitset = FUTEX_WAKE_BITSET as isize,
+}
+
+/// Options for a futex operation.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct FutexFlags(isize);
+
+impl FutexFlags {
+    /// Creates new `FutexFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The futex is only used by this process, which is faster as the kernel doesn't look up shared mappings.
+    /// Waiters and wakers have to agree on this.
+    pub fn private(mut self) -> Self {
+        self.0 |= FUTEX_PRIVATE_FLAG as isize;
+        self
+    }
+
+    /// The absolute timeout of `WaitBitset` is on the `Realtime` clock instead of `Monotonic`.
+    pub fn clock_realtime(mut self) -> Self {
+        self.0 |= FUTEX_CLOCK_REALTIME as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for FutexFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FutexFlags")
+            .field("PRIVATE", &(self.0 & FUTEX_PRIVATE_FLAG as isize > 0))
+            .field(
+                "CLOCK_REALTIME",
+                &(self.0 & FUTEX_CLOCK_REALTIME as isize > 0),
+            )
+            .finish()
+    }
+}
+
+/// The raw futex syscall, how `val`, `val2` (a timeout pointer or a count), `uaddr2` and `val3` are used depends on `op`.
+#[inline]
+pub unsafe fn futex(
+    uaddr: &AtomicU32,
+    op: FutexOp,
+    flags: FutexFlags,
+    val: u32,
+    val2: usize,
+    uaddr2: Option<&AtomicU32>,
+    val3: u32,
+) -> io::Result<usize> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::FutexTime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::Futex;
+
+    let res = syscall!(
+        nr,
+        uaddr.as_ptr() as isize,
+        op as isize | flags.0,
+        val as isize,
+        val2 as isize,
+        uaddr2.map(AtomicU32::as_ptr).unwrap_or(ptr::null_mut()) as isize,
+        val3 as isize,
+    );
+    result!(res)
+}
+
+// The kernel takes the counts as an `int`, so `u32::MAX` would be negative.
+fn count(n: u32) -> u32 {
+    cmp::min(n, i32::MAX as u32)
+}
+
+fn timeout_ptr(timeout: &Option<__kernel_timespec>) -> usize {
+    timeout
+        .as_ref()
+        .map(|t| t as *const __kernel_timespec)
+        .unwrap_or(ptr::null()) as usize
+}
+
+/// Wait on `futex_word` while its value is `expected`, for up to `timeout` (`None` waits forever).
+/// Fails with `EAGAIN` if it wasn't `expected`, `ETIMEDOUT` if the timeout expired and `EINTR` if interrupted by a signal.
+/// It can also return spuriously, so the value has to be checked again.
+#[inline]
+pub fn futex_wait(
+    futex_word: &AtomicU32,
+    expected: u32,
+    timeout: Option<Duration>,
+    flags: FutexFlags,
+) -> io::Result<()> {
+    let timeout = timeout.map(timespec_from_duration);
+    let val2 = timeout_ptr(&timeout);
+    unsafe { futex(futex_word, FutexOp::Wait, flags, expected, val2, None, 0) }.map(drop)
+}
+
+/// Like [`futex_wait`](fn.futex_wait.html), until the absolute `deadline` on the `Monotonic` clock (or `Realtime` with `clock_realtime`),
+/// and only woken by wakes whose bitset intersects `bitset`.
+#[inline]
+pub fn futex_wait_bitset(
+    futex_word: &AtomicU32,
+    expected: u32,
+    deadline: Option<Duration>,
+    bitset: u32,
+    flags: FutexFlags,
+) -> io::Result<()> {
+    let deadline = deadline.map(timespec_from_duration);
+    let val2 = timeout_ptr(&deadline);
+    let op = FutexOp::WaitBitset;
+    unsafe { futex(futex_word, op, flags, expected, val2, None, bitset) }.map(drop)
+}
+
+/// Wake up to `n` waiters of `futex_word`, returns how many were woken.
+#[inline]
+pub fn futex_wake(futex_word: &AtomicU32, n: u32, flags: FutexFlags) -> io::Result<usize> {
+    unsafe { futex(futex_word, FutexOp::Wake, flags, count(n), 0, None, 0) }
+}
+
+/// Wake up to `n` waiters of `futex_word` whose bitset intersects `bitset`, returns how many were woken.
+#[inline]
+pub fn futex_wake_bitset(
+    futex_word: &AtomicU32,
+    n: u32,
+    bitset: u32,
+    flags: FutexFlags,
+) -> io::Result<usize> {
+    let op = FutexOp::WakeBitset;
+    unsafe { futex(futex_word, op, flags, count(n), 0, None, bitset) }
+}
+
+/// Wake up to `wake` waiters of `futex_word`, and move up to `requeue` of the others to wait on `target` instead.
+/// Returns how many were woken and requeued.
+#[inline]
+pub fn futex_requeue(
+    futex_word: &AtomicU32,
+    wake: u32,
+    requeue: u32,
+    target: &AtomicU32,
+    flags: FutexFlags,
+) -> io::Result<usize> {
+    let (op, val2) = (FutexOp::Requeue, count(requeue) as usize);
+    unsafe { futex(futex_word, op, flags, count(wake), val2, Some(target), 0) }
+}
+
+/// Like [`futex_requeue`](fn.futex_requeue.html), but fails with `EAGAIN` if `futex_word` isn't `expected`.
+#[inline]
+pub fn futex_cmp_requeue(
+    futex_word: &AtomicU32,
+    wake: u32,
+    requeue: u32,
+    target: &AtomicU32,
+    expected: u32,
+    flags: FutexFlags,
+) -> io::Result<usize> {
+    let (op, val2) = (FutexOp::CmpRequeue, count(requeue) as usize);
+    unsafe {
+        futex(
+            futex_word,
+            op,
+            flags,
+            count(wake),
+            val2,
+            Some(target),
+            expected,
+        )
+    }
+}
+
+/// Lock the priority inheritance futex `futex_word`, whose value is the owner's thread ID (or 0 when unlocked),
+/// blocking until the absolute `deadline` on the `Realtime` clock. The owner is boosted to the priority of its waiters.
+/// Fails with `EDEADLK` if this thread already owns it.
+#[inline]
+pub fn futex_lock_pi(
+    futex_word: &AtomicU32,
+    deadline: Option<Duration>,
+    flags: FutexFlags,
+) -> io::Result<()> {
+    let deadline = deadline.map(timespec_from_duration);
+    let val2 = timeout_ptr(&deadline);
+    unsafe { futex(futex_word, FutexOp::LockPi, flags, 0, val2, None, 0) }.map(drop)
+}
+
+/// Lock the priority inheritance futex `futex_word` if it isn't locked, fails with `EAGAIN` if it is.
+#[inline]
+pub fn futex_trylock_pi(futex_word: &AtomicU32, flags: FutexFlags) -> io::Result<()> {
+    unsafe { futex(futex_word, FutexOp::TrylockPi, flags, 0, 0, None, 0) }.map(drop)
+}
+
+/// Unlock the priority inheritance futex `futex_word`, which this thread has to own.
+#[inline]
+pub fn futex_unlock_pi(futex_word: &AtomicU32, flags: FutexFlags) -> io::Result<()> {
+    unsafe { futex(futex_word, FutexOp::UnlockPi, flags, 0, 0, None, 0) }.map(drop)
+}
+
+/// The maximum number of futexes [`futex_waitv`](fn.futex_waitv.html) waits on.
+pub const WAITV_MAX: usize = FUTEX_WAITV_MAX as usize;
+
+/// A futex to wait on with [`futex_waitv`](fn.futex_waitv.html), the `struct futex_waitv`.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct FutexWaitv<'a>(linux_sys::futex_waitv, PhantomData<&'a AtomicU32>);
+
+impl<'a> FutexWaitv<'a> {
+    /// Wait on `futex_word` while its value is `expected`.
+    pub fn new(futex_word: &'a AtomicU32, expected: u32) -> Self {
+        let waiter = linux_sys::futex_waitv {
+            val: expected as u64,
+            uaddr: futex_word.as_ptr() as u64,
+            flags: FUTEX_32,
+            __reserved: 0,
+        };
+        FutexWaitv(waiter, PhantomData)
+    }
+
+    /// The futex is only used by this process, like [`FutexFlags::private`](struct.FutexFlags.html#method.private).
+    pub fn private(mut self) -> Self {
+        self.0.flags |= FUTEX_PRIVATE_FLAG;
+        self
+    }
+
+    pub fn expected(&self) -> u32 {
+        self.0.val as u32
+    }
+}
+
+impl core::fmt::Debug for FutexWaitv<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FutexWaitv")
+            .field("uaddr", &(self.0.uaddr as *const AtomicU32))
+            .field("expected", &self.expected())
+            .field("private", &(self.0.flags & FUTEX_PRIVATE_FLAG != 0))
+            .finish()
+    }
+}
+
+/// Wait on all the `waiters` at once until one of them is woken, or until the absolute `deadline` on `clock`
+/// (which can only be `Monotonic` or `Realtime`, Linux 5.16+). Returns the index of the woken futex.
+/// Fails with `EAGAIN` if any of them isn't its expected value, and `ETIMEDOUT` if the deadline passed.
+#[inline]
+pub fn futex_waitv(
+    waiters: &[FutexWaitv<'_>],
+    deadline: Option<Duration>,
+    clock: ClockId,
+) -> io::Result<usize> {
+    let deadline = deadline.map(timespec_from_duration);
+    let res = unsafe {
+        syscall!(
+            Syscalls::FutexWaitv,
+            waiters.as_ptr() as isize,
+            waiters.len() as isize,
+            0,
+            timeout_ptr(&deadline) as isize,
+            clock.raw() as isize,
+        )
+    };
+    result!(res)
+}
+
+/// Wait until one of the (private) futexes isn't its expected value, or is woken with [`futex_wake`](fn.futex_wake.html),
+/// for up to `timeout` (`None` waits forever). Returns the index of that futex, or `None` if the timeout expired.
+/// The values are checked first, so a change between reading them and calling this isn't missed.
+pub fn wait_any(
+    futexes: &[(&AtomicU32, u32)],
+    timeout: Option<Duration>,
+) -> io::Result<Option<usize>> {
+    if futexes.len() > WAITV_MAX {
+        return Err(io::Error::from_raw_os_error(linux_sys::EINVAL as i32));
+    }
+    let deadline = match timeout {
+        Some(timeout) => Some(clock_gettime(ClockId::Monotonic)? + timeout),
+        None => None,
+    };
+    let waiters: Vec<_> = futexes
+        .iter()
+        .map(|&(futex_word, expected)| FutexWaitv::new(futex_word, expected).private())
+        .collect();
+    loop {
+        let changed = futexes
+            .iter()
+            .position(|(futex_word, expected)| futex_word.load(Ordering::Acquire) != *expected);
+        if changed.is_some() {
+            return Ok(changed);
+        }
+        match futex_waitv(&waiters, deadline, ClockId::Monotonic) {
+            Ok(index) => return Ok(Some(index)),
+            Err(ref e) if e.raw_os_error() == Some(ETIMEDOUT as i32) => return Ok(None),
+            // The value changed (and maybe back) since we checked it, or a signal interrupted us.
+            Err(ref e) if e.raw_os_error() == Some(EAGAIN as i32) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gettid;
+    use linux_sys::EDEADLK;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    // There's no way to know when the other thread is waiting, so retry until it was woken.
+    fn wake_until_woken(wake: impl Fn() -> usize) {
+        while wake() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_futex_wait_wake() {
+        let word = Arc::new(AtomicU32::new(0));
+        let private = FutexFlags::new().private();
+        let err = futex_wait(&word, 1, None, private).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EAGAIN as i32));
+        let start = Instant::now();
+        let err = futex_wait(&word, 0, Some(TICK), private).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ETIMEDOUT as i32));
+        assert!(start.elapsed() >= TICK);
+        assert_eq!(futex_wake(&word, 1, private).unwrap(), 0);
+
+        let waiter = {
+            let word = Arc::clone(&word);
+            thread::spawn(move || {
+                while word.load(Ordering::Acquire) == 0 {
+                    futex_wait(&word, 0, None, private).ok();
+                }
+            })
+        };
+        thread::sleep(TICK);
+        // The waiter checks the value again, so it can't miss the wake even if it wasn't waiting yet.
+        word.store(1, Ordering::Release);
+        assert!(futex_wake(&word, u32::MAX, private).unwrap() <= 1);
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn test_futex_bitset() {
+        let word = Arc::new(AtomicU32::new(0));
+        let flags = FutexFlags::new();
+        let deadline = clock_gettime(ClockId::Monotonic).unwrap() + TICK;
+        let err = futex_wait_bitset(&word, 0, Some(deadline), 1, flags).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ETIMEDOUT as i32));
+        assert!(clock_gettime(ClockId::Monotonic).unwrap() >= deadline);
+        let deadline = clock_gettime(ClockId::Realtime).unwrap() + TICK;
+        let realtime = flags.clock_realtime();
+        let err = futex_wait_bitset(&word, 0, Some(deadline), 1, realtime).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ETIMEDOUT as i32));
+
+        let waiter = {
+            let word = Arc::clone(&word);
+            thread::spawn(move || futex_wait_bitset(&word, 0, None, 0b01, flags))
+        };
+        wake_until_woken(|| {
+            // A disjoint bitset doesn't wake it.
+            assert_eq!(futex_wake_bitset(&word, 1, 0b10, flags).unwrap(), 0);
+            futex_wake_bitset(&word, 1, BITSET_MATCH_ANY, flags).unwrap()
+        });
+        waiter.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_futex_requeue() {
+        let from = Arc::new(AtomicU32::new(0));
+        let to = Arc::new(AtomicU32::new(0));
+        let private = FutexFlags::new().private();
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let from = Arc::clone(&from);
+                thread::spawn(move || futex_wait(&from, 0, None, private))
+            })
+            .collect();
+
+        let err = futex_cmp_requeue(&from, 0, 0, &to, 1, private).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EAGAIN as i32));
+        // Move them to `to` without waking them, as they start waiting.
+        let mut moved = 0;
+        while moved < 3 {
+            moved += futex_cmp_requeue(&from, 0, u32::MAX, &to, 0, private).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(futex_wake(&from, u32::MAX, private).unwrap(), 0);
+        assert_eq!(futex_requeue(&to, 1, 0, &from, private).unwrap(), 1);
+        assert_eq!(futex_wake(&to, u32::MAX, private).unwrap(), 2);
+        for waiter in waiters {
+            waiter.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_futex_pi() {
+        let word = Arc::new(AtomicU32::new(0));
+        let flags = FutexFlags::new().private();
+        futex_lock_pi(&word, None, flags).unwrap();
+        assert_eq!(word.load(Ordering::Relaxed), gettid());
+        let err = futex_lock_pi(&word, None, flags).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EDEADLK as i32));
+
+        let other = {
+            let word = Arc::clone(&word);
+            thread::spawn(move || {
+                let err = futex_trylock_pi(&word, flags).unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(EAGAIN as i32));
+                let deadline = clock_gettime(ClockId::Realtime).unwrap() + TICK;
+                let err = futex_lock_pi(&word, Some(deadline), flags).unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(ETIMEDOUT as i32));
+            })
+        };
+        other.join().unwrap();
+        futex_unlock_pi(&word, flags).unwrap();
+        assert_eq!(word.load(Ordering::Relaxed), 0);
+        futex_trylock_pi(&word, flags).unwrap();
+        futex_unlock_pi(&word, flags).unwrap();
+    }
+
+    #[test]
+    fn test_futex_waitv() {
+        let words = Arc::new([AtomicU32::new(0), AtomicU32::new(5)]);
+        let waiters = [FutexWaitv::new(&words[0], 0), FutexWaitv::new(&words[1], 5)];
+        let deadline = clock_gettime(ClockId::Realtime).unwrap() + TICK;
+        let err = futex_waitv(&waiters, Some(deadline), ClockId::Realtime).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ETIMEDOUT as i32));
+        let err = futex_waitv(&waiters, Some(deadline), ClockId::Boottime).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mismatch = [FutexWaitv::new(&words[0], 0), FutexWaitv::new(&words[1], 6)];
+        let err = futex_waitv(&mismatch, None, ClockId::Monotonic).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EAGAIN as i32));
+
+        let waiter = {
+            let words = Arc::clone(&words);
+            thread::spawn(move || {
+                let waiters = [FutexWaitv::new(&words[0], 0), FutexWaitv::new(&words[1], 5)];
+                futex_waitv(&waiters, None, ClockId::Monotonic)
+            })
+        };
+        wake_until_woken(|| futex_wake(&words[1], 1, FutexFlags::new()).unwrap());
+        assert_eq!(waiter.join().unwrap().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_wait_any() {
+        let queues = Arc::new([AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)]);
+        let futexes: Vec<_> = queues.iter().map(|queue| (queue, 0)).collect();
+        let start = Instant::now();
+        assert_eq!(wait_any(&futexes, Some(TICK)).unwrap(), None);
+        assert!(start.elapsed() >= TICK);
+
+        queues[2].store(1, Ordering::Release);
+        assert_eq!(wait_any(&futexes, None).unwrap(), Some(2));
+
+        let worker = {
+            let queues = Arc::clone(&queues);
+            thread::spawn(move || {
+                let seen: Vec<_> = queues
+                    .iter()
+                    .map(|queue| queue.load(Ordering::Acquire))
+                    .collect();
+                let futexes: Vec<_> = queues.iter().zip(seen).collect();
+                wait_any(&futexes, None).unwrap()
+            })
+        };
+        thread::sleep(TICK);
+        queues[1].fetch_add(1, Ordering::Release);
+        futex_wake(&queues[1], u32::MAX, FutexFlags::new().private()).unwrap();
+        assert_eq!(worker.join().unwrap(), Some(1));
+
+        let too_many = vec![(&queues[0], 0); WAITV_MAX + 1];
+        let err = wait_any(&too_many, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
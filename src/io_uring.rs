@@ -0,0 +1,881 @@
+//! Asynchronous I/O through rings shared with the kernel, see `io_uring(7)`.
+//!
+//! Requests are pushed as [`Sqe`](struct.Sqe.html)s to the submission queue of an [`IoUring`](struct.IoUring.html)
+//! and submitted with [`submit`](struct.IoUring.html#method.submit), their results are then reaped from the completion queue
+//! with [`completions`](struct.IoUring.html#method.completions), matched by their [`user_data`](struct.Sqe.html#method.user_data).
+use crate::arch::Syscalls;
+use crate::mman::{mmap, munmap, MapFlags, ProtFlags};
+use crate::poll::PollEvents;
+use crate::signal::SigSet;
+use crate::socket::SockFlags;
+use crate::{close, result, static_assert, syscall};
+use std::cmp;
+use std::ffi::CStr;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+
+use linux_sys::{
+    __kernel_timespec, io_uring_cqe, io_uring_params, EBUSY, IORING_CQE_F_MORE,
+    IORING_ENTER_GETEVENTS, IORING_ENTER_SQ_WAIT, IORING_ENTER_SQ_WAKEUP, IORING_FEAT_SINGLE_MMAP,
+    IORING_FSYNC_DATASYNC, IORING_OFF_CQ_RING, IORING_OFF_SQES, IORING_OFF_SQ_RING,
+    IORING_SETUP_CLAMP, IORING_SETUP_IOPOLL, IORING_SETUP_SQPOLL, IORING_SQ_NEED_WAKEUP,
+    IORING_TIMEOUT_ABS, IORING_TIMEOUT_BOOTTIME, IORING_TIMEOUT_REALTIME,
+};
+
+// The opcodes are a C enum, so bindgen doesn't give us plain constants.
+const IORING_OP_NOP: u8 = 0;
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ_FIXED: u8 = 4;
+const IORING_OP_WRITE_FIXED: u8 = 5;
+const IORING_OP_POLL_ADD: u8 = 6;
+const IORING_OP_TIMEOUT: u8 = 11;
+const IORING_OP_ACCEPT: u8 = 13;
+const IORING_OP_CONNECT: u8 = 16;
+const IORING_OP_OPENAT: u8 = 18;
+const IORING_OP_CLOSE: u8 = 19;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+const IORING_OP_SEND: u8 = 26;
+const IORING_OP_RECV: u8 = 27;
+
+// The `IOSQE_*` flags are defined with the bits of a C enum, which bindgen can't evaluate.
+const IOSQE_FIXED_FILE: u8 = 1 << 0;
+const IOSQE_IO_DRAIN: u8 = 1 << 1;
+const IOSQE_IO_LINK: u8 = 1 << 2;
+const IOSQE_IO_HARDLINK: u8 = 1 << 3;
+const IOSQE_ASYNC: u8 = 1 << 4;
+
+/// Additional options for [`io_uring_setup`](fn.io_uring_setup.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct SetupFlags(isize);
+
+impl SetupFlags {
+    /// Creates new `SetupFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Busy-poll for completions instead of using interrupts, only for `O_DIRECT` files that support it.
+    pub fn iopoll(mut self) -> Self {
+        self.0 |= IORING_SETUP_IOPOLL as isize;
+        self
+    }
+
+    /// A kernel thread polls the submission queue, so submitting doesn't need a syscall while it's awake.
+    pub fn sqpoll(mut self) -> Self {
+        self.0 |= IORING_SETUP_SQPOLL as isize;
+        self
+    }
+
+    /// Clamp the number of entries to the maximum instead of failing with `EINVAL`.
+    pub fn clamp(mut self) -> Self {
+        self.0 |= IORING_SETUP_CLAMP as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for SetupFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SetupFlags")
+            .field("IOPOLL", &(self.0 & IORING_SETUP_IOPOLL as isize > 0))
+            .field("SQPOLL", &(self.0 & IORING_SETUP_SQPOLL as isize > 0))
+            .field("CLAMP", &(self.0 & IORING_SETUP_CLAMP as isize > 0))
+            .finish()
+    }
+}
+
+/// Additional options for [`io_uring_enter`](fn.io_uring_enter.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct EnterFlags(isize);
+
+impl EnterFlags {
+    /// Creates new `EnterFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Wait for `min_complete` completions.
+    pub fn getevents(mut self) -> Self {
+        self.0 |= IORING_ENTER_GETEVENTS as isize;
+        self
+    }
+
+    /// Wake up the submission queue polling thread.
+    pub fn sq_wakeup(mut self) -> Self {
+        self.0 |= IORING_ENTER_SQ_WAKEUP as isize;
+        self
+    }
+
+    /// Wait until the submission queue polling thread made room in the submission queue.
+    pub fn sq_wait(mut self) -> Self {
+        self.0 |= IORING_ENTER_SQ_WAIT as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for EnterFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EnterFlags")
+            .field("GETEVENTS", &(self.0 & IORING_ENTER_GETEVENTS as isize > 0))
+            .field("SQ_WAKEUP", &(self.0 & IORING_ENTER_SQ_WAKEUP as isize > 0))
+            .field("SQ_WAIT", &(self.0 & IORING_ENTER_SQ_WAIT as isize > 0))
+            .finish()
+    }
+}
+
+/// An [`io_uring_register`](fn.io_uring_register.html) operation, the values of the C enum.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum RegisterOp {
+    /// Register an array of `iovec`s, for the fixed buffer operations.
+    Buffers = 0,
+    UnregisterBuffers = 1,
+    /// Register an array of descriptors, used by index with [`SqeFlags::fixed_file`](struct.SqeFlags.html#method.fixed_file).
+    Files = 2,
+    UnregisterFiles = 3,
+    /// Register an eventfd, which is signaled on every completion.
+    Eventfd = 4,
+    UnregisterEventfd = 5,
+}
+
+/// Create a new `io_uring` with at least `entries` submission queue entries, and return its descriptor.
+/// `params.flags` (and the fields they use) are read, and the kernel fills the rest, including the ring offsets to `mmap`.
+#[inline]
+pub unsafe fn io_uring_setup(entries: u32, params: &mut io_uring_params) -> io::Result<usize> {
+    let res = syscall!(
+        Syscalls::IoUringSetup,
+        entries as isize,
+        params as *mut io_uring_params as isize,
+    );
+    result!(res)
+}
+
+/// Submit `to_submit` entries from the submission queue, and with `getevents` wait for `min_complete` completions.
+/// The signal mask is atomically replaced with `sigmask` while waiting. Returns the number of entries submitted.
+#[inline]
+pub unsafe fn io_uring_enter<F: AsRawFd>(
+    fd: &F,
+    to_submit: u32,
+    min_complete: u32,
+    flags: EnterFlags,
+    sigmask: Option<&SigSet>,
+) -> io::Result<usize> {
+    let res = syscall!(
+        Syscalls::IoUringEnter,
+        fd.as_raw_fd() as isize,
+        to_submit as isize,
+        min_complete as isize,
+        flags.0,
+        sigmask
+            .map(|set| set as *const SigSet)
+            .unwrap_or(ptr::null()) as isize,
+        size_of::<SigSet>() as isize,
+    );
+    result!(res)
+}
+
+/// Register (or unregister) resources with the `io_uring` `fd`, `arg` points to an array of `nr_args` elements
+/// whose type depends on `opcode`.
+#[inline]
+pub unsafe fn io_uring_register<F: AsRawFd>(
+    fd: &F,
+    opcode: RegisterOp,
+    arg: *const u8,
+    nr_args: u32,
+) -> io::Result<usize> {
+    let res = syscall!(
+        Syscalls::IoUringRegister,
+        fd.as_raw_fd() as isize,
+        opcode as isize,
+        arg as isize,
+        nr_args as isize,
+    );
+    result!(res)
+}
+
+/// Options for how an [`Sqe`](struct.Sqe.html) is executed.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct SqeFlags(u8);
+
+impl SqeFlags {
+    /// Creates new `SqeFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The descriptor is an index into the [registered files](struct.IoUring.html#method.register_files).
+    pub fn fixed_file(mut self) -> Self {
+        self.0 |= IOSQE_FIXED_FILE;
+        self
+    }
+
+    /// Start only after all the previously submitted entries completed, and delay the later ones until this one completed.
+    pub fn drain(mut self) -> Self {
+        self.0 |= IOSQE_IO_DRAIN;
+        self
+    }
+
+    /// Start the next entry only after this one completed, and cancel it if this one fails.
+    pub fn link(mut self) -> Self {
+        self.0 |= IOSQE_IO_LINK;
+        self
+    }
+
+    /// Like `link`, but the next entry is started even if this one fails.
+    pub fn hardlink(mut self) -> Self {
+        self.0 |= IOSQE_IO_HARDLINK;
+        self
+    }
+
+    /// Always execute in a kernel worker thread, instead of first trying a non-blocking attempt.
+    pub fn force_async(mut self) -> Self {
+        self.0 |= IOSQE_ASYNC;
+        self
+    }
+}
+
+impl core::fmt::Debug for SqeFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SqeFlags")
+            .field("FIXED_FILE", &(self.0 & IOSQE_FIXED_FILE > 0))
+            .field("IO_DRAIN", &(self.0 & IOSQE_IO_DRAIN > 0))
+            .field("IO_LINK", &(self.0 & IOSQE_IO_LINK > 0))
+            .field("IO_HARDLINK", &(self.0 & IOSQE_IO_HARDLINK > 0))
+            .field("ASYNC", &(self.0 & IOSQE_ASYNC > 0))
+            .finish()
+    }
+}
+
+/// How the timespec of a [`timeout`](struct.Sqe.html#method.timeout) is interpreted.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct TimeoutFlags(u32);
+
+impl TimeoutFlags {
+    /// Creates new `TimeoutFlags`, the timeout is relative to now on the `Monotonic` clock.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The timeout is an absolute time.
+    pub fn abs(mut self) -> Self {
+        self.0 |= IORING_TIMEOUT_ABS;
+        self
+    }
+
+    /// Use the `Boottime` clock (Linux 5.15+).
+    pub fn boottime(mut self) -> Self {
+        self.0 |= IORING_TIMEOUT_BOOTTIME;
+        self
+    }
+
+    /// Use the `Realtime` clock (Linux 5.15+).
+    pub fn realtime(mut self) -> Self {
+        self.0 |= IORING_TIMEOUT_REALTIME;
+        self
+    }
+}
+
+impl core::fmt::Debug for TimeoutFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TimeoutFlags")
+            .field("ABS", &(self.0 & IORING_TIMEOUT_ABS > 0))
+            .field("BOOTTIME", &(self.0 & IORING_TIMEOUT_BOOTTIME > 0))
+            .field("REALTIME", &(self.0 & IORING_TIMEOUT_REALTIME > 0))
+            .finish()
+    }
+}
+
+/// A submission queue entry, the `struct io_uring_sqe` (its unions are named after their first member).
+///
+/// The memory an entry points to (buffers, paths, addresses and timespecs) isn't borrowed,
+/// it has to stay valid until the entry completed, which is why [`push`](struct.IoUring.html#method.push) is unsafe.
+/// Offsets of `None` use (and advance) the file position.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+static_assert!(size_of::<Sqe>() == 64);
+
+impl Sqe {
+    fn new(opcode: u8, fd: RawFd, addr: *const u8, len: usize, off: u64) -> Self {
+        Sqe {
+            opcode,
+            fd,
+            addr: addr as u64,
+            len: len as u32,
+            off,
+            ..Default::default()
+        }
+    }
+
+    /// Does nothing, only completes.
+    pub fn nop() -> Self {
+        Self::new(IORING_OP_NOP, -1, ptr::null(), 0, 0)
+    }
+
+    /// Read from `fd` into `buf`, like `pread(2)`.
+    pub fn read(fd: RawFd, buf: &mut [u8], offset: Option<u64>) -> Self {
+        let off = offset.unwrap_or(u64::MAX);
+        Self::new(IORING_OP_READ, fd, buf.as_mut_ptr(), buf.len(), off)
+    }
+
+    /// Write `buf` to `fd`, like `pwrite(2)`.
+    pub fn write(fd: RawFd, buf: &[u8], offset: Option<u64>) -> Self {
+        let off = offset.unwrap_or(u64::MAX);
+        Self::new(IORING_OP_WRITE, fd, buf.as_ptr(), buf.len(), off)
+    }
+
+    /// Read from `fd` into `bufs`, like `preadv(2)`.
+    pub fn readv(fd: RawFd, bufs: &mut [IoSliceMut<'_>], offset: Option<u64>) -> Self {
+        let off = offset.unwrap_or(u64::MAX);
+        let addr = bufs.as_mut_ptr() as *const u8;
+        Self::new(IORING_OP_READV, fd, addr, bufs.len(), off)
+    }
+
+    /// Write `bufs` to `fd`, like `pwritev(2)`.
+    pub fn writev(fd: RawFd, bufs: &[IoSlice<'_>], offset: Option<u64>) -> Self {
+        let off = offset.unwrap_or(u64::MAX);
+        let addr = bufs.as_ptr() as *const u8;
+        Self::new(IORING_OP_WRITEV, fd, addr, bufs.len(), off)
+    }
+
+    /// Like `read`, into `buf` which has to be inside the [registered buffer](struct.IoUring.html#method.register_buffers) `buf_index`.
+    pub fn read_fixed(fd: RawFd, buf: &mut [u8], offset: Option<u64>, buf_index: u16) -> Self {
+        let off = offset.unwrap_or(u64::MAX);
+        let mut sqe = Self::new(IORING_OP_READ_FIXED, fd, buf.as_mut_ptr(), buf.len(), off);
+        sqe.buf_index = buf_index;
+        sqe
+    }
+
+    /// Like `write`, from `buf` which has to be inside the [registered buffer](struct.IoUring.html#method.register_buffers) `buf_index`.
+    pub fn write_fixed(fd: RawFd, buf: &[u8], offset: Option<u64>, buf_index: u16) -> Self {
+        let off = offset.unwrap_or(u64::MAX);
+        let mut sqe = Self::new(IORING_OP_WRITE_FIXED, fd, buf.as_ptr(), buf.len(), off);
+        sqe.buf_index = buf_index;
+        sqe
+    }
+
+    /// Flush `fd` to the disk like `fsync(2)`, or like `fdatasync(2)` if `datasync`.
+    pub fn fsync(fd: RawFd, datasync: bool) -> Self {
+        let mut sqe = Self::new(IORING_OP_FSYNC, fd, ptr::null(), 0, 0);
+        if datasync {
+            sqe.rw_flags = IORING_FSYNC_DATASYNC;
+        }
+        sqe
+    }
+
+    /// Complete once with the events of `fd` that happened out of `events`, like a single `poll(2)`.
+    pub fn poll_add(fd: RawFd, events: PollEvents) -> Self {
+        let mut sqe = Self::new(IORING_OP_POLL_ADD, fd, ptr::null(), 0, 0);
+        sqe.rw_flags = events.bits() as u16 as u32;
+        sqe
+    }
+
+    /// Complete with `ETIME` when `timeout` expires, or successfully after `count` other completions (if it isn't 0).
+    pub fn timeout(timeout: &__kernel_timespec, count: u32, flags: TimeoutFlags) -> Self {
+        let addr = timeout as *const __kernel_timespec as *const u8;
+        let mut sqe = Self::new(IORING_OP_TIMEOUT, -1, addr, 1, count as u64);
+        sqe.rw_flags = flags.0;
+        sqe
+    }
+
+    /// Accept a connection on the socket `fd` like `accept4(2)`, completing with the new descriptor.
+    /// The peer address is written to `addr`, whose size `addrlen` points to, both can be null.
+    pub fn accept(fd: RawFd, addr: *mut u8, addrlen: *mut u32, flags: SockFlags) -> Self {
+        let mut sqe = Self::new(IORING_OP_ACCEPT, fd, addr, 0, addrlen as u64);
+        sqe.rw_flags = flags.0 as u32;
+        sqe
+    }
+
+    /// Connect the socket `fd` to the socket address `addr`, `addrlen` bytes long.
+    pub fn connect(fd: RawFd, addr: *const u8, addrlen: u32) -> Self {
+        Self::new(IORING_OP_CONNECT, fd, addr, 0, addrlen as u64)
+    }
+
+    /// Send `buf` on the socket `fd`, `flags` are the `MSG_*` flags of `send(2)`.
+    pub fn send(fd: RawFd, buf: &[u8], flags: u32) -> Self {
+        let mut sqe = Self::new(IORING_OP_SEND, fd, buf.as_ptr(), buf.len(), 0);
+        sqe.rw_flags = flags;
+        sqe
+    }
+
+    /// Receive into `buf` from the socket `fd`, `flags` are the `MSG_*` flags of `recv(2)`.
+    pub fn recv(fd: RawFd, buf: &mut [u8], flags: u32) -> Self {
+        let mut sqe = Self::new(IORING_OP_RECV, fd, buf.as_mut_ptr(), buf.len(), 0);
+        sqe.rw_flags = flags;
+        sqe
+    }
+
+    /// Open `path` relative to the directory `dirfd` (or `AT_FDCWD`) like `openat(2)`, completing with the new descriptor.
+    pub fn openat(dirfd: RawFd, path: &CStr, oflags: u32, mode: Option<u32>) -> Self {
+        let mode = mode.unwrap_or(0) as usize;
+        let mut sqe = Self::new(IORING_OP_OPENAT, dirfd, path.as_ptr() as *const u8, mode, 0);
+        sqe.rw_flags = oflags;
+        sqe
+    }
+
+    /// Close `fd`.
+    pub fn close(fd: RawFd) -> Self {
+        Self::new(IORING_OP_CLOSE, fd, ptr::null(), 0, 0)
+    }
+
+    /// Set the value that's passed back in the completion of this entry.
+    pub fn user_data(mut self, user_data: u64) -> Self {
+        self.user_data = user_data;
+        self
+    }
+
+    pub fn flags(mut self, flags: SqeFlags) -> Self {
+        self.flags = flags.0;
+        self
+    }
+}
+
+/// A completion queue entry, the `struct io_uring_cqe`.
+#[derive(Clone, Copy, Debug)]
+#[repr(transparent)]
+pub struct Cqe(io_uring_cqe);
+
+impl Cqe {
+    /// The [`user_data`](struct.Sqe.html#method.user_data) of the completed entry.
+    pub fn user_data(&self) -> u64 {
+        self.0.user_data
+    }
+
+    /// The result of the operation, like the return value of its syscall.
+    pub fn result(&self) -> io::Result<u32> {
+        if self.0.res < 0 {
+            Err(io::Error::from_raw_os_error(-self.0.res))
+        } else {
+            Ok(self.0.res as u32)
+        }
+    }
+
+    /// The `IORING_CQE_F_*` flags.
+    pub fn flags(&self) -> u32 {
+        self.0.flags
+    }
+
+    /// More completions will follow for the same entry.
+    pub fn more(&self) -> bool {
+        self.0.flags & IORING_CQE_F_MORE != 0
+    }
+}
+
+// A shared mapping of a part of the `io_uring`, unmapped on drop.
+struct RingMap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl RingMap {
+    fn new(fd: RawFd, len: usize, offset: u64) -> io::Result<Self> {
+        let prot = ProtFlags::new().read().write();
+        let flags = MapFlags::new().shared().populate();
+        let ptr = unsafe { mmap(ptr::null_mut(), len, prot, flags, Some(&fd), offset) }?;
+        Ok(RingMap { ptr, len })
+    }
+
+    // `offset` has to be one of the offsets the kernel returned for this mapping.
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.ptr.add(offset as usize) as *mut T
+    }
+}
+
+impl Drop for RingMap {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.len).ok();
+        }
+    }
+}
+
+/// An `io_uring` with its submission and completion queues mapped, the descriptor is closed on drop.
+///
+/// The kernel consumes the submission queue from its head and fills the completion queue at its tail,
+/// we only write the submission queue tail and the completion queue head.
+/// So the tail is published with a release store after the entries were written, and the kernel's indices are
+/// loaded with acquire, which orders reading the completions after it (and reusing submission slots after the kernel read them).
+pub struct IoUring {
+    fd: RawFd,
+    params: io_uring_params,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_flags: *const AtomicU32,
+    sq_mask: u32,
+    sqes: *mut Sqe,
+    // The tail including entries that were pushed but not submitted yet.
+    sqe_tail: u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    _sq_ring: RingMap,
+    _cq_ring: Option<RingMap>,
+    _sqes: RingMap,
+}
+
+// The rings are owned by the `IoUring`, and only accessed through `&mut self`.
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    /// Create a new `io_uring` with at least `entries` submission queue entries (a power of 2 up to 32768),
+    /// and twice as many completion queue entries.
+    pub fn new(entries: u32, flags: SetupFlags) -> io::Result<Self> {
+        let mut params = io_uring_params {
+            flags: flags.0 as u32,
+            ..Default::default()
+        };
+        let fd = unsafe { io_uring_setup(entries, &mut params) }? as RawFd;
+        match Self::map(fd, params) {
+            Ok(ring) => Ok(ring),
+            Err(e) => {
+                unsafe { close(&fd).ok() };
+                Err(e)
+            }
+        }
+    }
+
+    fn map(fd: RawFd, params: io_uring_params) -> io::Result<Self> {
+        let (sq_off, cq_off) = (params.sq_off, params.cq_off);
+        let sq_len = sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        // Since Linux 5.4 both rings are in a single mapping.
+        let single_mmap = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+        let sq_ring = if single_mmap {
+            RingMap::new(fd, cmp::max(sq_len, cq_len), IORING_OFF_SQ_RING as u64)?
+        } else {
+            RingMap::new(fd, sq_len, IORING_OFF_SQ_RING as u64)?
+        };
+        let cq_ring = if single_mmap {
+            None
+        } else {
+            Some(RingMap::new(fd, cq_len, IORING_OFF_CQ_RING as u64)?)
+        };
+        let sqes_len = params.sq_entries as usize * size_of::<Sqe>();
+        let sqes = RingMap::new(fd, sqes_len, IORING_OFF_SQES as u64)?;
+
+        unsafe {
+            // The submission queue is an array of indices into the entries, we always use the entry at the same index.
+            let array = sq_ring.at::<u32>(sq_off.array);
+            for i in 0..params.sq_entries {
+                *array.add(i as usize) = i;
+            }
+            let cq = cq_ring.as_ref().unwrap_or(&sq_ring);
+            let sq_tail = sq_ring.at::<AtomicU32>(sq_off.tail);
+            Ok(IoUring {
+                fd,
+                params,
+                sq_head: sq_ring.at(sq_off.head),
+                sq_tail,
+                sq_flags: sq_ring.at(sq_off.flags),
+                sq_mask: *sq_ring.at::<u32>(sq_off.ring_mask),
+                sqes: sqes.at(0),
+                sqe_tail: (*sq_tail).load(Ordering::Relaxed),
+                cq_head: cq.at(cq_off.head),
+                cq_tail: cq.at(cq_off.tail),
+                cq_mask: *cq.at::<u32>(cq_off.ring_mask),
+                cqes: cq.at(cq_off.cqes),
+                _sq_ring: sq_ring,
+                _cq_ring: cq_ring,
+                _sqes: sqes,
+            })
+        }
+    }
+
+    /// The parameters the kernel returned from [`io_uring_setup`](fn.io_uring_setup.html), e.g. the supported `features`.
+    pub fn params(&self) -> &io_uring_params {
+        &self.params
+    }
+
+    /// The number of entries that can be pushed before the submission queue is full.
+    pub fn sq_space_left(&self) -> u32 {
+        let head = unsafe { (*self.sq_head).load(Ordering::Acquire) };
+        self.params.sq_entries - self.sqe_tail.wrapping_sub(head)
+    }
+
+    /// Push `sqe` to the submission queue, it's passed to the kernel by the next [`submit`](#method.submit).
+    /// Fails with `EBUSY` if the submission queue is full.
+    ///
+    /// # Safety
+    /// The memory `sqe` points to has to stay valid (and not be accessed if the kernel writes to it) until its completion.
+    pub unsafe fn push(&mut self, sqe: &Sqe) -> io::Result<()> {
+        if self.sq_space_left() == 0 {
+            return Err(io::Error::from_raw_os_error(EBUSY as i32));
+        }
+        *self.sqes.add((self.sqe_tail & self.sq_mask) as usize) = *sqe;
+        self.sqe_tail = self.sqe_tail.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Submit the pushed entries, returns the number of entries submitted.
+    pub fn submit(&mut self) -> io::Result<usize> {
+        self.submit_and_wait(0)
+    }
+
+    /// Submit the pushed entries and wait until there are at least `want` completions.
+    /// Returns the number of entries submitted.
+    pub fn submit_and_wait(&mut self, want: u32) -> io::Result<usize> {
+        unsafe {
+            // Publish the entries, the release orders writing them before it.
+            (*self.sq_tail).store(self.sqe_tail, Ordering::Release);
+            let pending = self
+                .sqe_tail
+                .wrapping_sub((*self.sq_head).load(Ordering::Acquire));
+            let mut flags = EnterFlags::new();
+            if want > 0 {
+                flags = flags.getevents();
+            }
+            if self.params.flags & IORING_SETUP_SQPOLL != 0 {
+                // The polling thread could go to sleep right before the tail was published,
+                // so the flag has to be read after it.
+                fence(Ordering::SeqCst);
+                if (*self.sq_flags).load(Ordering::Relaxed) & IORING_SQ_NEED_WAKEUP != 0 {
+                    flags = flags.sq_wakeup();
+                } else if want == 0 {
+                    return Ok(pending as usize);
+                }
+            }
+            io_uring_enter(&self.fd, pending, want, flags, None)
+        }
+    }
+
+    /// Take the completions that are ready, each one frees its slot in the completion queue when it's yielded.
+    pub fn completions(&mut self) -> impl ExactSizeIterator<Item = Cqe> + '_ {
+        let (cq_head, cqes, mask) = (self.cq_head, self.cqes, self.cq_mask);
+        let head = unsafe { (*cq_head).load(Ordering::Relaxed) };
+        // The acquire orders reading the entries after the kernel wrote them.
+        let tail = unsafe { (*self.cq_tail).load(Ordering::Acquire) };
+        (0..tail.wrapping_sub(head)).map(move |i| unsafe {
+            let i = head.wrapping_add(i);
+            let cqe = *cqes.add((i & mask) as usize);
+            // The release orders reading the entry before the kernel can overwrite it.
+            (*cq_head).store(i.wrapping_add(1), Ordering::Release);
+            cqe
+        })
+    }
+
+    /// Register `bufs` as the fixed buffers (up to 1024, each up to 1GiB) used by [`Sqe::read_fixed`](struct.Sqe.html#method.read_fixed)
+    /// and [`Sqe::write_fixed`](struct.Sqe.html#method.write_fixed), their pages stay pinned until they're unregistered.
+    pub fn register_buffers(&self, bufs: &[IoSliceMut<'_>]) -> io::Result<()> {
+        // `IoSliceMut` has the same layout as `struct iovec`.
+        let arg = bufs.as_ptr() as *const u8;
+        let res =
+            unsafe { io_uring_register(&self.fd, RegisterOp::Buffers, arg, bufs.len() as u32) };
+        res.map(drop)
+    }
+
+    pub fn unregister_buffers(&self) -> io::Result<()> {
+        let res =
+            unsafe { io_uring_register(&self.fd, RegisterOp::UnregisterBuffers, ptr::null(), 0) };
+        res.map(drop)
+    }
+
+    /// Register `fds` as the fixed files, which entries with [`SqeFlags::fixed_file`](struct.SqeFlags.html#method.fixed_file)
+    /// refer to by their index. A `-1` leaves a slot empty.
+    pub fn register_files(&self, fds: &[RawFd]) -> io::Result<()> {
+        let arg = fds.as_ptr() as *const u8;
+        let res = unsafe { io_uring_register(&self.fd, RegisterOp::Files, arg, fds.len() as u32) };
+        res.map(drop)
+    }
+
+    pub fn unregister_files(&self) -> io::Result<()> {
+        let res =
+            unsafe { io_uring_register(&self.fd, RegisterOp::UnregisterFiles, ptr::null(), 0) };
+        res.map(drop)
+    }
+}
+
+impl core::fmt::Debug for IoUring {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("IoUring")
+            .field("fd", &self.fd)
+            .field("sq_entries", &self.params.sq_entries)
+            .field("cq_entries", &self.params.cq_entries)
+            .finish()
+    }
+}
+
+impl AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for IoUring {
+    fn drop(&mut self) {
+        unsafe {
+            close(&self.fd).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventfd::{EfdFlags, EventFd};
+    use crate::memfd::{MemFd, MfdFlags};
+    use crate::utils::timespec_from_duration;
+    use linux_sys::{AT_FDCWD, EBADF, ECANCELED, ETIME, O_RDONLY};
+    use std::time::{Duration, Instant};
+
+    fn complete_one(ring: &mut IoUring) -> Cqe {
+        ring.submit_and_wait(1).unwrap();
+        let mut completions = ring.completions();
+        assert_eq!(completions.len(), 1);
+        completions.next().unwrap()
+    }
+
+    #[test]
+    fn test_io_uring_nop() {
+        let mut ring = IoUring::new(4, SetupFlags::new()).unwrap();
+        assert_eq!(ring.params().sq_entries, 4);
+        assert_eq!(ring.sq_space_left(), 4);
+        for i in 0..4 {
+            unsafe { ring.push(&Sqe::nop().user_data(i)) }.unwrap();
+        }
+        let err = unsafe { ring.push(&Sqe::nop()) }.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EBUSY as i32));
+        assert_eq!(ring.submit_and_wait(4).unwrap(), 4);
+        let data: Vec<_> = ring.completions().map(|cqe| cqe.user_data()).collect();
+        assert_eq!(data, [0, 1, 2, 3]);
+        assert_eq!(ring.completions().len(), 0);
+
+        // The rings wrap around.
+        for i in 0..10 {
+            unsafe { ring.push(&Sqe::nop().user_data(i)) }.unwrap();
+            let cqe = complete_one(&mut ring);
+            assert_eq!((cqe.user_data(), cqe.result().unwrap()), (i, 0));
+        }
+    }
+
+    #[test]
+    fn test_io_uring_read_write() {
+        let file = MemFd::new(
+            CStr::from_bytes_with_nul(b"io_uring\0").unwrap(),
+            MfdFlags::new(),
+        )
+        .unwrap();
+        let fd = file.as_raw_fd();
+        let mut ring = IoUring::new(8, SetupFlags::new()).unwrap();
+
+        // The linked write completes before the read starts.
+        let data = b"hello io_uring";
+        let mut buf = [0u8; 14];
+        let mut vec_buf = [0u8; 5];
+        let mut iovecs = [IoSliceMut::new(&mut vec_buf)];
+        unsafe {
+            ring.push(&Sqe::write(fd, data, Some(0)).flags(SqeFlags::new().link()))
+                .unwrap();
+            ring.push(&Sqe::fsync(fd, true).flags(SqeFlags::new().link()))
+                .unwrap();
+            ring.push(&Sqe::read(fd, &mut buf, Some(0)).flags(SqeFlags::new().link()))
+                .unwrap();
+            ring.push(&Sqe::readv(fd, &mut iovecs, Some(6))).unwrap();
+        }
+        ring.submit_and_wait(4).unwrap();
+        let results: Vec<_> = ring
+            .completions()
+            .map(|cqe| cqe.result().unwrap())
+            .collect();
+        assert_eq!(results, [14, 0, 14, 5]);
+        assert_eq!(&buf, data);
+        assert_eq!(&vec_buf, b"io_ur");
+
+        // A failed entry cancels the ones linked to it.
+        unsafe {
+            ring.push(&Sqe::read(-1, &mut buf, None).flags(SqeFlags::new().link()))
+                .unwrap();
+            ring.push(&Sqe::nop()).unwrap();
+        }
+        ring.submit_and_wait(2).unwrap();
+        let errors: Vec<_> = ring
+            .completions()
+            .map(|cqe| cqe.result().unwrap_err().raw_os_error().unwrap())
+            .collect();
+        assert_eq!(errors, [EBADF as i32, ECANCELED as i32]);
+    }
+
+    #[test]
+    fn test_io_uring_register() {
+        let file = MemFd::new(
+            CStr::from_bytes_with_nul(b"io_uring\0").unwrap(),
+            MfdFlags::new(),
+        )
+        .unwrap();
+        let mut ring = IoUring::new(2, SetupFlags::new()).unwrap();
+        let mut buf = vec![0u8; 4096];
+        ring.register_buffers(&[IoSliceMut::new(&mut buf)]).unwrap();
+        ring.register_files(&[-1, file.as_raw_fd()]).unwrap();
+
+        buf[..4].copy_from_slice(b"ring");
+        let fixed = SqeFlags::new().fixed_file();
+        unsafe { ring.push(&Sqe::write_fixed(1, &buf[..4], Some(0), 0).flags(fixed)) }.unwrap();
+        assert_eq!(complete_one(&mut ring).result().unwrap(), 4);
+        unsafe { ring.push(&Sqe::read_fixed(1, &mut buf[8..16], Some(0), 0).flags(fixed)) }
+            .unwrap();
+        assert_eq!(complete_one(&mut ring).result().unwrap(), 4);
+        assert_eq!(&buf[8..12], b"ring");
+
+        // The empty slot isn't a file.
+        unsafe { ring.push(&Sqe::read(0, &mut buf, Some(0)).flags(fixed)) }.unwrap();
+        let err = complete_one(&mut ring).result().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EBADF as i32));
+
+        ring.unregister_files().unwrap();
+        ring.unregister_buffers().unwrap();
+        assert!(ring.unregister_buffers().is_err());
+    }
+
+    #[test]
+    fn test_io_uring_timeout_poll() {
+        let mut ring = IoUring::new(4, SetupFlags::new()).unwrap();
+        let ts = timespec_from_duration(Duration::from_millis(10));
+        let start = Instant::now();
+        unsafe { ring.push(&Sqe::timeout(&ts, 0, TimeoutFlags::new())) }.unwrap();
+        let err = complete_one(&mut ring).result().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ETIME as i32));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+
+        let fd = EventFd::new(0, EfdFlags::new()).unwrap();
+        unsafe { ring.push(&Sqe::poll_add(fd.as_raw_fd(), PollEvents::IN).user_data(7)) }.unwrap();
+        ring.submit().unwrap();
+        assert_eq!(ring.completions().len(), 0);
+        fd.add(1).unwrap();
+        let cqe = complete_one(&mut ring);
+        assert_eq!(cqe.user_data(), 7);
+        assert_eq!(cqe.result().unwrap(), PollEvents::IN.bits() as u32);
+    }
+
+    #[test]
+    fn test_io_uring_open_close() {
+        let mut ring = IoUring::new(2, SetupFlags::new()).unwrap();
+        let path = CStr::from_bytes_with_nul(b"/dev/null\0").unwrap();
+        unsafe { ring.push(&Sqe::openat(AT_FDCWD, path, O_RDONLY, None)) }.unwrap();
+        let fd = complete_one(&mut ring).result().unwrap();
+        unsafe { ring.push(&Sqe::close(fd as RawFd)) }.unwrap();
+        assert_eq!(complete_one(&mut ring).result().unwrap(), 0);
+        unsafe { ring.push(&Sqe::close(fd as RawFd)) }.unwrap();
+        let err = complete_one(&mut ring).result().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EBADF as i32));
+    }
+}
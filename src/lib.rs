@@ -1,9 +1,34 @@
 #![feature(io_error_uncategorized)]
+#![feature(linkage)]
 #![allow(clippy::missing_safety_doc)]
 
+#[cfg(feature = "alloc")]
+pub mod alloc;
 mod arch;
+#[cfg(feature = "async")]
+pub mod r#async;
+pub mod epoll;
+pub mod eventfd;
+pub mod futex;
+pub mod io_uring;
+pub mod membarrier;
+pub mod memfd;
+pub mod mempolicy;
+pub mod mman;
+pub mod pkey;
+pub mod poll;
+pub mod rseq;
+pub mod signal;
+pub mod signalfd;
 pub mod socket;
+pub mod stack_overflow;
+pub mod sync;
+pub mod time;
+pub mod timer;
+pub mod timerfd;
+pub mod userfaultfd;
 pub(crate) mod utils;
+pub mod vdso;
 
 use arch::Syscalls;
 use std::ffi::{CStr, OsString};
@@ -129,16 +154,19 @@ pub unsafe fn close<F: AsRawFd>(fd: &F) -> io::Result<usize> {
     result!(res)
 }
 
-// The timezone is useless. see man gettimeofday(2).
+// The timezone is useless. see man gettimeofday(2). This uses the vDSO when the kernel provides one.
 #[inline]
 pub fn gettimeofday() -> io::Result<timeval> {
     let mut time: MaybeUninit<timeval> = MaybeUninit::uninit();
-    let res = unsafe {
-        syscall!(
-            Syscalls::Gettimeofday,
-            time.as_mut_ptr() as isize,
-            ptr::null_mut::<()>() as isize
-        )
+    let res = match vdso::functions().gettimeofday {
+        Some(gettimeofday) => unsafe { gettimeofday(time.as_mut_ptr(), ptr::null_mut()) as isize },
+        None => unsafe {
+            syscall!(
+                Syscalls::Gettimeofday,
+                time.as_mut_ptr() as isize,
+                ptr::null_mut::<()>() as isize
+            )
+        },
     };
     if res < 0 {
         Err(io::Error::from_raw_os_error(-res as i32))
@@ -168,6 +196,13 @@ pub unsafe fn kill(pid: u32, signal: u32) -> io::Result<usize> {
     result!(res)
 }
 
+/// Returns a file descriptor referring to the process `pid` (Linux 5.3+).
+#[inline]
+pub unsafe fn pidfd_open(pid: u32) -> io::Result<usize> {
+    let res = syscall!(Syscalls::PidfdOpen, pid as isize, 0);
+    result!(res)
+}
+
 #[inline]
 pub unsafe fn getcwd() -> io::Result<PathBuf> {
     let mut buf = Vec::with_capacity(PATH_MAX as usize);
@@ -276,12 +311,38 @@ pub unsafe fn chmod(path: &CStr, mode: u32) -> io::Result<usize> {
     result!(res)
 }
 
+// On i686 this is `ftruncate64(2)`, so lengths past 2GiB work too.
+#[inline]
+pub unsafe fn ftruncate<F: AsRawFd>(fd: &F, len: u64) -> io::Result<()> {
+    #[cfg(target_arch = "x86")]
+    let res = syscall!(
+        Syscalls::Ftruncate64,
+        fd.as_raw_fd() as isize,
+        len as u32 as isize,
+        (len >> 32) as u32 as isize,
+    );
+    #[cfg(not(target_arch = "x86"))]
+    let res = syscall!(Syscalls::Ftruncate, fd.as_raw_fd() as isize, len as isize);
+    result_none!(res)
+}
+
 #[inline]
 pub unsafe fn getuid() -> io::Result<u32> {
     let res = syscall!(Syscalls::Getuid);
     result!(res)
 }
 
+// These can't fail, see getpid(2) and gettid(2).
+#[inline]
+pub fn getpid() -> u32 {
+    unsafe { syscall!(Syscalls::Getpid) as u32 }
+}
+
+#[inline]
+pub fn gettid() -> u32 {
+    unsafe { syscall!(Syscalls::Gettid) as u32 }
+}
+
 // TODO: Not thread safe. see open question 15.
 #[inline]
 pub unsafe fn setuid(id: u32) -> io::Result<()> {
@@ -363,7 +424,7 @@ mod tests {
     use std::sync::atomic::{AtomicU8, Ordering};
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    struct TestFile(File, PathBuf, bool);
+    pub(crate) struct TestFile(File, PathBuf, bool);
 
     impl TestFile {
         pub fn new() -> io::Result<Self> {
@@ -528,6 +589,23 @@ mod tests {
         assert_eq!(res, None);
     }
 
+    #[test]
+    fn test_getpid() {
+        assert_eq!(super::getpid(), std::process::id());
+        let tid = std::thread::spawn(super::gettid).join().unwrap();
+        assert_ne!(tid, super::getpid());
+        assert_ne!(tid, super::gettid());
+    }
+
+    #[test]
+    fn test_pidfd_open() {
+        let fd = unsafe { super::pidfd_open(std::process::id()) }.unwrap();
+        let fd = unsafe { File::from_raw_fd(fd as i32) };
+        let err = unsafe { super::pidfd_open(0) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        drop(fd);
+    }
+
     #[test]
     fn test_rand() {
         let mut buf = [0u8; 32];
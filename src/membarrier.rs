@@ -0,0 +1,95 @@
+use crate::arch::Syscalls;
+use crate::{result, syscall};
+use std::io;
+
+// `enum membarrier_cmd` and `enum membarrier_cmd_flag` are C enums, so bindgen doesn't give us plain constants.
+const MEMBARRIER_CMD_QUERY: u32 = 0;
+const MEMBARRIER_CMD_FLAG_CPU: u32 = 1 << 0;
+
+/// A `membarrier(2)` command, every command is a single bit so they can be [queried](fn.membarrier_query.html) together.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum MembarrierCmd {
+    /// A memory barrier on all running threads of all processes, slow (it waits for an RCU grace period).
+    Global = 1 << 0,
+    /// A memory barrier on the running threads of all the processes that registered with `RegisterGlobalExpedited`.
+    GlobalExpedited = 1 << 1,
+    RegisterGlobalExpedited = 1 << 2,
+    /// A memory barrier on the running threads of this process, requires `RegisterPrivateExpedited` first.
+    PrivateExpedited = 1 << 3,
+    RegisterPrivateExpedited = 1 << 4,
+    /// Like `PrivateExpedited`, and also serializes the instruction stream (for JITs).
+    PrivateExpeditedSyncCore = 1 << 5,
+    RegisterPrivateExpeditedSyncCore = 1 << 6,
+    /// Restart the rseq critical sections of the running threads of this process (Linux 5.10+).
+    PrivateExpeditedRseq = 1 << 7,
+    RegisterPrivateExpeditedRseq = 1 << 8,
+    /// Returns the registrations of this process instead of executing a barrier (Linux 6.3+).
+    GetRegistrations = 1 << 9,
+}
+
+/// The set of commands returned by [`membarrier_query`](fn.membarrier_query.html).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Default)]
+pub struct MembarrierCmds(u32);
+
+impl MembarrierCmds {
+    pub fn contains(self, cmd: MembarrierCmd) -> bool {
+        self.0 & cmd as u32 != 0
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+/// Execute `cmd`, `cpu` restricts `PrivateExpeditedRseq` to a single CPU.
+/// The expedited commands require their `Register*` command to be executed first, otherwise they fail with `EPERM`.
+/// Returns the result of the command, which is only meaningful for `GetRegistrations`.
+#[inline]
+pub fn membarrier(cmd: MembarrierCmd, cpu: Option<u32>) -> io::Result<u32> {
+    let (flags, cpu) = match cpu {
+        Some(cpu) => (MEMBARRIER_CMD_FLAG_CPU, cpu),
+        None => (0, 0),
+    };
+    let res = unsafe {
+        syscall!(
+            Syscalls::Membarrier,
+            cmd as isize,
+            flags as isize,
+            cpu as isize
+        )
+    };
+    result!(res)
+}
+
+/// Returns the commands supported by the kernel, fails with `ENOSYS` if `membarrier(2)` isn't supported at all.
+#[inline]
+pub fn membarrier_query() -> io::Result<MembarrierCmds> {
+    let res = unsafe { syscall!(Syscalls::Membarrier, MEMBARRIER_CMD_QUERY as isize, 0) };
+    result!(res).map(MembarrierCmds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_membarrier() {
+        let cmds = membarrier_query().unwrap();
+        assert!(cmds.contains(MembarrierCmd::PrivateExpedited));
+        assert!(cmds.contains(MembarrierCmd::RegisterPrivateExpedited));
+
+        // Private expedited barriers need registering first.
+        let registered = membarrier(MembarrierCmd::GetRegistrations, None).ok();
+        if matches!(registered, Some(r) if r & MembarrierCmd::RegisterPrivateExpedited as u32 == 0)
+        {
+            let err = membarrier(MembarrierCmd::PrivateExpedited, None).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+        membarrier(MembarrierCmd::RegisterPrivateExpedited, None).unwrap();
+        membarrier(MembarrierCmd::PrivateExpedited, None).unwrap();
+
+        let err = membarrier(MembarrierCmd::Global, Some(0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
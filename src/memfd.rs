@@ -0,0 +1,309 @@
+use crate::arch::Syscalls;
+use crate::mman::HugePageSize;
+use crate::{close, ftruncate, result, result_none, syscall};
+use std::ffi::CStr;
+use std::io;
+use std::ops::{BitOr, BitOrAssign};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+
+use linux_sys::{
+    F_ADD_SEALS, F_GET_SEALS, F_SEAL_FUTURE_WRITE, F_SEAL_GROW, F_SEAL_SEAL, F_SEAL_SHRINK,
+    F_SEAL_WRITE, MFD_ALLOW_SEALING, MFD_CLOEXEC, MFD_HUGETLB,
+};
+
+// These are newer (Linux 6.3) than our headers.
+const MFD_NOEXEC_SEAL: u32 = 0x0008;
+const MFD_EXEC: u32 = 0x0010;
+const F_SEAL_EXEC: u32 = 0x0020;
+
+/// Options for [`memfd_create`](fn.memfd_create.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct MfdFlags(isize);
+
+impl MfdFlags {
+    /// Creates new `MfdFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set close-on-exec on the new descriptor.
+    pub fn cloexec(mut self) -> Self {
+        self.0 |= MFD_CLOEXEC as isize;
+        self
+    }
+
+    /// Allow adding seals, otherwise the file starts with `Seals::SEAL`.
+    pub fn allow_sealing(mut self) -> Self {
+        self.0 |= MFD_ALLOW_SEALING as isize;
+        self
+    }
+
+    /// Back the file with huge pages from hugetlbfs.
+    pub fn hugetlb(mut self) -> Self {
+        self.0 |= MFD_HUGETLB as isize;
+        self
+    }
+
+    /// Back the file with huge pages of `size`.
+    pub fn hugetlb_size(mut self, size: HugePageSize) -> Self {
+        self.0 |= (MFD_HUGETLB | size.bits()) as isize;
+        self
+    }
+
+    /// Make the file non executable and seal that with `Seals::EXEC`, implies `allow_sealing` (Linux 6.3+).
+    pub fn noexec_seal(mut self) -> Self {
+        self.0 |= MFD_NOEXEC_SEAL as isize;
+        self
+    }
+
+    /// Make the file executable, required when `vm.memfd_noexec` defaults to non executable (Linux 6.3+).
+    pub fn exec(mut self) -> Self {
+        self.0 |= MFD_EXEC as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for MfdFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MfdFlags")
+            .field("CLOEXEC", &(self.0 & MFD_CLOEXEC as isize > 0))
+            .field("ALLOW_SEALING", &(self.0 & MFD_ALLOW_SEALING as isize > 0))
+            .field("HUGETLB", &(self.0 & MFD_HUGETLB as isize > 0))
+            .field("NOEXEC_SEAL", &(self.0 & MFD_NOEXEC_SEAL as isize > 0))
+            .field("EXEC", &(self.0 & MFD_EXEC as isize > 0))
+            .finish()
+    }
+}
+
+/// Create an anonymous file that lives in memory, `name` is only used for `/proc/self/fd` and can't contain `/`.
+#[inline]
+pub unsafe fn memfd_create(name: &CStr, flags: MfdFlags) -> io::Result<usize> {
+    let res = syscall!(Syscalls::MemfdCreate, name.as_ptr() as isize, flags.0);
+    result!(res)
+}
+
+/// A set of file seals, see `F_ADD_SEALS` in `fcntl(2)`.
+/// Seals can only be added, and once `SEAL` is set the set can't change anymore.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct Seals(u32);
+
+impl Seals {
+    /// Prevent adding more seals.
+    pub const SEAL: Seals = Seals(F_SEAL_SEAL);
+    /// Prevent shrinking the file.
+    pub const SHRINK: Seals = Seals(F_SEAL_SHRINK);
+    /// Prevent growing the file.
+    pub const GROW: Seals = Seals(F_SEAL_GROW);
+    /// Prevent writing to the file, this fails if there are writable shared mappings of it.
+    pub const WRITE: Seals = Seals(F_SEAL_WRITE);
+    /// Prevent new writable mappings and writes, existing writable mappings keep working (Linux 5.1+).
+    pub const FUTURE_WRITE: Seals = Seals(F_SEAL_FUTURE_WRITE);
+    /// Prevent changing the executable bits of the file (Linux 6.3+).
+    pub const EXEC: Seals = Seals(F_SEAL_EXEC);
+
+    /// An empty set of seals.
+    pub const fn empty() -> Self {
+        Seals(0)
+    }
+
+    /// Everything that makes the content immutable: `SHRINK`, `GROW`, `WRITE`, and `SEAL` so it stays that way.
+    pub const fn read_only() -> Self {
+        Seals(F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE | F_SEAL_SEAL)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns `None` if `bits` contains unknown seals.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        let all = F_SEAL_SEAL
+            | F_SEAL_SHRINK
+            | F_SEAL_GROW
+            | F_SEAL_WRITE
+            | F_SEAL_FUTURE_WRITE
+            | F_SEAL_EXEC;
+        if bits & !all == 0 {
+            Some(Seals(bits))
+        } else {
+            None
+        }
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if every seal in `other` is also in `self`.
+    pub const fn contains(self, other: Seals) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Seals {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Seals(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Seals {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl core::fmt::Debug for Seals {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Seals")
+            .field("SEAL", &self.contains(Seals::SEAL))
+            .field("SHRINK", &self.contains(Seals::SHRINK))
+            .field("GROW", &self.contains(Seals::GROW))
+            .field("WRITE", &self.contains(Seals::WRITE))
+            .field("FUTURE_WRITE", &self.contains(Seals::FUTURE_WRITE))
+            .field("EXEC", &self.contains(Seals::EXEC))
+            .finish()
+    }
+}
+
+/// Add `seals` to the file, it has to be a memfd (or shmem/hugetlbfs file) that allows sealing.
+#[inline]
+pub unsafe fn add_seals<F: AsRawFd>(fd: &F, seals: Seals) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::Fcntl,
+        fd.as_raw_fd() as isize,
+        F_ADD_SEALS as isize,
+        seals.0 as isize,
+    );
+    result_none!(res)
+}
+
+/// Get the seals of the file, fails with `EINVAL` if the file doesn't support sealing.
+#[inline]
+pub unsafe fn get_seals<F: AsRawFd>(fd: &F) -> io::Result<Seals> {
+    let res = syscall!(
+        Syscalls::Fcntl,
+        fd.as_raw_fd() as isize,
+        F_GET_SEALS as isize,
+    );
+    result!(res).map(|bits: u32| Seals(bits))
+}
+
+/// An in-memory file created by `memfd_create(2)`, closed on drop.
+#[derive(Debug)]
+pub struct MemFd(RawFd);
+
+impl MemFd {
+    pub fn new(name: &CStr, flags: MfdFlags) -> io::Result<Self> {
+        let fd = unsafe { memfd_create(name, flags) }?;
+        Ok(MemFd(fd as RawFd))
+    }
+
+    /// Resize the file, new space reads as zeros.
+    pub fn set_len(&self, len: u64) -> io::Result<()> {
+        unsafe { ftruncate(&self.0, len) }
+    }
+
+    pub fn add_seals(&self, seals: Seals) -> io::Result<()> {
+        unsafe { add_seals(&self.0, seals) }
+    }
+
+    pub fn seals(&self) -> io::Result<Seals> {
+        unsafe { get_seals(&self.0) }
+    }
+}
+
+impl AsRawFd for MemFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl IntoRawFd for MemFd {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.0;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for MemFd {
+    fn drop(&mut self) {
+        unsafe {
+            close(&self.0).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mman::{MapFlags, Mapping, ProtFlags};
+    use crate::{read, write};
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::io::FromRawFd;
+
+    fn name() -> &'static CStr {
+        CStr::from_bytes_with_nul(b"syscalls-rs\0").unwrap()
+    }
+
+    #[test]
+    fn test_memfd_seal_read_only() {
+        let mut fd = MemFd::new(name(), MfdFlags::new().cloexec().allow_sealing()).unwrap();
+        assert_eq!(fd.seals().unwrap(), Seals::empty());
+        assert_eq!(unsafe { write(&mut fd, b"hello") }.unwrap(), 5);
+        fd.set_len(4096).unwrap();
+
+        fd.add_seals(Seals::read_only()).unwrap();
+        assert_eq!(fd.seals().unwrap(), Seals::read_only());
+        let err = unsafe { write(&mut fd, b"world") }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = fd.set_len(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = fd.add_seals(Seals::EXEC).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let prot = ProtFlags::new().read().write();
+        let err = unsafe { Mapping::new(4096, prot, MapFlags::new().shared(), Some(&fd), 0) }
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let mut file = unsafe { File::from_raw_fd(fd.into_raw_fd()) };
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0u8; 5];
+        assert_eq!(unsafe { read(&file, &mut buf) }.unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn test_memfd_future_write() {
+        let fd = MemFd::new(name(), MfdFlags::new().allow_sealing()).unwrap();
+        fd.set_len(4096).unwrap();
+        let prot = ProtFlags::new().read().write();
+        let mut map =
+            unsafe { Mapping::new(4096, prot, MapFlags::new().shared(), Some(&fd), 0) }.unwrap();
+        // A writable mapping prevents `WRITE` but not `FUTURE_WRITE`.
+        let err = fd.add_seals(Seals::WRITE).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(linux_sys::EBUSY as i32));
+        fd.add_seals(Seals::FUTURE_WRITE).unwrap();
+        map.as_mut_slice().unwrap()[0] = 1;
+        let seals = fd.seals().unwrap();
+        assert!(seals.contains(Seals::FUTURE_WRITE));
+        assert!(!seals.contains(Seals::WRITE));
+    }
+
+    #[test]
+    fn test_memfd_no_sealing() {
+        let fd = MemFd::new(name(), MfdFlags::new()).unwrap();
+        assert_eq!(fd.seals().unwrap(), Seals::SEAL);
+        let err = fd.add_seals(Seals::GROW).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let proc = File::open("/proc/self/stat").unwrap();
+        let err = unsafe { get_seals(&proc) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(Seals::from_bits(1 << 10), None);
+        assert_eq!(Seals::from_bits(3), Some(Seals::SEAL | Seals::SHRINK));
+    }
+}
@@ -0,0 +1,457 @@
+//! NUMA memory policies, see `set_mempolicy(2)` and `mbind(2)`.
+use crate::arch::Syscalls;
+use crate::{result, result_none, syscall};
+use std::io;
+use std::iter::FromIterator;
+use std::mem::size_of;
+use std::os::raw::c_ulong;
+use std::ptr;
+
+use linux_sys::{
+    EINVAL, MPOL_F_ADDR, MPOL_F_MEMS_ALLOWED, MPOL_F_NODE, MPOL_F_NUMA_BALANCING,
+    MPOL_F_RELATIVE_NODES, MPOL_F_STATIC_NODES, MPOL_MF_MOVE, MPOL_MF_MOVE_ALL, MPOL_MF_STRICT,
+    MPOL_MODE_FLAGS,
+};
+
+/// The biggest node number + 1 a [`NodeMask`](struct.NodeMask.html) can hold, the kernel's maximum (`NODES_SHIFT` of 10).
+pub const MAX_NUMNODES: u32 = 1024;
+
+const BITS_PER_WORD: u32 = 8 * size_of::<c_ulong>() as u32;
+const NODEMASK_WORDS: usize = (MAX_NUMNODES / BITS_PER_WORD) as usize;
+
+/// A set of NUMA nodes, in the kernel's `unsigned long` bitmap layout.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+#[repr(transparent)]
+pub struct NodeMask([c_ulong; NODEMASK_WORDS]);
+
+impl NodeMask {
+    /// Creates a set without any nodes.
+    pub const fn empty() -> Self {
+        NodeMask([0; NODEMASK_WORDS])
+    }
+
+    /// Adds `node` to the set.
+    ///
+    /// # Panics
+    /// If `node` isn't below `MAX_NUMNODES`.
+    pub fn insert(&mut self, node: u32) {
+        let (word, bit) = Self::position(node);
+        self.0[word] |= bit;
+    }
+
+    /// Removes `node` from the set.
+    pub fn remove(&mut self, node: u32) {
+        let (word, bit) = Self::position(node);
+        self.0[word] &= !bit;
+    }
+
+    /// Checks if `node` is in the set.
+    pub fn contains(&self, node: u32) -> bool {
+        let (word, bit) = Self::position(node);
+        self.0[word] & bit != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&word| word == 0)
+    }
+
+    /// Iterates over the nodes in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..MAX_NUMNODES).filter(move |&node| self.contains(node))
+    }
+
+    fn position(node: u32) -> (usize, c_ulong) {
+        assert!(node < MAX_NUMNODES, "Invalid node: {}", node);
+        ((node / BITS_PER_WORD) as usize, 1 << (node % BITS_PER_WORD))
+    }
+
+    // The kernel reads `maxnode - 1` bits, see `get_nodes` in mm/mempolicy.c.
+    fn maxnode() -> isize {
+        MAX_NUMNODES as isize + 1
+    }
+}
+
+impl Default for NodeMask {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl FromIterator<u32> for NodeMask {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> Self {
+        let mut mask = NodeMask::empty();
+        iter.into_iter().for_each(|node| mask.insert(node));
+        mask
+    }
+}
+
+impl core::fmt::Debug for NodeMask {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+fn mask_ptr(nodes: Option<&NodeMask>) -> isize {
+    nodes.map_or(ptr::null(), |nodes| nodes as *const NodeMask) as isize
+}
+
+/// A memory policy mode, `enum` in the kernel's `linux/mempolicy.h`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum MemPolicyMode {
+    /// Use the policy of the thread, or allocate on the local node if that's the thread's policy.
+    Default = 0,
+    /// Prefer the single node in the mask, falling back to other nodes.
+    Preferred = 1,
+    /// Only allocate on the nodes in the mask.
+    Bind = 2,
+    /// Interleave the pages across the nodes in the mask.
+    Interleave = 3,
+    /// Allocate on the node of the CPU that triggered the allocation (Linux 3.8+).
+    Local = 4,
+    /// Prefer the nodes in the mask, falling back to other nodes (Linux 5.15+).
+    PreferredMany = 5,
+    /// Interleave the pages according to `/sys/kernel/mm/mempolicy/weighted_interleave` (Linux 6.9+).
+    WeightedInterleave = 6,
+}
+
+impl MemPolicyMode {
+    fn from_raw(mode: i32) -> io::Result<Self> {
+        Ok(match mode & !(MPOL_MODE_FLAGS as i32) {
+            0 => MemPolicyMode::Default,
+            1 => MemPolicyMode::Preferred,
+            2 => MemPolicyMode::Bind,
+            3 => MemPolicyMode::Interleave,
+            4 => MemPolicyMode::Local,
+            5 => MemPolicyMode::PreferredMany,
+            6 => MemPolicyMode::WeightedInterleave,
+            _ => return Err(io::Error::from_raw_os_error(EINVAL as i32)),
+        })
+    }
+}
+
+/// Optional flags of a [`MemPolicyMode`](enum.MemPolicyMode.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct ModeFlags(isize);
+
+impl ModeFlags {
+    /// Creates new `ModeFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The node numbers are physical, and aren't remapped when the allowed nodes of the cpuset change.
+    pub fn static_nodes(mut self) -> Self {
+        self.0 |= MPOL_F_STATIC_NODES as isize;
+        self
+    }
+
+    /// The node numbers are relative to the allowed nodes of the cpuset.
+    pub fn relative_nodes(mut self) -> Self {
+        self.0 |= MPOL_F_RELATIVE_NODES as isize;
+        self
+    }
+
+    /// Let NUMA balancing migrate pages within the allowed nodes, with `Bind` (Linux 5.12+).
+    pub fn numa_balancing(mut self) -> Self {
+        self.0 |= MPOL_F_NUMA_BALANCING as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for ModeFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ModeFlags")
+            .field("STATIC_NODES", &(self.0 & MPOL_F_STATIC_NODES as isize > 0))
+            .field(
+                "RELATIVE_NODES",
+                &(self.0 & MPOL_F_RELATIVE_NODES as isize > 0),
+            )
+            .field(
+                "NUMA_BALANCING",
+                &(self.0 & MPOL_F_NUMA_BALANCING as isize > 0),
+            )
+            .finish()
+    }
+}
+
+/// Options for [`mbind`](fn.mbind.html), and which pages to move for [`move_pages`](fn.move_pages.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct MoveFlags(isize);
+
+impl MoveFlags {
+    /// Creates new `MoveFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Fail with `EIO` if existing pages don't follow the policy (and couldn't be moved).
+    pub fn strict(mut self) -> Self {
+        self.0 |= MPOL_MF_STRICT as isize;
+        self
+    }
+
+    /// Move existing pages that are only mapped by this process to follow the policy.
+    pub fn migrate(mut self) -> Self {
+        self.0 |= MPOL_MF_MOVE as isize;
+        self
+    }
+
+    /// Move existing pages even if they're shared with other processes, requires `CAP_SYS_NICE`.
+    pub fn migrate_all(mut self) -> Self {
+        self.0 |= MPOL_MF_MOVE_ALL as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for MoveFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MoveFlags")
+            .field("STRICT", &(self.0 & MPOL_MF_STRICT as isize > 0))
+            .field("MOVE", &(self.0 & MPOL_MF_MOVE as isize > 0))
+            .field("MOVE_ALL", &(self.0 & MPOL_MF_MOVE_ALL as isize > 0))
+            .finish()
+    }
+}
+
+/// Set the memory policy of the calling thread, `nodes` should be `None` for `Default` and `Local`.
+#[inline]
+pub unsafe fn set_mempolicy(
+    mode: MemPolicyMode,
+    flags: ModeFlags,
+    nodes: Option<&NodeMask>,
+) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::SetMempolicy,
+        mode as isize | flags.0,
+        mask_ptr(nodes),
+        NodeMask::maxnode(),
+    );
+    result_none!(res)
+}
+
+/// Set the memory policy of the pages in `addr..addr + len`, `addr` must be page aligned.
+#[inline]
+pub unsafe fn mbind(
+    addr: *mut u8,
+    len: usize,
+    mode: MemPolicyMode,
+    flags: ModeFlags,
+    nodes: Option<&NodeMask>,
+    move_flags: MoveFlags,
+) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::Mbind,
+        addr as isize,
+        len as isize,
+        mode as isize | flags.0,
+        mask_ptr(nodes),
+        NodeMask::maxnode(),
+        move_flags.0,
+    );
+    result_none!(res)
+}
+
+#[inline]
+unsafe fn get_mempolicy(
+    mode: &mut i32,
+    nodes: Option<&mut NodeMask>,
+    addr: *mut u8,
+    flags: u32,
+) -> io::Result<()> {
+    let nodes = nodes.map_or(ptr::null_mut(), |nodes| nodes as *mut NodeMask);
+    let res = syscall!(
+        Syscalls::GetMempolicy,
+        mode as *mut i32 as isize,
+        nodes as isize,
+        NodeMask::maxnode(),
+        addr as isize,
+        flags as isize,
+    );
+    result_none!(res)
+}
+
+/// Returns the memory policy of the calling thread.
+pub fn thread_mempolicy() -> io::Result<(MemPolicyMode, NodeMask)> {
+    let (mut mode, mut nodes) = (0, NodeMask::empty());
+    unsafe { get_mempolicy(&mut mode, Some(&mut nodes), ptr::null_mut(), 0) }?;
+    Ok((MemPolicyMode::from_raw(mode)?, nodes))
+}
+
+/// Returns the memory policy of the mapping that contains `addr`.
+pub unsafe fn addr_mempolicy(addr: *mut u8) -> io::Result<(MemPolicyMode, NodeMask)> {
+    let (mut mode, mut nodes) = (0, NodeMask::empty());
+    get_mempolicy(&mut mode, Some(&mut nodes), addr, MPOL_F_ADDR)?;
+    Ok((MemPolicyMode::from_raw(mode)?, nodes))
+}
+
+/// Returns the node the page at `addr` is on.
+pub unsafe fn addr_node(addr: *mut u8) -> io::Result<u32> {
+    let mut node = 0;
+    get_mempolicy(&mut node, None, addr, MPOL_F_ADDR | MPOL_F_NODE)?;
+    Ok(node as u32)
+}
+
+/// Returns the nodes the calling thread is allowed to use (by its cpuset).
+pub fn mems_allowed() -> io::Result<NodeMask> {
+    let (mut mode, mut nodes) = (0, NodeMask::empty());
+    unsafe {
+        get_mempolicy(
+            &mut mode,
+            Some(&mut nodes),
+            ptr::null_mut(),
+            MPOL_F_MEMS_ALLOWED,
+        )
+    }?;
+    Ok(nodes)
+}
+
+/// Move the pages of process `pid` (0 for the calling process) to `nodes`, or only query their node if `nodes` is `None`.
+/// `status` is filled with the node of every page, or a negative errno if it couldn't be moved, see [`page_status`](fn.page_status.html).
+/// Returns the number of pages that weren't moved for non fatal reasons.
+#[inline]
+pub unsafe fn move_pages(
+    pid: u32,
+    pages: &[*mut u8],
+    nodes: Option<&[i32]>,
+    status: &mut [i32],
+    flags: MoveFlags,
+) -> io::Result<usize> {
+    if status.len() != pages.len() || nodes.is_some_and(|nodes| nodes.len() != pages.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "`nodes` and `status` must have an entry for every page",
+        ));
+    }
+    let res = syscall!(
+        Syscalls::MovePages,
+        pid as isize,
+        pages.len() as isize,
+        pages.as_ptr() as isize,
+        nodes.map_or(ptr::null(), |nodes| nodes.as_ptr()) as isize,
+        status.as_mut_ptr() as isize,
+        flags.0,
+    );
+    result!(res)
+}
+
+/// Decode an entry of the `status` of [`move_pages`](fn.move_pages.html), e.g. `ENOENT` if the page isn't allocated.
+pub fn page_status(status: i32) -> io::Result<u32> {
+    if status < 0 {
+        Err(io::Error::from_raw_os_error(-status))
+    } else {
+        Ok(status as u32)
+    }
+}
+
+/// Move all the pages of process `pid` (0 for the calling process) that are on `old_nodes` to `new_nodes`.
+/// Returns the number of pages that couldn't be moved.
+#[inline]
+pub unsafe fn migrate_pages(
+    pid: u32,
+    old_nodes: &NodeMask,
+    new_nodes: &NodeMask,
+) -> io::Result<usize> {
+    let res = syscall!(
+        Syscalls::MigratePages,
+        pid as isize,
+        NodeMask::maxnode(),
+        old_nodes as *const NodeMask as isize,
+        new_nodes as *const NodeMask as isize,
+    );
+    result!(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::PAGE_SIZE;
+    use crate::mman::{Mapping, ProtFlags};
+    use linux_sys::ENOENT;
+
+    fn node0() -> NodeMask {
+        Some(0).into_iter().collect()
+    }
+
+    #[test]
+    fn test_nodemask() {
+        let mut mask: NodeMask = vec![0, 3, 1000].into_iter().collect();
+        assert!(mask.contains(3) && !mask.contains(2));
+        mask.remove(3);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [0, 1000]);
+        assert_eq!(format!("{:?}", mask), "{0, 1000}");
+        assert!(NodeMask::empty().is_empty());
+    }
+
+    #[test]
+    fn test_thread_mempolicy() {
+        // Every machine has node 0, even without NUMA.
+        assert!(mems_allowed().unwrap().contains(0));
+        assert_eq!(
+            thread_mempolicy().unwrap(),
+            (MemPolicyMode::Default, NodeMask::empty())
+        );
+
+        unsafe { set_mempolicy(MemPolicyMode::Bind, ModeFlags::new(), Some(&node0())) }.unwrap();
+        assert_eq!(thread_mempolicy().unwrap(), (MemPolicyMode::Bind, node0()));
+        unsafe { set_mempolicy(MemPolicyMode::Default, ModeFlags::new(), None) }.unwrap();
+
+        // Nodes that don't exist.
+        let mask: NodeMask = Some(MAX_NUMNODES - 1).into_iter().collect();
+        let err = unsafe { set_mempolicy(MemPolicyMode::Bind, ModeFlags::new(), Some(&mask)) }
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_mbind() {
+        let map = Mapping::anonymous(PAGE_SIZE * 2, ProtFlags::new().read().write()).unwrap();
+        let addr = map.as_ptr();
+        let flags = MoveFlags::new().strict().migrate();
+        unsafe {
+            mbind(
+                addr,
+                map.len(),
+                MemPolicyMode::Bind,
+                ModeFlags::new(),
+                Some(&node0()),
+                flags,
+            )
+        }
+        .unwrap();
+        assert_eq!(
+            unsafe { addr_mempolicy(addr) }.unwrap(),
+            (MemPolicyMode::Bind, node0())
+        );
+        assert_eq!(unsafe { addr_node(addr) }.unwrap(), 0);
+
+        // Only the first page is allocated.
+        unsafe { ptr::write_volatile(addr, 1) };
+        let pages = [addr, unsafe { addr.add(PAGE_SIZE) }];
+        let mut status = [0; 2];
+        let res = unsafe { move_pages(0, &pages, None, &mut status, MoveFlags::new()) }.unwrap();
+        assert_eq!(res, 0);
+        assert_eq!(page_status(status[0]).unwrap(), 0);
+        let err = page_status(status[1]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ENOENT as i32));
+
+        let nodes = [0, 0];
+        let res = unsafe {
+            move_pages(
+                0,
+                &pages,
+                Some(&nodes),
+                &mut status,
+                MoveFlags::new().migrate(),
+            )
+        };
+        assert_eq!(res.unwrap(), 0);
+        let err =
+            unsafe { move_pages(0, &pages, Some(&nodes[..1]), &mut status, MoveFlags::new()) }
+                .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let res = unsafe { migrate_pages(0, &node0(), &node0()) }.unwrap();
+        assert_eq!(res, 0);
+    }
+}
@@ -0,0 +1,1053 @@
+use crate::arch::{Syscalls, PAGE_SIZE};
+use crate::{result, result_none, result_ptr, syscall};
+use std::io::{self, IoSlice, IoSliceMut};
+use std::mem::{size_of, MaybeUninit};
+use std::os::unix::io::AsRawFd;
+use std::{ptr, slice};
+
+#[cfg(target_arch = "x86")]
+use linux_sys::EINVAL;
+use linux_sys::{
+    MADV_COLD, MADV_DODUMP, MADV_DOFORK, MADV_DONTDUMP, MADV_DONTFORK, MADV_DONTNEED, MADV_FREE,
+    MADV_HUGEPAGE, MADV_KEEPONFORK, MADV_MERGEABLE, MADV_NOHUGEPAGE, MADV_NORMAL, MADV_PAGEOUT,
+    MADV_POPULATE_READ, MADV_POPULATE_WRITE, MADV_RANDOM, MADV_REMOVE, MADV_SEQUENTIAL,
+    MADV_UNMERGEABLE, MADV_WILLNEED, MADV_WIPEONFORK, MAP_ANONYMOUS, MAP_FIXED,
+    MAP_FIXED_NOREPLACE, MAP_GROWSDOWN, MAP_HUGETLB, MAP_HUGE_16GB, MAP_HUGE_16MB, MAP_HUGE_1GB,
+    MAP_HUGE_1MB, MAP_HUGE_256MB, MAP_HUGE_2GB, MAP_HUGE_2MB, MAP_HUGE_32MB, MAP_HUGE_512KB,
+    MAP_HUGE_512MB, MAP_HUGE_64KB, MAP_HUGE_8MB, MAP_HUGE_MASK, MAP_HUGE_SHIFT, MAP_LOCKED,
+    MAP_NORESERVE, MAP_POPULATE, MAP_PRIVATE, MAP_SHARED, MAP_SHARED_VALIDATE, MAP_STACK, MAP_TYPE,
+    MCL_CURRENT, MCL_FUTURE, MCL_ONFAULT, MLOCK_ONFAULT, MREMAP_DONTUNMAP, MREMAP_FIXED,
+    MREMAP_MAYMOVE, MS_ASYNC, MS_INVALIDATE, MS_SYNC, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE,
+};
+
+/// The memory protection of a mapping, see [`mmap`](fn.mmap.html) and [`mprotect`](fn.mprotect.html).
+/// `ProtFlags::new()` is `PROT_NONE`, the pages can't be accessed at all.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct ProtFlags(isize);
+
+impl ProtFlags {
+    /// Creates new `ProtFlags`.
+    pub fn new() -> Self {
+        ProtFlags(PROT_NONE as isize)
+    }
+
+    /// The pages may be read.
+    pub fn read(mut self) -> Self {
+        self.0 |= PROT_READ as isize;
+        self
+    }
+
+    /// The pages may be written.
+    pub fn write(mut self) -> Self {
+        self.0 |= PROT_WRITE as isize;
+        self
+    }
+
+    /// The pages may be executed.
+    pub fn exec(mut self) -> Self {
+        self.0 |= PROT_EXEC as isize;
+        self
+    }
+
+    fn contains(self, prot: u32) -> bool {
+        self.0 & prot as isize != 0
+    }
+}
+
+impl core::fmt::Debug for ProtFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ProtFlags")
+            .field("READ", &self.contains(PROT_READ))
+            .field("WRITE", &self.contains(PROT_WRITE))
+            .field("EXEC", &self.contains(PROT_EXEC))
+            .finish()
+    }
+}
+
+/// Options for [`mmap`](fn.mmap.html). exactly one of `shared`, `shared_validate` and `private` is required.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct MapFlags(isize);
+
+impl MapFlags {
+    /// Creates new `MapFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Updates are visible to other mappings of the same region, and are carried through to the file.
+    pub fn shared(mut self) -> Self {
+        self.0 |= MAP_SHARED as isize;
+        self
+    }
+
+    /// Like `shared`, but fails on unknown flags instead of ignoring them (Linux 4.15+).
+    pub fn shared_validate(mut self) -> Self {
+        self.0 |= MAP_SHARED_VALIDATE as isize;
+        self
+    }
+
+    /// Create a private copy-on-write mapping.
+    pub fn private(mut self) -> Self {
+        self.0 |= MAP_PRIVATE as isize;
+        self
+    }
+
+    /// The mapping isn't backed by any file, and is zero initialized.
+    pub fn anonymous(mut self) -> Self {
+        self.0 |= MAP_ANONYMOUS as isize;
+        self
+    }
+
+    /// Place the mapping at exactly the given address, replacing whatever was mapped there.
+    pub fn fixed(mut self) -> Self {
+        self.0 |= MAP_FIXED as isize;
+        self
+    }
+
+    /// Like `fixed` but fails with `EEXIST` instead of replacing existing mappings (Linux 4.17+).
+    pub fn fixed_noreplace(mut self) -> Self {
+        self.0 |= MAP_FIXED_NOREPLACE as isize;
+        self
+    }
+
+    /// Prefault the pages.
+    pub fn populate(mut self) -> Self {
+        self.0 |= MAP_POPULATE as isize;
+        self
+    }
+
+    /// Don't reserve swap space for the mapping.
+    pub fn noreserve(mut self) -> Self {
+        self.0 |= MAP_NORESERVE as isize;
+        self
+    }
+
+    /// The mapping will be used as a stack.
+    pub fn stack(mut self) -> Self {
+        self.0 |= MAP_STACK as isize;
+        self
+    }
+
+    /// The mapping grows down like a stack.
+    pub fn growsdown(mut self) -> Self {
+        self.0 |= MAP_GROWSDOWN as isize;
+        self
+    }
+
+    /// Lock the pages like `mlock(2)` does.
+    pub fn locked(mut self) -> Self {
+        self.0 |= MAP_LOCKED as isize;
+        self
+    }
+
+    /// Use huge pages of the default size.
+    pub fn hugetlb(mut self) -> Self {
+        self.0 |= MAP_HUGETLB as isize;
+        self
+    }
+
+    /// Use huge pages of `size`, a pool of them has to be reserved (see `/sys/kernel/mm/hugepages`).
+    pub fn hugetlb_size(mut self, size: HugePageSize) -> Self {
+        self.0 |= (MAP_HUGETLB | size.bits()) as isize;
+        self
+    }
+
+    fn contains(self, flag: u32) -> bool {
+        self.0 & flag as isize != 0
+    }
+}
+
+impl core::fmt::Debug for MapFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MapFlags")
+            .field(
+                "SHARED",
+                &(self.0 & MAP_TYPE as isize == MAP_SHARED as isize),
+            )
+            .field(
+                "PRIVATE",
+                &(self.0 & MAP_TYPE as isize == MAP_PRIVATE as isize),
+            )
+            .field(
+                "SHARED_VALIDATE",
+                &(self.0 & MAP_TYPE as isize == MAP_SHARED_VALIDATE as isize),
+            )
+            .field("ANONYMOUS", &self.contains(MAP_ANONYMOUS))
+            .field("FIXED", &self.contains(MAP_FIXED))
+            .field("FIXED_NOREPLACE", &self.contains(MAP_FIXED_NOREPLACE))
+            .field("POPULATE", &self.contains(MAP_POPULATE))
+            .field("NORESERVE", &self.contains(MAP_NORESERVE))
+            .field("STACK", &self.contains(MAP_STACK))
+            .field("GROWSDOWN", &self.contains(MAP_GROWSDOWN))
+            .field("LOCKED", &self.contains(MAP_LOCKED))
+            .field("HUGETLB", &self.contains(MAP_HUGETLB))
+            .finish()
+    }
+}
+
+/// The size of huge pages, the sizes that are available depend on the CPU (x86 has 2MiB and 1GiB pages).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum HugePageSize {
+    Size64KB,
+    Size512KB,
+    Size1MB,
+    Size2MB,
+    Size8MB,
+    Size16MB,
+    Size32MB,
+    Size256MB,
+    Size512MB,
+    Size1GB,
+    Size2GB,
+    Size16GB,
+}
+
+impl HugePageSize {
+    // The log2 of the size shifted by `MAP_HUGE_SHIFT`, `MFD_HUGE_*` uses the same encoding.
+    pub(crate) fn bits(self) -> u32 {
+        match self {
+            HugePageSize::Size64KB => MAP_HUGE_64KB,
+            HugePageSize::Size512KB => MAP_HUGE_512KB,
+            HugePageSize::Size1MB => MAP_HUGE_1MB,
+            HugePageSize::Size2MB => MAP_HUGE_2MB,
+            HugePageSize::Size8MB => MAP_HUGE_8MB,
+            HugePageSize::Size16MB => MAP_HUGE_16MB,
+            HugePageSize::Size32MB => MAP_HUGE_32MB,
+            HugePageSize::Size256MB => MAP_HUGE_256MB,
+            HugePageSize::Size512MB => MAP_HUGE_512MB,
+            HugePageSize::Size1GB => MAP_HUGE_1GB,
+            HugePageSize::Size2GB => MAP_HUGE_2GB,
+            HugePageSize::Size16GB => MAP_HUGE_16GB,
+        }
+    }
+
+    /// The size in bytes.
+    pub fn size(self) -> u64 {
+        1 << ((self.bits() >> MAP_HUGE_SHIFT) & MAP_HUGE_MASK)
+    }
+}
+
+/// Options for [`mremap`](fn.mremap.html).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RemapFlags {
+    /// Fail with `ENOMEM` if the mapping can't be resized in place.
+    InPlace,
+    /// The kernel may move the mapping to a new address.
+    MayMove,
+    /// Move the mapping to exactly this address, replacing whatever was mapped there.
+    Fixed(*mut u8),
+    /// Move the pages to this address (or anywhere if it's null) without unmapping the old range (Linux 5.7+).
+    /// The old and new size must be the same, and the mapping must be private and anonymous.
+    DontUnmap(*mut u8),
+}
+
+// TODO: Should we support `MAP_UNINITIALIZED`? it's ignored unless the kernel was configured for it.
+/// Map `len` bytes of `fd` starting at `offset` (or anonymous memory if `fd` is `None`),
+/// `addr` is a hint for where to place the mapping (or the exact address with `MAP_FIXED`), null lets the kernel decide.
+#[inline]
+pub unsafe fn mmap<F: AsRawFd>(
+    addr: *mut u8,
+    len: usize,
+    prot: ProtFlags,
+    flags: MapFlags,
+    fd: Option<&F>,
+    offset: u64,
+) -> io::Result<*mut u8> {
+    let fd = fd.map(|fd| fd.as_raw_fd()).unwrap_or(-1);
+    // On i686 the offset is a 32 bit long, so we use `mmap2` which takes it in pages.
+    #[cfg(target_arch = "x86")]
+    let (nr, offset) = {
+        if offset % PAGE_SIZE as u64 != 0 {
+            return Err(io::Error::from_raw_os_error(EINVAL as i32));
+        }
+        (Syscalls::Mmap2, (offset / PAGE_SIZE as u64) as isize)
+    };
+    #[cfg(not(target_arch = "x86"))]
+    let (nr, offset) = (Syscalls::Mmap, offset as isize);
+
+    let res = syscall!(
+        nr,
+        addr as isize,
+        len as isize,
+        prot.0,
+        flags.0,
+        fd as isize,
+        offset,
+    );
+    result_ptr!(res)
+}
+
+#[inline]
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> io::Result<()> {
+    let res = syscall!(Syscalls::Munmap, addr as isize, len as isize);
+    result_none!(res)
+}
+
+/// Change the protection of the pages in `addr..addr + len`, `addr` must be page aligned.
+#[inline]
+pub unsafe fn mprotect(addr: *mut u8, len: usize, prot: ProtFlags) -> io::Result<()> {
+    let res = syscall!(Syscalls::Mprotect, addr as isize, len as isize, prot.0);
+    result_none!(res)
+}
+
+/// Like [`mprotect`](fn.mprotect.html), and also assign the protection key `pkey` to the pages (Linux 4.9+),
+/// see [`pkey`](../pkey/index.html).
+#[inline]
+pub unsafe fn pkey_mprotect(
+    addr: *mut u8,
+    len: usize,
+    prot: ProtFlags,
+    pkey: u32,
+) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::PkeyMprotect,
+        addr as isize,
+        len as isize,
+        prot.0,
+        pkey as isize,
+    );
+    result_none!(res)
+}
+
+/// Resize (and maybe move) the mapping at `old_addr`, returning its new address.
+#[inline]
+pub unsafe fn mremap(
+    old_addr: *mut u8,
+    old_len: usize,
+    new_len: usize,
+    flags: RemapFlags,
+) -> io::Result<*mut u8> {
+    let (flags, new_addr) = match flags {
+        RemapFlags::InPlace => (0, ptr::null_mut()),
+        RemapFlags::MayMove => (MREMAP_MAYMOVE, ptr::null_mut()),
+        RemapFlags::Fixed(addr) => (MREMAP_MAYMOVE | MREMAP_FIXED, addr),
+        RemapFlags::DontUnmap(addr) => (MREMAP_MAYMOVE | MREMAP_DONTUNMAP, addr),
+    };
+    let res = syscall!(
+        Syscalls::Mremap,
+        old_addr as isize,
+        old_len as isize,
+        new_len as isize,
+        flags as isize,
+        new_addr as isize,
+    );
+    result_ptr!(res)
+}
+
+/// Advice for [`madvise`](fn.madvise.html) about how a range of memory will be used.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Advice {
+    /// No special treatment.
+    Normal = MADV_NORMAL as isize,
+    /// Expect random access, read ahead less.
+    Random = MADV_RANDOM as isize,
+    /// Expect sequential access, read ahead more and free pages after they were accessed.
+    Sequential = MADV_SEQUENTIAL as isize,
+    /// Expect access soon, read ahead now.
+    WillNeed = MADV_WILLNEED as isize,
+    /// Free the pages now. private anonymous memory will read back as zeros.
+    DontNeed = MADV_DONTNEED as isize,
+    /// Free the pages lazily, when there's memory pressure. until then writes cancel the free (Linux 4.5+).
+    Free = MADV_FREE as isize,
+    /// Free the pages and their backing store (e.g. punch a hole in a shmem file).
+    Remove = MADV_REMOVE as isize,
+    /// Don't make the pages available to children after `fork(2)`.
+    DontFork = MADV_DONTFORK as isize,
+    /// Undo `DontFork`.
+    DoFork = MADV_DOFORK as isize,
+    /// Allow KSM to merge identical pages.
+    Mergeable = MADV_MERGEABLE as isize,
+    /// Undo `Mergeable`.
+    Unmergeable = MADV_UNMERGEABLE as isize,
+    /// Use transparent huge pages where possible.
+    HugePage = MADV_HUGEPAGE as isize,
+    /// Don't use transparent huge pages.
+    NoHugePage = MADV_NOHUGEPAGE as isize,
+    /// Exclude the pages from core dumps.
+    DontDump = MADV_DONTDUMP as isize,
+    /// Undo `DontDump`.
+    DoDump = MADV_DODUMP as isize,
+    /// Children get zeroed pages after `fork(2)` instead of a copy, private anonymous memory only (Linux 4.14+).
+    WipeOnFork = MADV_WIPEONFORK as isize,
+    /// Undo `WipeOnFork`.
+    KeepOnFork = MADV_KEEPONFORK as isize,
+    /// Deactivate the pages, they're reclaimed first under memory pressure (Linux 5.4+).
+    Cold = MADV_COLD as isize,
+    /// Reclaim the pages now (Linux 5.4+).
+    PageOut = MADV_PAGEOUT as isize,
+    /// Prefault the pages for reading (Linux 5.14+).
+    PopulateRead = MADV_POPULATE_READ as isize,
+    /// Prefault the pages for writing (Linux 5.14+).
+    PopulateWrite = MADV_POPULATE_WRITE as isize,
+}
+
+/// Give the kernel advice about the pages in `addr..addr + len`, `addr` must be page aligned.
+/// Some advice (e.g. `DontNeed`) changes the content of the memory.
+#[inline]
+pub unsafe fn madvise(addr: *mut u8, len: usize, advice: Advice) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::Madvise,
+        addr as isize,
+        len as isize,
+        advice as isize
+    );
+    result_none!(res)
+}
+
+/// A range of memory in another process. this has the same layout as `struct iovec`,
+/// but unlike `IoSlice` it doesn't point into our address space.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(C)]
+pub struct RemoteIoVec {
+    pub base: usize,
+    pub len: usize,
+}
+
+impl From<(usize, usize)> for RemoteIoVec {
+    fn from((base, len): (usize, usize)) -> Self {
+        RemoteIoVec { base, len }
+    }
+}
+
+/// Read the memory of process `pid` at `remote` into `local`, returns the number of bytes read.
+/// This needs `ptrace(2)` access to the process, and stops at the first range that isn't readable (a partial read).
+#[inline]
+pub unsafe fn process_vm_readv(
+    pid: u32,
+    local: &mut [IoSliceMut<'_>],
+    remote: &[RemoteIoVec],
+) -> io::Result<usize> {
+    let res = syscall!(
+        Syscalls::ProcessVmReadv,
+        pid as isize,
+        local.as_mut_ptr() as isize,
+        local.len() as isize,
+        remote.as_ptr() as isize,
+        remote.len() as isize,
+        0,
+    );
+    result!(res)
+}
+
+/// Write `local` into the memory of process `pid` at `remote`, returns the number of bytes written.
+/// Like [`process_vm_readv`](fn.process_vm_readv.html) this can be partial.
+#[inline]
+pub unsafe fn process_vm_writev(
+    pid: u32,
+    local: &[IoSlice<'_>],
+    remote: &[RemoteIoVec],
+) -> io::Result<usize> {
+    let res = syscall!(
+        Syscalls::ProcessVmWritev,
+        pid as isize,
+        local.as_ptr() as isize,
+        local.len() as isize,
+        remote.as_ptr() as isize,
+        remote.len() as isize,
+        0,
+    );
+    result!(res)
+}
+
+/// Read a `T` from process `pid` at `addr`, fails with `UnexpectedEof` if only part of it is readable.
+/// `T` must be valid for any bit pattern.
+pub unsafe fn read_remote<T: Copy>(pid: u32, addr: usize) -> io::Result<T> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let buf = slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>());
+    let remote = RemoteIoVec::from((addr, buf.len()));
+    let len = process_vm_readv(pid, &mut [IoSliceMut::new(buf)], &[remote])?;
+    if len != size_of::<T>() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Only part of the value was readable",
+        ));
+    }
+    Ok(value.assume_init())
+}
+
+/// Give advice about the memory of the process referred to by `pidfd`, returns the number of bytes advised.
+/// Other processes only support `Cold`, `PageOut` and `WillNeed` (Linux 5.10+).
+#[inline]
+pub unsafe fn process_madvise<F: AsRawFd>(
+    pidfd: &F,
+    ranges: &[RemoteIoVec],
+    advice: Advice,
+) -> io::Result<usize> {
+    let res = syscall!(
+        Syscalls::ProcessMadvise,
+        pidfd.as_raw_fd() as isize,
+        ranges.as_ptr() as isize,
+        ranges.len() as isize,
+        advice as isize,
+        0,
+    );
+    result!(res)
+}
+
+/// Fill `vec` with the residency of the pages in `addr..addr + len`, the least significant bit of each byte
+/// is set if the page is resident in memory. `vec` must have a byte for every page.
+#[inline]
+pub unsafe fn mincore(addr: *mut u8, len: usize, vec: &mut [u8]) -> io::Result<()> {
+    if vec.len() < page_align(len) / PAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The vector is smaller than the amount of pages",
+        ));
+    }
+    let res = syscall!(
+        Syscalls::Mincore,
+        addr as isize,
+        len as isize,
+        vec.as_mut_ptr() as isize
+    );
+    result_none!(res)
+}
+
+/// Options for [`mlock2`](fn.mlock2.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct MlockFlags(isize);
+
+impl MlockFlags {
+    /// Creates new `MlockFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Lock the pages when they're faulted in, instead of populating them now.
+    pub fn onfault(mut self) -> Self {
+        self.0 |= MLOCK_ONFAULT as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for MlockFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MlockFlags")
+            .field("ONFAULT", &(self.0 & MLOCK_ONFAULT as isize > 0))
+            .finish()
+    }
+}
+
+/// Lock the pages in `addr..addr + len` in memory, so they never get swapped out.
+#[inline]
+pub unsafe fn mlock(addr: *mut u8, len: usize) -> io::Result<()> {
+    let res = syscall!(Syscalls::Mlock, addr as isize, len as isize);
+    result_none!(res)
+}
+
+/// Like [`mlock`](fn.mlock.html), with flags (Linux 4.4+).
+#[inline]
+pub unsafe fn mlock2(addr: *mut u8, len: usize, flags: MlockFlags) -> io::Result<()> {
+    let res = syscall!(Syscalls::Mlock2, addr as isize, len as isize, flags.0);
+    result_none!(res)
+}
+
+#[inline]
+pub unsafe fn munlock(addr: *mut u8, len: usize) -> io::Result<()> {
+    let res = syscall!(Syscalls::Munlock, addr as isize, len as isize);
+    result_none!(res)
+}
+
+/// Options for [`mlockall`](fn.mlockall.html), at least one of `current` and `future` is required.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct MlockAllFlags(isize);
+
+impl MlockAllFlags {
+    /// Creates new `MlockAllFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Lock all the pages that are currently mapped.
+    pub fn current(mut self) -> Self {
+        self.0 |= MCL_CURRENT as isize;
+        self
+    }
+
+    /// Lock all the pages that will be mapped in the future.
+    pub fn future(mut self) -> Self {
+        self.0 |= MCL_FUTURE as isize;
+        self
+    }
+
+    /// Lock the pages when they're faulted in (Linux 4.4+).
+    pub fn onfault(mut self) -> Self {
+        self.0 |= MCL_ONFAULT as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for MlockAllFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MlockAllFlags")
+            .field("CURRENT", &(self.0 & MCL_CURRENT as isize > 0))
+            .field("FUTURE", &(self.0 & MCL_FUTURE as isize > 0))
+            .field("ONFAULT", &(self.0 & MCL_ONFAULT as isize > 0))
+            .finish()
+    }
+}
+
+/// Lock the whole address space of the process.
+#[inline]
+pub unsafe fn mlockall(flags: MlockAllFlags) -> io::Result<()> {
+    let res = syscall!(Syscalls::Mlockall, flags.0);
+    result_none!(res)
+}
+
+#[inline]
+pub unsafe fn munlockall() -> io::Result<()> {
+    let res = syscall!(Syscalls::Munlockall);
+    result_none!(res)
+}
+
+/// Options for [`msync`](fn.msync.html), exactly one of `sync` and `asynchronous` is required.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct MsFlags(isize);
+
+impl MsFlags {
+    /// Creates new `MsFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Wait for the write back to finish.
+    pub fn sync(mut self) -> Self {
+        self.0 |= MS_SYNC as isize;
+        self
+    }
+
+    /// Schedule the write back and return immediately.
+    pub fn asynchronous(mut self) -> Self {
+        self.0 |= MS_ASYNC as isize;
+        self
+    }
+
+    /// Invalidate other mappings of the same file.
+    pub fn invalidate(mut self) -> Self {
+        self.0 |= MS_INVALIDATE as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for MsFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MsFlags")
+            .field("SYNC", &(self.0 & MS_SYNC as isize > 0))
+            .field("ASYNC", &(self.0 & MS_ASYNC as isize > 0))
+            .field("INVALIDATE", &(self.0 & MS_INVALIDATE as isize > 0))
+            .finish()
+    }
+}
+
+/// Flush changes to a shared file mapping in `addr..addr + len` back to the file.
+#[inline]
+pub unsafe fn msync(addr: *mut u8, len: usize, flags: MsFlags) -> io::Result<()> {
+    let res = syscall!(Syscalls::Msync, addr as isize, len as isize, flags.0);
+    result_none!(res)
+}
+
+/// A range of whole pages that we own, this gives every mapping type the memory management syscalls as methods.
+///
+/// # Safety
+/// `region` must return a page aligned range which is mapped for as long as `self` lives, and isn't used by anyone else.
+pub unsafe trait MemoryRegion {
+    /// The start and length of the region.
+    fn region(&self) -> (*mut u8, usize);
+
+    /// See [`madvise`](fn.madvise.html). this takes `&mut self` as some advice changes the content.
+    fn advise(&mut self, advice: Advice) -> io::Result<()> {
+        let (addr, len) = self.region();
+        unsafe { madvise(addr, len, advice) }
+    }
+
+    /// Which pages are resident in memory, see [`mincore`](fn.mincore.html).
+    fn residency(&self) -> io::Result<Vec<bool>> {
+        let (addr, len) = self.region();
+        let mut vec = vec![0u8; page_align(len) / PAGE_SIZE];
+        unsafe { mincore(addr, len, &mut vec) }?;
+        Ok(vec.iter().map(|page| page & 1 != 0).collect())
+    }
+
+    /// Lock the region in memory, see [`mlock2`](fn.mlock2.html).
+    fn lock(&self, flags: MlockFlags) -> io::Result<()> {
+        let (addr, len) = self.region();
+        unsafe { mlock2(addr, len, flags) }
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        let (addr, len) = self.region();
+        unsafe { munlock(addr, len) }
+    }
+
+    /// Flush the region back to its file, see [`msync`](fn.msync.html).
+    fn sync(&self, flags: MsFlags) -> io::Result<()> {
+        let (addr, len) = self.region();
+        unsafe { msync(addr, len, flags) }
+    }
+}
+
+/// An owned memory mapping, unmapped on drop.
+///
+/// The memory is accessed with [`as_slice`](#method.as_slice) and [`as_mut_slice`](#method.as_mut_slice),
+/// which depend on the protection.
+#[derive(Debug)]
+pub struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    prot: ProtFlags,
+}
+
+// The mapping is owned memory just like a `Vec<u8>`.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Map `len` bytes of private, zero initialized memory.
+    pub fn anonymous(len: usize, prot: ProtFlags) -> io::Result<Self> {
+        unsafe { Self::new::<i32>(len, prot, MapFlags::new().private(), None, 0) }
+    }
+
+    /// Map `len` bytes of `fd` (or anonymous memory if `fd` is `None`) starting at `offset`, see [`mmap`](fn.mmap.html).
+    ///
+    /// # Safety
+    /// Shared mappings can be changed by other processes at any time, which the `[u8]` view doesn't account for.
+    /// `flags` must not contain `MAP_FIXED`, which could replace memory we don't own.
+    pub unsafe fn new<F: AsRawFd>(
+        len: usize,
+        prot: ProtFlags,
+        flags: MapFlags,
+        fd: Option<&F>,
+        offset: u64,
+    ) -> io::Result<Self> {
+        let flags = if fd.is_none() {
+            flags.anonymous()
+        } else {
+            flags
+        };
+        let ptr = mmap(ptr::null_mut(), len, prot, flags, fd, offset)?;
+        Ok(Mapping { ptr, len, prot })
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn prot(&self) -> ProtFlags {
+        self.prot
+    }
+
+    /// The mapped memory, if it's readable.
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        if self.prot.contains(PROT_READ) {
+            Some(unsafe { slice::from_raw_parts(self.ptr, self.len) })
+        } else {
+            None
+        }
+    }
+
+    /// The mapped memory, if it's readable and writable.
+    pub fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        if self.prot.contains(PROT_READ) && self.prot.contains(PROT_WRITE) {
+            Some(unsafe { slice::from_raw_parts_mut(self.ptr, self.len) })
+        } else {
+            None
+        }
+    }
+
+    /// Change the protection of the whole mapping.
+    pub fn protect(&mut self, prot: ProtFlags) -> io::Result<()> {
+        unsafe { mprotect(self.ptr, self.len, prot) }?;
+        self.prot = prot;
+        Ok(())
+    }
+
+    /// Resize the mapping to `new_len` bytes, moving it if it can't grow in place.
+    /// New anonymous memory is zero initialized.
+    ///
+    /// # Safety
+    /// A file mapping must not grow past the end of the file, the pages there raise `SIGBUS` when they're accessed
+    /// through [`as_slice`](#method.as_slice).
+    pub unsafe fn remap(&mut self, new_len: usize) -> io::Result<()> {
+        self.ptr = mremap(self.ptr, self.len, new_len, RemapFlags::MayMove)?;
+        self.len = new_len;
+        Ok(())
+    }
+
+    /// Like [`remap`](#method.remap) but fails with `ENOMEM` instead of moving the mapping.
+    ///
+    /// # Safety
+    /// See [`remap`](#method.remap).
+    pub unsafe fn remap_in_place(&mut self, new_len: usize) -> io::Result<()> {
+        mremap(self.ptr, self.len, new_len, RemapFlags::InPlace)?;
+        self.len = new_len;
+        Ok(())
+    }
+
+    /// Leak the mapping, returning its address.
+    pub fn into_raw(self) -> *mut u8 {
+        let ptr = self.ptr;
+        std::mem::forget(self);
+        ptr
+    }
+}
+
+unsafe impl MemoryRegion for Mapping {
+    fn region(&self) -> (*mut u8, usize) {
+        (self.ptr, self.len)
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr, self.len).ok();
+        }
+    }
+}
+
+/// Round `len` up to whole pages.
+pub(crate) fn page_align(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestFile;
+    use linux_sys::{EINVAL, EPERM};
+    use std::io::Write;
+
+    #[test]
+    fn test_anonymous() {
+        let mut map = Mapping::anonymous(PAGE_SIZE * 2, ProtFlags::new().read().write()).unwrap();
+        assert_eq!(map.len(), PAGE_SIZE * 2);
+        assert!(map.as_slice().unwrap().iter().all(|&b| b == 0));
+        map.as_mut_slice().unwrap()[PAGE_SIZE + 1] = 0xaa;
+        assert_eq!(map.as_slice().unwrap()[PAGE_SIZE + 1], 0xaa);
+    }
+
+    #[test]
+    fn test_hugetlb() {
+        let size = HugePageSize::Size2MB;
+        assert_eq!(size.size(), 2 << 20);
+        assert_eq!(HugePageSize::Size1GB.size(), 1 << 30);
+        // Only works if huge pages were reserved, see `/proc/sys/vm/nr_hugepages`.
+        let flags = MapFlags::new().private().hugetlb_size(size);
+        let prot = ProtFlags::new().read().write();
+        match unsafe { Mapping::new::<i32>(size.size() as usize, prot, flags, None, 0) } {
+            Ok(mut map) => {
+                map.as_mut_slice().unwrap()[0] = 1;
+                assert_eq!(map.as_slice().unwrap()[0], 1);
+            }
+            Err(e) => assert_eq!(e.raw_os_error(), Some(linux_sys::ENOMEM as i32)),
+        }
+    }
+
+    #[test]
+    fn test_protect() {
+        let mut map = Mapping::anonymous(PAGE_SIZE, ProtFlags::new().read().write()).unwrap();
+        map.as_mut_slice().unwrap()[0] = 7;
+        map.protect(ProtFlags::new().read()).unwrap();
+        assert!(map.as_mut_slice().is_none());
+        assert_eq!(map.as_slice().unwrap()[0], 7);
+        map.protect(ProtFlags::new()).unwrap();
+        assert!(map.as_slice().is_none());
+    }
+
+    #[test]
+    fn test_remap() {
+        let mut map = Mapping::anonymous(PAGE_SIZE, ProtFlags::new().read().write()).unwrap();
+        map.as_mut_slice()
+            .unwrap()
+            .iter_mut()
+            .for_each(|b| *b = 0x42);
+        unsafe { map.remap(PAGE_SIZE * 16) }.unwrap();
+        assert_eq!(map.len(), PAGE_SIZE * 16);
+        assert!(map.as_slice().unwrap()[..PAGE_SIZE]
+            .iter()
+            .all(|&b| b == 0x42));
+        assert!(map.as_slice().unwrap()[PAGE_SIZE..].iter().all(|&b| b == 0));
+        unsafe { map.remap_in_place(PAGE_SIZE) }.unwrap();
+        assert_eq!(map.len(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_file_mapping() {
+        let mut file = TestFile::new().unwrap();
+        file.write_all(&[1u8; PAGE_SIZE]).unwrap();
+        file.write_all(&[2u8; PAGE_SIZE]).unwrap();
+        let prot = ProtFlags::new().read();
+        let flags = MapFlags::new().shared();
+        let map = unsafe { Mapping::new(PAGE_SIZE, prot, flags, Some(&*file), PAGE_SIZE as u64) }
+            .unwrap();
+        assert!(map.as_slice().unwrap().iter().all(|&b| b == 2));
+
+        let err = unsafe { Mapping::new(PAGE_SIZE, prot, flags, Some(&*file), 1) }.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EINVAL as i32));
+    }
+
+    #[test]
+    fn test_advise_dontneed() {
+        let mut map = Mapping::anonymous(PAGE_SIZE * 2, ProtFlags::new().read().write()).unwrap();
+        map.as_mut_slice().unwrap().iter_mut().for_each(|b| *b = 1);
+        map.advise(Advice::DontNeed).unwrap();
+        assert!(map.as_slice().unwrap().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_advise_wipeonfork() {
+        let mut map = Mapping::anonymous(PAGE_SIZE, ProtFlags::new().read().write()).unwrap();
+        map.as_mut_slice().unwrap()[0] = 0x55;
+        map.advise(Advice::WipeOnFork).unwrap();
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            crate::_exit(map.as_slice().unwrap()[0] as i32);
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+        assert_eq!(map.as_slice().unwrap()[0], 0x55);
+    }
+
+    #[test]
+    fn test_process_vm() {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        #[repr(C)]
+        struct Frame {
+            ip: u64,
+            sp: u64,
+        }
+        let frame = Box::new(Frame {
+            ip: 0xdead,
+            sp: 0xbeef,
+        });
+        let map = Mapping::anonymous(PAGE_SIZE * 2, ProtFlags::new().read().write()).unwrap();
+        let second = unsafe { map.as_ptr().add(PAGE_SIZE) };
+        unsafe { mprotect(second, PAGE_SIZE, ProtFlags::new()) }.unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // Wait for the parent to write into us, then check it.
+            let mut buf = [0u8; 1];
+            unsafe { crate::read(&fds[0], &mut buf) }.ok();
+            crate::_exit((map.as_slice().unwrap()[..5] != *b"hello") as i32);
+        }
+        let pid = pid as u32;
+        let addr = &*frame as *const Frame as usize;
+        let remote = unsafe { read_remote::<Frame>(pid, addr) }.unwrap();
+        assert_eq!(remote, *frame);
+
+        let (mut ip, mut sp) = ([0u8; 8], [0u8; 8]);
+        let mut local = [IoSliceMut::new(&mut ip), IoSliceMut::new(&mut sp)];
+        let len = unsafe { process_vm_readv(pid, &mut local, &[(addr, 16).into()]) }.unwrap();
+        assert_eq!(len, 16);
+        assert_eq!(u64::from_ne_bytes(sp), 0xbeef);
+
+        // The second page isn't readable.
+        let end = map.as_ptr() as usize + PAGE_SIZE - 4;
+        let err = unsafe { read_remote::<u64>(pid, end) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let remote = RemoteIoVec::from((map.as_ptr() as usize, 5));
+        let len = unsafe { process_vm_writev(pid, &[IoSlice::new(b"hello")], &[remote]) }.unwrap();
+        assert_eq!(len, 5);
+        assert_eq!(map.as_slice().unwrap()[0], 0);
+        unsafe { crate::write(&mut fds[1], b"x") }.unwrap();
+
+        let mut status = 0;
+        assert_eq!(
+            unsafe { libc::waitpid(pid as i32, &mut status, 0) },
+            pid as i32
+        );
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn test_process_madvise() {
+        let map = Mapping::anonymous(PAGE_SIZE * 4, ProtFlags::new().read().write()).unwrap();
+        let fd = unsafe { crate::pidfd_open(std::process::id()) }.unwrap() as i32;
+        let range = RemoteIoVec {
+            base: map.as_ptr() as usize,
+            len: map.len(),
+        };
+        // Linux 5.12 to 6.12 require `CAP_SYS_NICE` even for the calling process.
+        match unsafe { process_madvise(&fd, &[range], Advice::Cold) } {
+            Ok(res) => assert_eq!(res, map.len()),
+            Err(ref e) if e.raw_os_error() == Some(EPERM as i32) => {
+                eprintln!("Skipping process_madvise test: {}", e)
+            }
+            Err(e) => panic!("process_madvise failed: {}", e),
+        }
+        unsafe { crate::close(&fd) }.unwrap();
+    }
+
+    #[test]
+    fn test_residency() {
+        let mut map = Mapping::anonymous(PAGE_SIZE * 3, ProtFlags::new().read().write()).unwrap();
+        assert_eq!(map.residency().unwrap(), [false, false, false]);
+        map.as_mut_slice().unwrap()[PAGE_SIZE] = 1;
+        assert_eq!(map.residency().unwrap(), [false, true, false]);
+
+        let mut vec = [0u8; 2];
+        let err = unsafe { mincore(map.as_ptr(), map.len(), &mut vec) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_lock() {
+        let map = Mapping::anonymous(PAGE_SIZE * 2, ProtFlags::new().read().write()).unwrap();
+        map.lock(MlockFlags::new().onfault()).unwrap();
+        // `MLOCK_ONFAULT` doesn't populate the pages.
+        assert_eq!(map.residency().unwrap(), [false, false]);
+        map.unlock().unwrap();
+        unsafe { mlock(map.as_ptr(), map.len()) }.unwrap();
+        assert_eq!(map.residency().unwrap(), [true, true]);
+        unsafe { munlock(map.as_ptr(), map.len()) }.unwrap();
+
+        let err = unsafe { mlockall(MlockAllFlags::new().onfault()) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_msync() {
+        let mut file = TestFile::new().unwrap();
+        file.write_all(&[0u8; PAGE_SIZE]).unwrap();
+        let prot = ProtFlags::new().read().write();
+        let mut map =
+            unsafe { Mapping::new(PAGE_SIZE, prot, MapFlags::new().shared(), Some(&*file), 0) }
+                .unwrap();
+        map.as_mut_slice().unwrap()[..5].copy_from_slice(b"hello");
+        map.sync(MsFlags::new().sync()).unwrap();
+        let content = std::fs::read(file.path()).unwrap();
+        assert_eq!(&content[..5], b"hello");
+
+        let err = map.sync(MsFlags::new().sync().asynchronous()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_munmap_unaligned() {
+        let map = Mapping::anonymous(PAGE_SIZE, ProtFlags::new()).unwrap();
+        let err = unsafe { munmap(map.as_ptr().add(1), 1) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,250 @@
+//! Memory protection keys, see `pkeys(7)`.
+//!
+//! Pages are tagged with a key by [`pkey_mprotect`](../mman/fn.pkey_mprotect.html), and every thread has its own
+//! access rights for every key in the `PKRU` register, which can be changed without a syscall.
+//! This is x86 only, and requires a CPU with PKU enabled by the kernel (see [`has_pku`](fn.has_pku.html)).
+use crate::arch::{rdpkru, wrpkru, Syscalls};
+use crate::mman::{pkey_mprotect, ProtFlags};
+use crate::{result, result_none, syscall};
+use std::io;
+use std::marker::PhantomData;
+
+use linux_sys::{EOPNOTSUPP, PKEY_ACCESS_MASK, PKEY_DISABLE_ACCESS, PKEY_DISABLE_WRITE};
+
+pub use crate::arch::has_pku;
+
+/// The rights a thread has to the pages tagged with a key, the default is full access.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct PkeyRights(u32);
+
+impl PkeyRights {
+    /// Creates new `PkeyRights`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Disallow all data access (instruction fetches aren't affected).
+    pub fn disable_access(mut self) -> Self {
+        self.0 |= PKEY_DISABLE_ACCESS;
+        self
+    }
+
+    /// Disallow writes.
+    pub fn disable_write(mut self) -> Self {
+        self.0 |= PKEY_DISABLE_WRITE;
+        self
+    }
+
+    pub fn can_read(self) -> bool {
+        self.0 & PKEY_DISABLE_ACCESS == 0
+    }
+
+    pub fn can_write(self) -> bool {
+        self.0 & (PKEY_DISABLE_ACCESS | PKEY_DISABLE_WRITE) == 0
+    }
+}
+
+impl core::fmt::Debug for PkeyRights {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PkeyRights")
+            .field("DISABLE_ACCESS", &(self.0 & PKEY_DISABLE_ACCESS > 0))
+            .field("DISABLE_WRITE", &(self.0 & PKEY_DISABLE_WRITE > 0))
+            .finish()
+    }
+}
+
+/// Allocate a protection key, the calling thread gets `rights` to it and other threads get the default of the process.
+/// Fails with `ENOSPC` if there are no free keys, or when the CPU doesn't support them.
+#[inline]
+pub unsafe fn pkey_alloc(rights: PkeyRights) -> io::Result<u32> {
+    let res = syscall!(Syscalls::PkeyAlloc, 0, rights.0 as isize);
+    result!(res)
+}
+
+/// Free a protection key, it must not be assigned to any pages anymore.
+#[inline]
+pub unsafe fn pkey_free(pkey: u32) -> io::Result<()> {
+    let res = syscall!(Syscalls::PkeyFree, pkey as isize);
+    result_none!(res)
+}
+
+fn check_pku() -> io::Result<()> {
+    if has_pku() {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(EOPNOTSUPP as i32))
+    }
+}
+
+/// Read `PKRU` of the current thread, fails with `EOPNOTSUPP` without PKU.
+pub fn read_pkru() -> io::Result<u32> {
+    check_pku()?;
+    Ok(unsafe { rdpkru() })
+}
+
+/// Write `PKRU` of the current thread, fails with `EOPNOTSUPP` without PKU.
+///
+/// This changes the rights to every key, including the default key 0 which all the other memory uses.
+pub unsafe fn write_pkru(pkru: u32) -> io::Result<()> {
+    check_pku()?;
+    wrpkru(pkru);
+    Ok(())
+}
+
+// Every key has 2 bits in PKRU, in the same layout as the `PKEY_DISABLE_*` flags.
+fn pkru_shift(pkey: u32) -> u32 {
+    pkey * 2
+}
+
+/// An allocated protection key, freed on drop.
+///
+/// The key should be dropped only after the pages it protects were unmapped or assigned another key,
+/// otherwise a later `pkey_alloc` can return the same key with different rights.
+#[derive(Debug)]
+pub struct Pkey(u32);
+
+impl Pkey {
+    /// Allocate a new key, with `rights` for the calling thread.
+    /// Fails with `EOPNOTSUPP` without PKU, and with `ENOSPC` if there are no free keys.
+    pub fn new(rights: PkeyRights) -> io::Result<Self> {
+        check_pku()?;
+        let pkey = unsafe { pkey_alloc(rights) }?;
+        Ok(Pkey(pkey))
+    }
+
+    /// The number of the key.
+    pub fn key(&self) -> u32 {
+        self.0
+    }
+
+    /// Assign this key to the pages in `addr..addr + len`, see [`pkey_mprotect`](../mman/fn.pkey_mprotect.html).
+    pub unsafe fn protect(&self, addr: *mut u8, len: usize, prot: ProtFlags) -> io::Result<()> {
+        pkey_mprotect(addr, len, prot, self.0)
+    }
+
+    /// The rights the current thread has to this key.
+    pub fn rights(&self) -> io::Result<PkeyRights> {
+        let pkru = read_pkru()?;
+        Ok(PkeyRights((pkru >> pkru_shift(self.0)) & PKEY_ACCESS_MASK))
+    }
+
+    /// Change the rights the current thread has to this key.
+    pub fn set_rights(&self, rights: PkeyRights) -> io::Result<()> {
+        let pkru = read_pkru()?;
+        let shift = pkru_shift(self.0);
+        let pkru = (pkru & !(PKEY_ACCESS_MASK << shift)) | (rights.0 << shift);
+        unsafe { write_pkru(pkru) }
+    }
+
+    /// Give the current thread `rights` to this key until the guard is dropped, then restore the previous rights.
+    pub fn grant(&self, rights: PkeyRights) -> io::Result<PkeyGuard<'_>> {
+        let previous = self.rights()?;
+        self.set_rights(rights)?;
+        Ok(PkeyGuard {
+            pkey: self,
+            previous,
+            _not_send: PhantomData,
+        })
+    }
+}
+
+impl Drop for Pkey {
+    fn drop(&mut self) {
+        unsafe {
+            pkey_free(self.0).ok();
+        }
+    }
+}
+
+/// Restores the rights of the current thread to a key on drop, see [`Pkey::grant`](struct.Pkey.html#method.grant).
+#[derive(Debug)]
+pub struct PkeyGuard<'a> {
+    pkey: &'a Pkey,
+    previous: PkeyRights,
+    // PKRU is per thread, so the guard has to be dropped on the thread that created it.
+    _not_send: PhantomData<*mut u8>,
+}
+
+impl Drop for PkeyGuard<'_> {
+    fn drop(&mut self) {
+        self.pkey.set_rights(self.previous).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::_exit;
+    use crate::arch::PAGE_SIZE;
+    use crate::mman::Mapping;
+    use std::ptr;
+
+    // Protection keys need both the CPU and kernel support.
+    fn new_pkey(rights: PkeyRights) -> Option<Pkey> {
+        match Pkey::new(rights) {
+            Ok(pkey) => Some(pkey),
+            Err(e) => {
+                assert!(!has_pku(), "pkey_alloc failed with PKU: {}", e);
+                eprintln!("Skipping pkey test: {}", e);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_pkey_rights() {
+        let pkey = match new_pkey(PkeyRights::new().disable_write()) {
+            Some(pkey) => pkey,
+            None => return,
+        };
+        assert_ne!(pkey.key(), 0);
+        let rights = pkey.rights().unwrap();
+        assert!(rights.can_read() && !rights.can_write());
+        {
+            let _guard = pkey.grant(PkeyRights::new()).unwrap();
+            assert!(pkey.rights().unwrap().can_write());
+        }
+        assert_eq!(pkey.rights().unwrap(), PkeyRights::new().disable_write());
+    }
+
+    #[test]
+    fn test_pkey_protect() {
+        let pkey = match new_pkey(PkeyRights::new().disable_access()) {
+            Some(pkey) => pkey,
+            None => return,
+        };
+        let prot = ProtFlags::new().read().write();
+        let map = Mapping::anonymous(PAGE_SIZE, prot).unwrap();
+        unsafe { pkey.protect(map.as_ptr(), map.len(), prot) }.unwrap();
+        {
+            let _guard = pkey.grant(PkeyRights::new()).unwrap();
+            unsafe { ptr::write_volatile(map.as_ptr(), 7) };
+            assert_eq!(unsafe { ptr::read_volatile(map.as_ptr()) }, 7);
+        }
+
+        // The child inherits our PKRU, without access to the key.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            unsafe { ptr::read_volatile(map.as_ptr()) };
+            _exit(0);
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::WTERMSIG(status), libc::SIGSEGV);
+        drop(map);
+    }
+
+    #[test]
+    fn test_pkey_invalid() {
+        let err = unsafe { pkey_free(1 << 20) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        if !has_pku() {
+            let err = read_pkru().unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(EOPNOTSUPP as i32));
+            let err = Pkey::new(PkeyRights::new()).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(EOPNOTSUPP as i32));
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//! Waiting for file descriptors to become ready, see `poll(2)` and `select(2)`.
+use crate::arch::Syscalls;
+use crate::signal::SigSet;
+use crate::utils::{timeout_ms, timespec_from_duration};
+use crate::{result, syscall};
+use std::io;
+use std::mem::size_of;
+use std::ops::{BitOr, BitOrAssign};
+use std::os::raw::c_ulong;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::time::Duration;
+
+use linux_sys::{
+    __kernel_timespec, pollfd, __FD_SETSIZE, EINVAL, POLLERR, POLLHUP, POLLIN, POLLNVAL, POLLOUT,
+    POLLPRI, POLLRDBAND, POLLRDHUP, POLLRDNORM, POLLWRBAND, POLLWRNORM,
+};
+
+/// A set of poll events, what to wait for in a [`PollFd`](struct.PollFd.html) and what happened.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct PollEvents(i16);
+
+impl PollEvents {
+    /// The file is readable.
+    pub const IN: PollEvents = PollEvents(POLLIN as i16);
+    /// There's urgent data to read, e.g. TCP out of band data.
+    pub const PRI: PollEvents = PollEvents(POLLPRI as i16);
+    /// The file is writable.
+    pub const OUT: PollEvents = PollEvents(POLLOUT as i16);
+    /// Normal data is readable, like `IN`.
+    pub const RDNORM: PollEvents = PollEvents(POLLRDNORM as i16);
+    /// Priority band data is readable.
+    pub const RDBAND: PollEvents = PollEvents(POLLRDBAND as i16);
+    /// Normal data is writable, like `OUT`.
+    pub const WRNORM: PollEvents = PollEvents(POLLWRNORM as i16);
+    /// Priority band data is writable.
+    pub const WRBAND: PollEvents = PollEvents(POLLWRBAND as i16);
+    /// The peer of a stream socket shut down its writing half.
+    pub const RDHUP: PollEvents = PollEvents(POLLRDHUP as i16);
+    /// An error happened, only returned.
+    pub const ERR: PollEvents = PollEvents(POLLERR as i16);
+    /// The file was hung up, only returned.
+    pub const HUP: PollEvents = PollEvents(POLLHUP as i16);
+    /// The descriptor isn't open, only returned.
+    pub const NVAL: PollEvents = PollEvents(POLLNVAL as i16);
+
+    /// An empty set of events.
+    pub const fn empty() -> Self {
+        PollEvents(0)
+    }
+
+    pub const fn bits(self) -> i16 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if every event in `other` is also in `self`.
+    pub const fn contains(self, other: PollEvents) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for PollEvents {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        PollEvents(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PollEvents {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl core::fmt::Debug for PollEvents {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PollEvents")
+            .field("IN", &self.contains(PollEvents::IN))
+            .field("PRI", &self.contains(PollEvents::PRI))
+            .field("OUT", &self.contains(PollEvents::OUT))
+            .field("RDNORM", &self.contains(PollEvents::RDNORM))
+            .field("RDBAND", &self.contains(PollEvents::RDBAND))
+            .field("WRNORM", &self.contains(PollEvents::WRNORM))
+            .field("WRBAND", &self.contains(PollEvents::WRBAND))
+            .field("RDHUP", &self.contains(PollEvents::RDHUP))
+            .field("ERR", &self.contains(PollEvents::ERR))
+            .field("HUP", &self.contains(PollEvents::HUP))
+            .field("NVAL", &self.contains(PollEvents::NVAL))
+            .finish()
+    }
+}
+
+/// A file descriptor to poll, the `struct pollfd`.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct PollFd(pollfd);
+
+impl PollFd {
+    /// Wait for `events` on `fd`, a negative `fd` is ignored.
+    pub fn new(fd: RawFd, events: PollEvents) -> Self {
+        PollFd(pollfd {
+            fd,
+            events: events.0,
+            revents: 0,
+        })
+    }
+
+    /// Wait for `events` on `fd`.
+    pub fn from_fd<F: AsRawFd>(fd: &F, events: PollEvents) -> Self {
+        Self::new(fd.as_raw_fd(), events)
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.0.fd
+    }
+
+    pub fn events(&self) -> PollEvents {
+        PollEvents(self.0.events)
+    }
+
+    /// The events that happened in the last poll.
+    pub fn revents(&self) -> PollEvents {
+        PollEvents(self.0.revents)
+    }
+}
+
+impl core::fmt::Debug for PollFd {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PollFd")
+            .field("fd", &self.fd())
+            .field("events", &self.events())
+            .field("revents", &self.revents())
+            .finish()
+    }
+}
+
+/// Wait for one of the `fds` to become ready for up to `timeout` (rounded up to milliseconds), `None` waits forever.
+/// Returns the number of `fds` with events, 0 if the timeout expired.
+#[inline]
+pub fn poll(fds: &mut [PollFd], timeout: Option<Duration>) -> io::Result<usize> {
+    let res = unsafe {
+        syscall!(
+            Syscalls::Poll,
+            fds.as_mut_ptr() as isize,
+            fds.len() as isize,
+            timeout_ms(timeout) as isize,
+        )
+    };
+    result!(res)
+}
+
+/// Like [`poll`](fn.poll.html) with a nanosecond `timeout`, and atomically replaces the signal mask with `sigmask` while waiting.
+#[inline]
+pub unsafe fn ppoll(
+    fds: &mut [PollFd],
+    timeout: Option<Duration>,
+    sigmask: Option<&SigSet>,
+) -> io::Result<usize> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::PpollTime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::Ppoll;
+
+    // The kernel writes the remaining time back.
+    let mut timeout = timeout.map(timespec_from_duration);
+    let res = syscall!(
+        nr,
+        fds.as_mut_ptr() as isize,
+        fds.len() as isize,
+        timeout
+            .as_mut()
+            .map(|t| t as *mut __kernel_timespec)
+            .unwrap_or(ptr::null_mut()) as isize,
+        sigmask
+            .map(|set| set as *const SigSet)
+            .unwrap_or(ptr::null()) as isize,
+        size_of::<SigSet>() as isize,
+    );
+    result!(res)
+}
+
+/// The maximum number of descriptors in a [`FdSet`](struct.FdSet.html), descriptors must be below it.
+pub const FD_SETSIZE: usize = __FD_SETSIZE as usize;
+
+const BITS_PER_WORD: usize = c_ulong::BITS as usize;
+
+/// A set of file descriptors for [`pselect6`](fn.pselect6.html), the `fd_set`.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+#[repr(transparent)]
+pub struct FdSet([c_ulong; FD_SETSIZE / BITS_PER_WORD]);
+
+impl FdSet {
+    /// Creates an empty set.
+    pub const fn empty() -> Self {
+        FdSet([0; FD_SETSIZE / BITS_PER_WORD])
+    }
+
+    /// Adds `fd` to the set, fails with `EINVAL` if it's negative or not below `FD_SETSIZE`.
+    pub fn insert(&mut self, fd: RawFd) -> io::Result<()> {
+        let (word, bit) = Self::position(fd)?;
+        self.0[word] |= bit;
+        Ok(())
+    }
+
+    /// Removes `fd` from the set.
+    pub fn remove(&mut self, fd: RawFd) {
+        if let Ok((word, bit)) = Self::position(fd) {
+            self.0[word] &= !bit;
+        }
+    }
+
+    /// Checks if `fd` is in the set.
+    pub fn contains(&self, fd: RawFd) -> bool {
+        match Self::position(fd) {
+            Ok((word, bit)) => self.0[word] & bit != 0,
+            Err(_) => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::empty();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&word| word == 0)
+    }
+
+    /// Iterates over the descriptors in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = RawFd> + '_ {
+        (0..FD_SETSIZE as RawFd).filter(move |&fd| self.contains(fd))
+    }
+
+    // The highest descriptor in the set + 1, the `nfds` of `select(2)`.
+    fn nfds(&self) -> usize {
+        self.iter().last().map_or(0, |fd| fd as usize + 1)
+    }
+
+    fn position(fd: RawFd) -> io::Result<(usize, c_ulong)> {
+        if fd < 0 || fd as usize >= FD_SETSIZE {
+            return Err(io::Error::from_raw_os_error(EINVAL as i32));
+        }
+        let fd = fd as usize;
+        Ok((fd / BITS_PER_WORD, 1 << (fd % BITS_PER_WORD)))
+    }
+}
+
+impl Default for FdSet {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl core::fmt::Debug for FdSet {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+// The 6th argument of `pselect6`, as it only has 6 registers.
+#[repr(C)]
+struct SigmaskArg {
+    ss: *const SigSet,
+    ss_len: usize,
+}
+
+/// Wait for one of the descriptors in `readfds` to become readable, `writefds` writable,
+/// or `exceptfds` to have an exceptional condition, for up to `timeout` (`None` waits forever).
+/// The signal mask is atomically replaced with `sigmask` while waiting.
+/// Only the ready descriptors are left in the sets, returns how many there are (0 if the timeout expired).
+#[inline]
+pub unsafe fn pselect6(
+    mut readfds: Option<&mut FdSet>,
+    mut writefds: Option<&mut FdSet>,
+    mut exceptfds: Option<&mut FdSet>,
+    timeout: Option<Duration>,
+    sigmask: Option<&SigSet>,
+) -> io::Result<usize> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::Pselect6Time64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::Pselect6;
+
+    // Every set is `FD_SETSIZE` bits, so the kernel can't access beyond them.
+    let nfds = [&readfds, &writefds, &exceptfds]
+        .iter()
+        .filter_map(|set| set.as_ref().map(|set| set.nfds()))
+        .max()
+        .unwrap_or(0);
+    let set_ptr = |set: &mut Option<&mut FdSet>| {
+        set.as_mut()
+            .map(|set| *set as *mut FdSet)
+            .unwrap_or(ptr::null_mut()) as isize
+    };
+    let (readfds, writefds, exceptfds) = (
+        set_ptr(&mut readfds),
+        set_ptr(&mut writefds),
+        set_ptr(&mut exceptfds),
+    );
+    let mut timeout = timeout.map(timespec_from_duration);
+    let sigmask = sigmask.map(|set| SigmaskArg {
+        ss: set,
+        ss_len: size_of::<SigSet>(),
+    });
+    let res = syscall!(
+        nr,
+        nfds as isize,
+        readfds,
+        writefds,
+        exceptfds,
+        timeout
+            .as_mut()
+            .map(|t| t as *mut __kernel_timespec)
+            .unwrap_or(ptr::null_mut()) as isize,
+        sigmask
+            .as_ref()
+            .map(|arg| arg as *const SigmaskArg)
+            .unwrap_or(ptr::null()) as isize,
+    );
+    result!(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventfd::{EfdFlags, EventFd};
+    use std::time::Instant;
+
+    #[test]
+    fn test_poll() {
+        let fd = EventFd::new(0, EfdFlags::new().nonblock()).unwrap();
+        let mut fds = [
+            PollFd::from_fd(&fd, PollEvents::IN),
+            PollFd::new(-1, PollEvents::IN),
+        ];
+        let start = Instant::now();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(poll(&mut fds, Some(timeout)).unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+
+        fd.add(1).unwrap();
+        assert_eq!(poll(&mut fds, None).unwrap(), 1);
+        assert!(fds[0].revents().contains(PollEvents::IN));
+        assert!(fds[1].revents().is_empty());
+
+        let mut fds = [PollFd::from_fd(&fd, PollEvents::OUT)];
+        let res = unsafe { ppoll(&mut fds, Some(Duration::ZERO), Some(&SigSet::empty())) };
+        assert_eq!(res.unwrap(), 1);
+        assert_eq!(fds[0].revents(), PollEvents::OUT);
+
+        // Closed descriptors are reported, not an error.
+        let mut fds = [PollFd::new(9999, PollEvents::IN)];
+        assert_eq!(poll(&mut fds, Some(Duration::ZERO)).unwrap(), 1);
+        assert_eq!(fds[0].revents(), PollEvents::NVAL);
+    }
+
+    #[test]
+    fn test_fdset() {
+        let mut set = FdSet::empty();
+        set.insert(3).unwrap();
+        set.insert(FD_SETSIZE as RawFd - 1).unwrap();
+        assert_eq!(set.iter().collect::<Vec<_>>(), [3, FD_SETSIZE as RawFd - 1]);
+        assert_eq!(set.nfds(), FD_SETSIZE);
+        let err = set.insert(FD_SETSIZE as RawFd).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(set.insert(-1).is_err());
+        assert!(!set.contains(FD_SETSIZE as RawFd));
+        set.remove(FD_SETSIZE as RawFd - 1);
+        assert_eq!(set.nfds(), 4);
+        set.clear();
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_pselect6() {
+        let readable = EventFd::new(1, EfdFlags::new()).unwrap();
+        let empty = EventFd::new(0, EfdFlags::new()).unwrap();
+        let mut readfds = FdSet::empty();
+        readfds.insert(readable.as_raw_fd()).unwrap();
+        readfds.insert(empty.as_raw_fd()).unwrap();
+        let mut writefds = FdSet::empty();
+        writefds.insert(empty.as_raw_fd()).unwrap();
+
+        let res = unsafe {
+            pselect6(
+                Some(&mut readfds),
+                Some(&mut writefds),
+                None,
+                None,
+                Some(&SigSet::empty()),
+            )
+        };
+        assert_eq!(res.unwrap(), 2);
+        assert_eq!(readfds.iter().collect::<Vec<_>>(), [readable.as_raw_fd()]);
+        assert_eq!(writefds.iter().collect::<Vec<_>>(), [empty.as_raw_fd()]);
+
+        let mut readfds = FdSet::empty();
+        readfds.insert(empty.as_raw_fd()).unwrap();
+        let timeout = Some(Duration::from_millis(5));
+        let res = unsafe { pselect6(Some(&mut readfds), None, None, timeout, None) };
+        assert_eq!(res.unwrap(), 0);
+        assert!(readfds.is_empty());
+    }
+}
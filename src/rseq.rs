@@ -0,0 +1,283 @@
+//! Restartable sequences, see the kernel's `include/uapi/linux/rseq.h`.
+//!
+//! Every thread can register one `struct rseq` with the kernel, which keeps the CPU the thread runs on up to date in it.
+//! So reading the current CPU is a plain load instead of a syscall. glibc (2.35+) registers an area for every thread,
+//! in which case we use that one, as a second registration isn't possible.
+use crate::arch::{thread_pointer, Syscalls};
+use crate::vdso;
+use crate::{result, result_none, static_assert, syscall};
+use std::cell::Cell;
+use std::io;
+use std::mem::size_of;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use linux_sys::EBUSY;
+
+// `enum rseq_flags` and `enum rseq_cpu_id_state`.
+const RSEQ_FLAG_UNREGISTER: u32 = 1 << 0;
+const RSEQ_CPU_ID_UNINITIALIZED: u32 = -1i32 as u32;
+
+/// The signature that has to precede abort handlers, this is the value glibc uses on x86 so we can unregister its area.
+pub const RSEQ_SIG: u32 = 0x5305_3053;
+
+/// The original 32 bytes `struct rseq`, the kernel writes to it on every return to userspace of its thread.
+#[repr(C, align(32))]
+#[derive(Debug)]
+pub struct Rseq {
+    cpu_id_start: AtomicU32,
+    cpu_id: AtomicU32,
+    rseq_cs: AtomicU64,
+    flags: AtomicU32,
+}
+
+static_assert!(size_of::<Rseq>() == 32);
+
+impl Rseq {
+    /// A new unregistered area.
+    pub const fn new() -> Self {
+        Rseq {
+            cpu_id_start: AtomicU32::new(0),
+            cpu_id: AtomicU32::new(RSEQ_CPU_ID_UNINITIALIZED),
+            rseq_cs: AtomicU64::new(0),
+            flags: AtomicU32::new(0),
+        }
+    }
+
+    /// The CPU the thread is running on, `None` if the area isn't registered.
+    #[inline]
+    pub fn cpu_id(&self) -> Option<u32> {
+        let cpu = self.cpu_id.load(Ordering::Relaxed);
+        if (cpu as i32) < 0 {
+            None
+        } else {
+            Some(cpu)
+        }
+    }
+
+    /// Like [`cpu_id`](#method.cpu_id), but always a valid CPU number (0 if unregistered).
+    #[inline]
+    pub fn cpu_id_start(&self) -> u32 {
+        self.cpu_id_start.load(Ordering::Relaxed)
+    }
+
+    /// The currently active `struct rseq_cs` critical section descriptor, 0 outside of critical sections.
+    pub fn rseq_cs(&self) -> &AtomicU64 {
+        &self.rseq_cs
+    }
+}
+
+impl Default for Rseq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Register `area` for the calling thread, aborts of critical sections jump to handlers preceded by `sig`.
+/// Fails with `EBUSY` if this exact area is already registered, and `EINVAL` if another area is.
+///
+/// The area must stay valid until it's unregistered or the thread exits.
+#[inline]
+pub unsafe fn rseq_register(area: *const Rseq, sig: u32) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::Rseq,
+        area as isize,
+        size_of::<Rseq>() as isize,
+        0,
+        sig as isize,
+    );
+    result_none!(res)
+}
+
+/// Unregister `area` of the calling thread, `sig` must be the signature it was registered with.
+#[inline]
+pub unsafe fn rseq_unregister(area: *const Rseq, sig: u32) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::Rseq,
+        area as isize,
+        size_of::<Rseq>() as isize,
+        RSEQ_FLAG_UNREGISTER as isize,
+        sig as isize,
+    );
+    result_none!(res)
+}
+
+// glibc exports the offset of its area from the thread pointer, and its size (0 if it didn't register).
+// These are weak so we still link without glibc, or with a glibc older than 2.35.
+extern "C" {
+    #[linkage = "extern_weak"]
+    static __rseq_offset: *const isize;
+    #[linkage = "extern_weak"]
+    static __rseq_size: *const u32;
+}
+
+fn libc_area() -> Option<*const Rseq> {
+    unsafe {
+        if __rseq_offset.is_null() || __rseq_size.is_null() || *__rseq_size == 0 {
+            return None;
+        }
+        let area = (thread_pointer() as isize + *__rseq_offset) as *const Rseq;
+        // It could have been unregistered behind glibc's back.
+        (*area).cpu_id().map(|_| area)
+    }
+}
+
+thread_local! {
+    static OWN_AREA: Rseq = const { Rseq::new() };
+    static CURRENT: Cell<*const Rseq> = const { Cell::new(ptr::null()) };
+}
+
+/// Who registered the area of a [`ThreadRseq`](struct.ThreadRseq.html).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Registrar {
+    /// The C library (glibc 2.35+).
+    Libc,
+    /// This crate.
+    Crate,
+}
+
+/// The registered rseq area of the current thread, this isn't `Send` as the area is per thread.
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadRseq {
+    area: *const Rseq,
+    registrar: Registrar,
+}
+
+impl ThreadRseq {
+    /// Returns the area of the current thread, registering one if libc didn't.
+    pub fn get() -> io::Result<Self> {
+        let current = CURRENT.with(Cell::get);
+        let own = OWN_AREA.with(|area| area as *const Rseq);
+        if !current.is_null() {
+            let registrar = if current == own {
+                Registrar::Crate
+            } else {
+                Registrar::Libc
+            };
+            return Ok(ThreadRseq {
+                area: current,
+                registrar,
+            });
+        }
+        let (area, registrar) = match libc_area() {
+            Some(area) => (area, Registrar::Libc),
+            None => {
+                match unsafe { rseq_register(own, RSEQ_SIG) } {
+                    Ok(()) => (),
+                    Err(ref e) if e.raw_os_error() == Some(EBUSY as i32) => (),
+                    Err(e) => return Err(e),
+                }
+                (own, Registrar::Crate)
+            }
+        };
+        CURRENT.with(|current| current.set(area));
+        Ok(ThreadRseq { area, registrar })
+    }
+
+    pub fn registrar(&self) -> Registrar {
+        self.registrar
+    }
+
+    pub fn area(&self) -> &Rseq {
+        unsafe { &*self.area }
+    }
+
+    /// The CPU the thread is running on.
+    #[inline]
+    pub fn cpu_id(&self) -> u32 {
+        // Never negative once registered.
+        self.area().cpu_id_start()
+    }
+}
+
+/// Returns the CPU the current thread is running on, without a syscall after the first call on every thread.
+pub fn current_cpu() -> io::Result<u32> {
+    Ok(ThreadRseq::get()?.cpu_id())
+}
+
+/// Returns the CPU and the NUMA node the current thread is running on, with the vDSO or the `getcpu(2)` syscall.
+#[inline]
+pub fn getcpu() -> io::Result<(u32, u32)> {
+    let (mut cpu, mut node) = (0u32, 0u32);
+    let res = match vdso::functions().getcpu {
+        Some(getcpu) => unsafe { getcpu(&mut cpu, &mut node, ptr::null_mut()) },
+        None => unsafe {
+            syscall!(
+                Syscalls::Getcpu,
+                &mut cpu as *mut u32 as isize,
+                &mut node as *mut u32 as isize,
+                0
+            )
+        },
+    };
+    result!(res).map(|_: usize| (cpu, node))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::_exit;
+    use std::thread;
+
+    fn configured_cpus() -> u32 {
+        unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) as u32 }
+    }
+
+    #[test]
+    fn test_current_cpu() {
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    let rseq = ThreadRseq::get().unwrap();
+                    assert!(rseq.cpu_id() < configured_cpus());
+                    assert!(rseq.area().cpu_id().is_some());
+                    assert_eq!(ThreadRseq::get().unwrap().area, rseq.area);
+                    current_cpu().unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap() < configured_cpus());
+        }
+        let (cpu, _node) = getcpu().unwrap();
+        assert!(cpu < configured_cpus());
+    }
+
+    #[test]
+    fn test_rseq_register() {
+        // A second area can't be registered, whoever registered the first.
+        let rseq = ThreadRseq::get().unwrap();
+        let other = Box::new(Rseq::new());
+        let err = unsafe { rseq_register(&*other, RSEQ_SIG) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        if rseq.registrar() != Registrar::Libc {
+            return;
+        }
+        // Take over from libc in a child, so it doesn't matter what we leave behind.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let ok = (|| {
+                // Newer glibc registers a bigger area, which we can't unregister with our size.
+                if unsafe { rseq_unregister(rseq.area, RSEQ_SIG) }.is_err() {
+                    return Some(());
+                }
+                if rseq.area().cpu_id().is_some() {
+                    return None;
+                }
+                CURRENT.with(|current| current.set(ptr::null()));
+                let own = ThreadRseq::get().ok()?;
+                if own.registrar() != Registrar::Crate || own.area().cpu_id()? >= configured_cpus()
+                {
+                    return None;
+                }
+                Some(())
+            })();
+            _exit(ok.is_none() as i32);
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
@@ -0,0 +1,981 @@
+use crate::arch::{restore_rt, Syscalls};
+use crate::utils::timespec_from_duration;
+use crate::{result, result_none, static_assert, syscall};
+use std::mem::{size_of, MaybeUninit};
+use std::os::raw::{c_long, c_ulong, c_void};
+use std::time::Duration;
+use std::{io, ptr};
+
+use linux_sys::{
+    __kernel_timespec, stack_t, NSIGBUS, NSIGCHLD, NSIGFPE, NSIGILL, NSIGPOLL, NSIGSEGV, NSIGSYS,
+    NSIGTRAP, SA_NOCLDSTOP, SA_NOCLDWAIT, SA_NODEFER, SA_ONSTACK, SA_RESETHAND, SA_RESTART,
+    SA_RESTORER, SA_SIGINFO, SIGBUS, SIGCHLD, SIGFPE, SIGILL, SIGPOLL, SIGSEGV, SIGSYS, SIGTRAP,
+    SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK, SI_KERNEL, SI_MAX_SIZE, SI_QUEUE, SI_SIGIO, SI_TIMER,
+    SI_USER,
+};
+
+/// The number of signals the kernel supports (`_NSIG`), including the real-time signals.
+pub const NSIG: u32 = 64;
+
+/// A set of signals, in the layout the kernel expects.
+///
+/// This is *not* glibc's `sigset_t`, which is 128 bytes long. the kernel only looks at `_NSIG` bits,
+/// and the syscalls take the size of the set as an argument.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[repr(transparent)]
+pub struct SigSet(u64);
+
+impl SigSet {
+    /// Creates a set without any signals.
+    pub const fn empty() -> Self {
+        SigSet(0)
+    }
+
+    /// Creates a set with all the signals.
+    pub const fn full() -> Self {
+        SigSet(!0)
+    }
+
+    /// Adds `signal` to the set.
+    ///
+    /// # Panics
+    /// If `signal` isn't in `1..=NSIG`.
+    pub fn insert(&mut self, signal: u32) {
+        self.0 |= Self::bit(signal);
+    }
+
+    /// Removes `signal` from the set.
+    pub fn remove(&mut self, signal: u32) {
+        self.0 &= !Self::bit(signal);
+    }
+
+    /// Checks if `signal` is in the set.
+    pub fn contains(&self, signal: u32) -> bool {
+        self.0 & Self::bit(signal) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The signals that are in either `self` or `other`.
+    pub fn union(&self, other: &SigSet) -> SigSet {
+        SigSet(self.0 | other.0)
+    }
+
+    /// The signals that are in both `self` and `other`.
+    pub fn intersection(&self, other: &SigSet) -> SigSet {
+        SigSet(self.0 & other.0)
+    }
+
+    /// The signals that are in `self` but not in `other`.
+    pub fn difference(&self, other: &SigSet) -> SigSet {
+        SigSet(self.0 & !other.0)
+    }
+
+    /// The signals that aren't in `self`.
+    pub fn complement(&self) -> SigSet {
+        SigSet(!self.0)
+    }
+
+    /// Iterates over the signals in the set, in ascending order.
+    pub fn iter(&self) -> SigSetIter {
+        SigSetIter(self.0)
+    }
+
+    fn bit(signal: u32) -> u64 {
+        assert!(signal > 0 && signal <= NSIG, "Invalid signal: {}", signal);
+        1 << (signal - 1)
+    }
+}
+
+impl core::iter::FromIterator<u32> for SigSet {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> Self {
+        let mut set = SigSet::empty();
+        iter.into_iter().for_each(|signal| set.insert(signal));
+        set
+    }
+}
+
+impl IntoIterator for SigSet {
+    type Item = u32;
+    type IntoIter = SigSetIter;
+
+    fn into_iter(self) -> SigSetIter {
+        self.iter()
+    }
+}
+
+/// An iterator over the signals in a [`SigSet`](struct.SigSet.html).
+#[derive(Clone, Debug)]
+pub struct SigSetIter(u64);
+
+impl Iterator for SigSetIter {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.0 == 0 {
+            return None;
+        }
+        let bit = self.0.trailing_zeros();
+        self.0 &= self.0 - 1;
+        Some(bit + 1)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.0.count_ones() as usize;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for SigSetIter {}
+
+/// The `siginfo_t` the kernel passes to `SA_SIGINFO` handlers.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SigInfo {
+    signo: i32,
+    errno: i32,
+    code: i32,
+    fields: SigFields,
+}
+
+// See `union __sifields` in asm-generic/siginfo.h.
+// The `pad` member makes the union size match `SI_MAX_SIZE` on both 32 and 64 bit.
+#[derive(Clone, Copy)]
+#[repr(C)]
+union SigFields {
+    kill: KillFields,
+    timer: TimerFields,
+    rt: RtFields,
+    chld: ChldFields,
+    fault: FaultFields,
+    poll: PollFields,
+    pad: [i32; SI_PAD_SIZE],
+}
+
+const SI_PREAMBLE_SIZE: usize = (3 + (size_of::<c_long>() == 8) as usize) * size_of::<i32>();
+const SI_PAD_SIZE: usize = (SI_MAX_SIZE as usize - SI_PREAMBLE_SIZE) / size_of::<i32>();
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct KillFields {
+    pid: i32,
+    uid: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct TimerFields {
+    tid: i32,
+    overrun: i32,
+    value: usize,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RtFields {
+    pid: i32,
+    uid: u32,
+    value: usize,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct ChldFields {
+    pid: i32,
+    uid: u32,
+    status: i32,
+    utime: c_long,
+    stime: c_long,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct FaultFields {
+    addr: *mut c_void,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct PollFields {
+    band: c_long,
+    fd: i32,
+}
+
+static_assert!(size_of::<SigInfo>() == SI_MAX_SIZE as usize);
+
+// Which member of `SigFields` is valid, this follows `siginfo_layout()` in the kernel's kernel/signal.c.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SigLayout {
+    Kill,
+    Timer,
+    Rt,
+    Chld,
+    Fault,
+    Poll,
+    Sys,
+}
+
+impl SigInfo {
+    /// Creates a `siginfo_t` for sending `signal` with [`rt_sigqueueinfo`](fn.rt_sigqueueinfo.html),
+    /// like `sigqueue(3)` does: the code is `SI_QUEUE` and the sender is the calling process.
+    pub fn new(signal: u32) -> Self {
+        let mut info = SigInfo {
+            signo: signal as i32,
+            errno: 0,
+            code: SI_QUEUE,
+            fields: SigFields {
+                pad: [0; SI_PAD_SIZE],
+            },
+        };
+        info.fields.rt = RtFields {
+            pid: crate::getpid() as i32,
+            uid: unsafe { crate::getuid() }.unwrap_or_default(),
+            value: 0,
+        };
+        info
+    }
+
+    /// Set the value (`sigval`) the receiver will get.
+    pub fn with_value(mut self, value: usize) -> Self {
+        self.fields.rt.value = value;
+        self
+    }
+
+    /// Set the `si_code`. The kernel only allows negative codes when sending to another process.
+    pub fn with_code(mut self, code: i32) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Set the pid and uid of the sender the receiver will see.
+    pub fn with_sender(mut self, pid: u32, uid: u32) -> Self {
+        self.fields.rt.pid = pid as i32;
+        self.fields.rt.uid = uid;
+        self
+    }
+
+    /// The signal number.
+    pub fn signo(&self) -> u32 {
+        self.signo as u32
+    }
+
+    /// The errno value associated with this signal, usually 0.
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+
+    /// The raw `si_code`, describing why the signal was sent.
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    /// The sending process, for signals sent by `kill(2)`, `sigqueue(3)` or a child changing state.
+    pub fn pid(&self) -> Option<i32> {
+        match self.layout() {
+            SigLayout::Kill | SigLayout::Rt | SigLayout::Chld => {
+                Some(unsafe { self.fields.kill.pid })
+            }
+            _ => None,
+        }
+    }
+
+    /// The real user ID of the sending process, see [`pid`](#method.pid).
+    pub fn uid(&self) -> Option<u32> {
+        match self.layout() {
+            SigLayout::Kill | SigLayout::Rt | SigLayout::Chld => {
+                Some(unsafe { self.fields.kill.uid })
+            }
+            _ => None,
+        }
+    }
+
+    /// The exit code or the signal of the child, for `SIGCHLD`. use [`code`](#method.code) to tell which.
+    pub fn status(&self) -> Option<i32> {
+        match self.layout() {
+            SigLayout::Chld => Some(unsafe { self.fields.chld.status }),
+            _ => None,
+        }
+    }
+
+    /// The value (`sigval`) that was sent with a queued signal or a timer.
+    pub fn value(&self) -> Option<usize> {
+        match self.layout() {
+            SigLayout::Rt => Some(unsafe { self.fields.rt.value }),
+            SigLayout::Timer => Some(unsafe { self.fields.timer.value }),
+            _ => None,
+        }
+    }
+
+    /// The timer ID and overrun count, for signals sent by POSIX timers.
+    pub fn timer(&self) -> Option<(i32, i32)> {
+        match self.layout() {
+            SigLayout::Timer => {
+                let timer = unsafe { self.fields.timer };
+                Some((timer.tid, timer.overrun))
+            }
+            _ => None,
+        }
+    }
+
+    /// The faulting address, for `SIGSEGV`, `SIGBUS`, `SIGILL`, `SIGFPE` and `SIGTRAP` sent by the kernel.
+    pub fn addr(&self) -> Option<*mut c_void> {
+        match self.layout() {
+            SigLayout::Fault | SigLayout::Sys => Some(unsafe { self.fields.fault.addr }),
+            _ => None,
+        }
+    }
+
+    /// The file descriptor and the band event, for `SIGPOLL`/`SIGIO` sent by the kernel.
+    pub fn poll(&self) -> Option<(i32, c_long)> {
+        match self.layout() {
+            SigLayout::Poll => {
+                let poll = unsafe { self.fields.poll };
+                Some((poll.fd, poll.band))
+            }
+            _ => None,
+        }
+    }
+
+    // Port of the kernel's `siginfo_layout()`: the signal specific codes are only the ones up to the
+    // signal's limit, the other positive codes are what `F_SETSIG` sends along with the `POLL_*` band.
+    fn layout(&self) -> SigLayout {
+        let code = self.code;
+        if code > SI_USER as i32 && code < SI_KERNEL as i32 {
+            let (limit, layout) = match self.signo as u32 {
+                SIGILL => (NSIGILL, SigLayout::Fault),
+                SIGFPE => (NSIGFPE, SigLayout::Fault),
+                SIGSEGV => (NSIGSEGV, SigLayout::Fault),
+                SIGBUS => (NSIGBUS, SigLayout::Fault),
+                SIGTRAP => (NSIGTRAP, SigLayout::Fault),
+                SIGCHLD => (NSIGCHLD, SigLayout::Chld),
+                SIGPOLL => (NSIGPOLL, SigLayout::Poll),
+                SIGSYS => (NSIGSYS, SigLayout::Sys),
+                _ => (0, SigLayout::Kill),
+            };
+            if code <= limit as i32 {
+                layout
+            } else if code <= NSIGPOLL as i32 {
+                SigLayout::Poll
+            } else {
+                SigLayout::Kill
+            }
+        } else {
+            match code {
+                SI_TIMER => SigLayout::Timer,
+                SI_SIGIO => SigLayout::Poll,
+                code if code < 0 => SigLayout::Rt,
+                _ => SigLayout::Kill,
+            }
+        }
+    }
+}
+
+impl core::fmt::Debug for SigInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SigInfo")
+            .field("signo", &self.signo)
+            .field("errno", &self.errno)
+            .field("code", &self.code)
+            .field("pid", &self.pid())
+            .field("uid", &self.uid())
+            .field("status", &self.status())
+            .field("value", &self.value())
+            .field("addr", &self.addr())
+            .finish()
+    }
+}
+
+/// What should happen when a signal is delivered.
+#[derive(Clone, Copy, Debug)]
+pub enum SigHandler {
+    /// The default action for the signal (`SIG_DFL`).
+    Default,
+    /// Ignore the signal (`SIG_IGN`).
+    Ignore,
+    /// A handler that only gets the signal number.
+    Handler(extern "C" fn(i32)),
+    /// A handler that also gets the `siginfo_t` and the `ucontext_t`, this sets `SA_SIGINFO`.
+    SigAction(extern "C" fn(i32, *mut SigInfo, *mut c_void)),
+}
+
+/// Additional options for [`sigaction`](fn.sigaction.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct SaFlags(c_ulong);
+
+impl SaFlags {
+    /// Creates new `SaFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Don't get `SIGCHLD` when children stop or resume.
+    pub fn nocldstop(mut self) -> Self {
+        self.0 |= SA_NOCLDSTOP as c_ulong;
+        self
+    }
+
+    /// Don't turn terminated children into zombies.
+    pub fn nocldwait(mut self) -> Self {
+        self.0 |= SA_NOCLDWAIT as c_ulong;
+        self
+    }
+
+    /// Call the handler on the alternate signal stack, see `sigaltstack(2)`.
+    pub fn onstack(mut self) -> Self {
+        self.0 |= SA_ONSTACK as c_ulong;
+        self
+    }
+
+    /// Restart syscalls interrupted by the handler instead of failing with `EINTR`.
+    pub fn restart(mut self) -> Self {
+        self.0 |= SA_RESTART as c_ulong;
+        self
+    }
+
+    /// Don't block the signal while its handler is running.
+    pub fn nodefer(mut self) -> Self {
+        self.0 |= SA_NODEFER as c_ulong;
+        self
+    }
+
+    /// Reset the handler to `SIG_DFL` once it was called.
+    pub fn resethand(mut self) -> Self {
+        self.0 |= SA_RESETHAND as c_ulong;
+        self
+    }
+}
+
+impl core::fmt::Debug for SaFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SaFlags")
+            .field("NOCLDSTOP", &(self.0 & SA_NOCLDSTOP as c_ulong > 0))
+            .field("NOCLDWAIT", &(self.0 & SA_NOCLDWAIT as c_ulong > 0))
+            .field("ONSTACK", &(self.0 & SA_ONSTACK as c_ulong > 0))
+            .field("RESTART", &(self.0 & SA_RESTART as c_ulong > 0))
+            .field("NODEFER", &(self.0 & SA_NODEFER as c_ulong > 0))
+            .field("RESETHAND", &(self.0 & SA_RESETHAND as c_ulong > 0))
+            .finish()
+    }
+}
+
+/// The disposition of a signal, see [`rt_sigaction`](fn.rt_sigaction.html).
+#[derive(Clone, Copy, Debug)]
+pub struct SigAction {
+    pub handler: SigHandler,
+    /// Signals to block while the handler is running.
+    pub mask: SigSet,
+    pub flags: SaFlags,
+}
+
+impl SigAction {
+    pub fn new(handler: SigHandler, mask: SigSet, flags: SaFlags) -> Self {
+        Self {
+            handler,
+            mask,
+            flags,
+        }
+    }
+}
+
+// This is the kernel's `struct sigaction`, not the one from glibc (or from the uapi headers on i686).
+#[repr(C)]
+struct KernelSigAction {
+    handler: usize,
+    flags: c_ulong,
+    restorer: usize,
+    mask: SigSet,
+}
+
+impl From<&SigAction> for KernelSigAction {
+    fn from(act: &SigAction) -> Self {
+        let (handler, siginfo) = match act.handler {
+            SigHandler::Default => (0, 0),
+            SigHandler::Ignore => (1, 0),
+            SigHandler::Handler(f) => (f as usize, 0),
+            SigHandler::SigAction(f) => (f as usize, SA_SIGINFO as c_ulong),
+        };
+        Self {
+            handler,
+            // x86 requires us to provide a restorer, otherwise the handler will return into garbage.
+            flags: act.flags.0 | siginfo | SA_RESTORER as c_ulong,
+            restorer: restore_rt as *const () as usize,
+            mask: act.mask,
+        }
+    }
+}
+
+impl From<&KernelSigAction> for SigAction {
+    fn from(act: &KernelSigAction) -> Self {
+        let handler = match act.handler {
+            0 => SigHandler::Default,
+            1 => SigHandler::Ignore,
+            // Safety: The kernel only stores what someone passed to `rt_sigaction`, which must be a function.
+            f if act.flags & SA_SIGINFO as c_ulong != 0 => SigHandler::SigAction(unsafe {
+                std::mem::transmute::<usize, extern "C" fn(i32, *mut SigInfo, *mut c_void)>(f)
+            }),
+            f => {
+                SigHandler::Handler(unsafe { std::mem::transmute::<usize, extern "C" fn(i32)>(f) })
+            }
+        };
+        let flags = SaFlags(act.flags & !(SA_SIGINFO | SA_RESTORER) as c_ulong);
+        SigAction::new(handler, act.mask, flags)
+    }
+}
+
+/// Change (if `act` is `Some`) and return the previous action of `signal`.
+// TODO: Can we make this safe? the handler runs in an async-signal context which rust knows nothing about.
+#[inline]
+pub unsafe fn rt_sigaction(signal: u32, act: Option<&SigAction>) -> io::Result<SigAction> {
+    let act = act.map(KernelSigAction::from);
+    let mut old: MaybeUninit<KernelSigAction> = MaybeUninit::uninit();
+    let res = syscall!(
+        Syscalls::RtSigaction,
+        signal as isize,
+        act.as_ref()
+            .map(|a| a as *const KernelSigAction)
+            .unwrap_or(ptr::null()) as isize,
+        old.as_mut_ptr() as isize,
+        size_of::<SigSet>() as isize,
+    );
+    result_none!(res)?;
+    Ok(SigAction::from(&old.assume_init()))
+}
+
+/// Set the action of `signal`, returning the previous one.
+#[inline]
+pub unsafe fn sigaction(signal: u32, act: &SigAction) -> io::Result<SigAction> {
+    rt_sigaction(signal, Some(act))
+}
+
+/// How [`rt_sigprocmask`](fn.rt_sigprocmask.html) should change the signal mask.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SigmaskHow {
+    /// Add the set to the blocked signals.
+    Block = SIG_BLOCK as isize,
+    /// Remove the set from the blocked signals.
+    Unblock = SIG_UNBLOCK as isize,
+    /// Replace the blocked signals with the set.
+    SetMask = SIG_SETMASK as isize,
+}
+
+/// Change (if `set` is `Some`) and return the previous signal mask of the calling thread.
+/// This is what `pthread_sigmask(3)` does, `sigprocmask(2)` is unspecified in multithreaded processes.
+#[inline]
+pub unsafe fn rt_sigprocmask(how: SigmaskHow, set: Option<&SigSet>) -> io::Result<SigSet> {
+    let mut old = SigSet::empty();
+    let res = syscall!(
+        Syscalls::RtSigprocmask,
+        how as isize,
+        set.map(|s| s as *const SigSet).unwrap_or(ptr::null()) as isize,
+        &mut old as *mut SigSet as isize,
+        size_of::<SigSet>() as isize,
+    );
+    result_none!(res)?;
+    Ok(old)
+}
+
+/// Returns the signals that are pending on the calling thread or the process.
+#[inline]
+pub fn rt_sigpending() -> io::Result<SigSet> {
+    let mut set = SigSet::empty();
+    let res = unsafe {
+        syscall!(
+            Syscalls::RtSigpending,
+            &mut set as *mut SigSet as isize,
+            size_of::<SigSet>() as isize,
+        )
+    };
+    result_none!(res)?;
+    Ok(set)
+}
+
+/// Temporarily replace the signal mask with `mask` and wait until a signal handler was called.
+/// This always "fails", with `EINTR` after a handler returned.
+#[inline]
+pub unsafe fn rt_sigsuspend(mask: &SigSet) -> io::Error {
+    let res = syscall!(
+        Syscalls::RtSigsuspend,
+        mask as *const SigSet as isize,
+        size_of::<SigSet>() as isize,
+    );
+    debug_assert!(res < 0);
+    io::Error::from_raw_os_error(-res as i32)
+}
+
+/// Wait for one of the signals in `set` to become pending and dequeue it, without calling its handler.
+/// The signals in `set` should be blocked beforehand. `None` waits forever,
+/// when the timeout expires this fails with `EAGAIN` (`io::ErrorKind::WouldBlock`).
+#[inline]
+pub unsafe fn rt_sigtimedwait(set: &SigSet, timeout: Option<Duration>) -> io::Result<SigInfo> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::RtSigtimedwaitTime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::RtSigtimedwait;
+
+    let timeout = timeout.map(timespec_from_duration);
+    let mut info: MaybeUninit<SigInfo> = MaybeUninit::uninit();
+    let res = syscall!(
+        nr,
+        set as *const SigSet as isize,
+        info.as_mut_ptr() as isize,
+        timeout
+            .as_ref()
+            .map(|t| t as *const __kernel_timespec)
+            .unwrap_or(ptr::null()) as isize,
+        size_of::<SigSet>() as isize,
+    );
+    let signo: u32 = result!(res)?;
+    let info = info.assume_init();
+    debug_assert_eq!(signo, info.signo());
+    Ok(info)
+}
+
+/// Set (if `ss` is `Some`) and return the previous alternate signal stack of the calling thread.
+/// Handlers installed with [`SaFlags::onstack`](struct.SaFlags.html#method.onstack) run on it.
+#[inline]
+pub unsafe fn sigaltstack(ss: Option<&stack_t>) -> io::Result<stack_t> {
+    let mut old: MaybeUninit<stack_t> = MaybeUninit::uninit();
+    let res = syscall!(
+        Syscalls::Sigaltstack,
+        ss.map(|s| s as *const stack_t).unwrap_or(ptr::null()) as isize,
+        old.as_mut_ptr() as isize,
+    );
+    result_none!(res)?;
+    Ok(old.assume_init())
+}
+
+/// Send `signal` to the thread `tid` in the thread group (process) `tgid`.
+#[inline]
+pub unsafe fn tgkill(tgid: u32, tid: u32, signal: u32) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::Tgkill,
+        tgid as isize,
+        tid as isize,
+        signal as isize
+    );
+    result_none!(res)
+}
+
+/// Send `signal` to the thread `tid`. This is racy if the thread can exit and its tid reused, prefer [`tgkill`](fn.tgkill.html).
+#[inline]
+pub unsafe fn tkill(tid: u32, signal: u32) -> io::Result<()> {
+    let res = syscall!(Syscalls::Tkill, tid as isize, signal as isize);
+    result_none!(res)
+}
+
+/// Send `signal` with `info` to the process `tgid`, see [`SigInfo::new`](struct.SigInfo.html#method.new).
+#[inline]
+pub unsafe fn rt_sigqueueinfo(tgid: u32, signal: u32, info: &SigInfo) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::RtSigqueueinfo,
+        tgid as isize,
+        signal as isize,
+        info as *const SigInfo as isize,
+    );
+    result_none!(res)
+}
+
+/// Send `signal` with `info` to the thread `tid` in the process `tgid`.
+#[inline]
+pub unsafe fn rt_tgsigqueueinfo(
+    tgid: u32,
+    tid: u32,
+    signal: u32,
+    info: &SigInfo,
+) -> io::Result<()> {
+    let res = syscall!(
+        Syscalls::RtTgsigqueueinfo,
+        tgid as isize,
+        tid as isize,
+        signal as isize,
+        info as *const SigInfo as isize,
+    );
+    result_none!(res)
+}
+
+/// Set the handler of `signal` with BSD semantics (`SA_RESTART`) like glibc's `signal(3)`, returning the previous one.
+#[inline]
+pub unsafe fn signal(signal: u32, handler: SigHandler) -> io::Result<SigHandler> {
+    let act = SigAction::new(handler, SigSet::empty(), SaFlags::new().restart());
+    sigaction(signal, &act).map(|old| old.handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use linux_sys::{
+        f_owner_ex, ENOMEM, FASYNC, F_OWNER_TID, F_SETFL, F_SETOWN_EX, F_SETSIG, MINSIGSTKSZ,
+        O_NONBLOCK, POLL_IN, SIGCHLD, SIGKILL, SIGRTMIN, SIGSTKSZ, SIGTERM, SIGUSR1, SIGUSR2,
+        SI_TKILL, SS_ONSTACK,
+    };
+    use std::os::raw::c_void;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    static RECEIVED: AtomicU32 = AtomicU32::new(0);
+
+    extern "C" fn handler(signo: i32, info: *mut SigInfo, _: *mut c_void) {
+        let info = unsafe { &*info };
+        assert_eq!(info.signo(), signo as u32);
+        RECEIVED.store(info.signo(), Ordering::SeqCst);
+    }
+
+    fn wait_for(signal: u32) {
+        for _ in 0..1000 {
+            if RECEIVED.load(Ordering::SeqCst) == signal {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("signal {} wasn't delivered", signal);
+    }
+
+    #[test]
+    fn test_sigaction() {
+        let mut mask = SigSet::empty();
+        mask.insert(SIGUSR2);
+        let act = SigAction::new(
+            SigHandler::SigAction(handler),
+            mask,
+            SaFlags::new().restart(),
+        );
+        let old = unsafe { sigaction(SIGUSR1, &act) }.unwrap();
+        assert!(matches!(old.handler, SigHandler::Default));
+
+        unsafe { crate::kill(std::process::id(), SIGUSR1) }.unwrap();
+        wait_for(SIGUSR1);
+
+        let current = unsafe { sigaction(SIGUSR1, &old) }.unwrap();
+        match current.handler {
+            SigHandler::SigAction(f) => assert_eq!(f as usize, handler as *const () as usize),
+            h => panic!("unexpected handler: {:?}", h),
+        }
+        assert_eq!(current.mask, mask);
+        assert_eq!(current.flags, act.flags);
+        let current = unsafe { rt_sigaction(SIGUSR1, None) }.unwrap();
+        assert!(matches!(current.handler, SigHandler::Default));
+    }
+
+    #[test]
+    fn test_signal_kill() {
+        let err = unsafe { signal(SIGKILL, SigHandler::Ignore) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_sigaltstack() {
+        // Run in a new thread so we don't leave a dangling stack installed on a test thread.
+        // (std installs its own alternate stack on new threads, which we restore at the end)
+        thread::spawn(|| {
+            let mut stack = vec![0u8; SIGSTKSZ as usize];
+            let new = stack_t {
+                ss_sp: stack.as_mut_ptr() as *mut c_void,
+                ss_flags: 0,
+                ss_size: stack.len() as _,
+            };
+            let old = unsafe { sigaltstack(Some(&new)) }.unwrap();
+            assert_eq!(old.ss_flags & SS_ONSTACK as i32, 0);
+            let current = unsafe { sigaltstack(None) }.unwrap();
+            assert_eq!(current.ss_sp, new.ss_sp);
+            assert_eq!(current.ss_size, new.ss_size);
+            unsafe { sigaltstack(Some(&old)) }.unwrap();
+
+            let small = stack_t {
+                ss_size: (MINSIGSTKSZ - 1) as _,
+                ..new
+            };
+            let err = unsafe { sigaltstack(Some(&small)) }.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(ENOMEM as i32));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_tgkill() {
+        const SIGNAL: u32 = SIGRTMIN + 6;
+        let mut set = SigSet::empty();
+        set.insert(SIGNAL);
+        let old = unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.unwrap();
+
+        unsafe { tgkill(crate::getpid(), crate::gettid(), SIGNAL) }.unwrap();
+        let info = unsafe { rt_sigtimedwait(&set, Some(Duration::from_secs(1))) }.unwrap();
+        assert_eq!(info.code(), SI_TKILL);
+        assert_eq!(info.pid(), Some(crate::getpid() as i32));
+
+        unsafe { tkill(crate::gettid(), SIGNAL) }.unwrap();
+        let info = unsafe { rt_sigtimedwait(&set, Some(Duration::from_secs(1))) }.unwrap();
+        assert_eq!(info.code(), SI_TKILL);
+        unsafe { rt_sigprocmask(SigmaskHow::SetMask, Some(&old)) }.unwrap();
+    }
+
+    #[test]
+    fn test_tgsigqueueinfo() {
+        const SIGNAL: u32 = SIGRTMIN + 7;
+        let mut set = SigSet::empty();
+        set.insert(SIGNAL);
+        let old = unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.unwrap();
+
+        let sent = SigInfo::new(SIGNAL).with_value(0xdead_beef);
+        unsafe { rt_tgsigqueueinfo(crate::getpid(), crate::gettid(), SIGNAL, &sent) }.unwrap();
+        let info = unsafe { rt_sigtimedwait(&set, Some(Duration::from_secs(1))) }.unwrap();
+        assert_eq!(info.signo(), SIGNAL);
+        assert_eq!(info.code(), SI_QUEUE);
+        assert_eq!(info.value(), Some(0xdead_beef));
+        assert_eq!(info.pid(), Some(crate::getpid() as i32));
+        assert_eq!(info.uid(), Some(unsafe { crate::getuid() }.unwrap()));
+        unsafe { rt_sigprocmask(SigmaskHow::SetMask, Some(&old)) }.unwrap();
+    }
+
+    #[test]
+    fn test_sigqueueinfo() {
+        // Only negative codes can be sent to other processes, so this doesn't actually send anything to init.
+        let info = SigInfo::new(SIGUSR1).with_code(0);
+        let err = unsafe { rt_sigqueueinfo(1, SIGUSR1, &info) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        // A process directed signal can go to any thread, so this has to run in a single threaded process.
+        const SIGNAL: u32 = SIGRTMIN + 8;
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let ok = (|| {
+                let mut set = SigSet::empty();
+                set.insert(SIGNAL);
+                unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.ok()?;
+                let sent = SigInfo::new(SIGNAL).with_value(42).with_sender(1, 2);
+                unsafe { rt_sigqueueinfo(crate::getpid(), SIGNAL, &sent) }.ok()?;
+                let info = unsafe { rt_sigtimedwait(&set, None) }.ok()?;
+                Some(info.value() == Some(42) && info.pid() == Some(1) && info.uid() == Some(2))
+            })();
+            crate::_exit(if ok == Some(true) { 0 } else { 1 });
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    #[test]
+    fn test_sigset() {
+        let mut set = SigSet::empty();
+        assert!(!set.contains(SIGUSR1));
+        set.insert(SIGUSR1);
+        set.insert(NSIG);
+        assert!(set.contains(SIGUSR1));
+        assert!(set.contains(NSIG));
+        assert!(!set.contains(SIGUSR2));
+        set.remove(NSIG);
+        assert!(!set.contains(NSIG));
+    }
+
+    #[test]
+    fn test_sigset_ops() {
+        let a: SigSet = [SIGUSR1, SIGUSR2, SIGTERM].iter().copied().collect();
+        let b: SigSet = [SIGUSR2, SIGCHLD].iter().copied().collect();
+        assert_eq!(
+            a.union(&b).iter().collect::<Vec<_>>(),
+            [SIGUSR1, SIGUSR2, SIGTERM, SIGCHLD]
+        );
+        assert_eq!(a.intersection(&b).iter().collect::<Vec<_>>(), [SIGUSR2]);
+        assert_eq!(
+            a.difference(&b).iter().collect::<Vec<_>>(),
+            [SIGUSR1, SIGTERM]
+        );
+        assert_eq!(a.complement().iter().len(), NSIG as usize - 3);
+        assert!(a.intersection(&a.complement()).is_empty());
+        assert_eq!(SigSet::full().into_iter().last(), Some(NSIG));
+    }
+
+    #[test]
+    fn test_sigtimedwait() {
+        let mut set = SigSet::empty();
+        set.insert(SIGUSR2);
+        let old = unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.unwrap();
+        assert!(!old.contains(SIGUSR2));
+        let current = unsafe { rt_sigprocmask(SigmaskHow::Block, None) }.unwrap();
+        assert!(current.contains(SIGUSR2));
+
+        assert_eq!(unsafe { libc::raise(SIGUSR2 as i32) }, 0);
+        assert!(rt_sigpending().unwrap().contains(SIGUSR2));
+        let info = unsafe { rt_sigtimedwait(&set, Some(Duration::from_secs(1))) }.unwrap();
+        assert_eq!(info.signo(), SIGUSR2);
+        assert_eq!(info.code(), SI_TKILL);
+        assert_eq!(info.pid(), Some(std::process::id() as i32));
+        assert_eq!(info.status(), None);
+        assert!(!rt_sigpending().unwrap().contains(SIGUSR2));
+
+        let err = unsafe { rt_sigtimedwait(&set, Some(Duration::from_millis(10))) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        unsafe { rt_sigprocmask(SigmaskHow::SetMask, Some(&old)) }.unwrap();
+    }
+
+    #[test]
+    fn test_sigtimedwait_setsig() {
+        const SIGNAL: u32 = SIGRTMIN + 7;
+        let mut set = SigSet::empty();
+        set.insert(SIGNAL);
+        let old = unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // Direct the signal to this thread, where it's blocked.
+        let owner = f_owner_ex {
+            type_: F_OWNER_TID as i32,
+            pid: crate::gettid() as i32,
+        };
+        unsafe {
+            assert_eq!(libc::fcntl(fds[0], F_SETOWN_EX as i32, &owner), 0);
+            assert_eq!(libc::fcntl(fds[0], F_SETSIG as i32, SIGNAL as i32), 0);
+            assert_eq!(
+                libc::fcntl(fds[0], F_SETFL as i32, (FASYNC | O_NONBLOCK) as i32),
+                0
+            );
+            assert_eq!(libc::write(fds[1], b"x".as_ptr() as *const c_void, 1), 1);
+        }
+
+        let info = unsafe { rt_sigtimedwait(&set, Some(Duration::from_secs(1))) }.unwrap();
+        assert_eq!(info.signo(), SIGNAL);
+        assert_eq!(info.code(), POLL_IN as i32);
+        let (fd, band) = info.poll().unwrap();
+        assert_eq!(fd, fds[0]);
+        assert_ne!(band & libc::POLLIN as c_long, 0);
+        assert_eq!(info.pid(), None);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+            rt_sigprocmask(SigmaskHow::SetMask, Some(&old)).unwrap();
+        }
+    }
+
+    static SUSPENDED: AtomicU32 = AtomicU32::new(0);
+
+    extern "C" fn suspend_handler(signo: i32) {
+        SUSPENDED.store(signo as u32, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_sigsuspend() {
+        const SIGNAL: u32 = SIGRTMIN + 4;
+        let act = SigAction::new(
+            SigHandler::Handler(suspend_handler),
+            SigSet::empty(),
+            SaFlags::new(),
+        );
+        let old_act = unsafe { sigaction(SIGNAL, &act) }.unwrap();
+        let mut set = SigSet::empty();
+        set.insert(SIGNAL);
+        let old = unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.unwrap();
+
+        assert_eq!(unsafe { libc::raise(SIGNAL as i32) }, 0);
+        assert_eq!(SUSPENDED.load(Ordering::SeqCst), 0);
+        let err = unsafe { rt_sigsuspend(&old.difference(&set)) };
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(SUSPENDED.load(Ordering::SeqCst), SIGNAL);
+
+        unsafe { rt_sigprocmask(SigmaskHow::SetMask, Some(&old)) }.unwrap();
+        unsafe { sigaction(SIGNAL, &old_act) }.unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+use crate::arch::Syscalls;
+use crate::signal::SigSet;
+use crate::{close, read, result, syscall};
+use std::io;
+use std::mem::{size_of, MaybeUninit};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::slice;
+
+use linux_sys::{
+    signalfd_siginfo, CLD_CONTINUED, CLD_DUMPED, CLD_EXITED, CLD_KILLED, CLD_STOPPED, CLD_TRAPPED,
+    SFD_CLOEXEC, SFD_NONBLOCK, SIGBUS, SIGCHLD, SIGFPE, SIGILL, SIGSEGV, SIGTRAP, SI_QUEUE,
+    SI_TKILL, SI_USER,
+};
+
+/// Additional options for [`signalfd4`](fn.signalfd4.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct SfdFlags(isize);
+
+impl SfdFlags {
+    /// Creates new `SfdFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set non-blocking mode on the new descriptor.
+    pub fn nonblock(mut self) -> Self {
+        self.0 |= SFD_NONBLOCK as isize;
+        self
+    }
+
+    /// Set close-on-exec on the new descriptor.
+    pub fn cloexec(mut self) -> Self {
+        self.0 |= SFD_CLOEXEC as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for SfdFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SfdFlags")
+            .field("NONBLOCK", &(self.0 & SFD_NONBLOCK as isize > 0))
+            .field("CLOEXEC", &(self.0 & SFD_CLOEXEC as isize > 0))
+            .finish()
+    }
+}
+
+/// Create a file descriptor that signals in `mask` can be read from, or change the mask of `fd` if it's `Some`.
+/// The signals should be blocked with [`rt_sigprocmask`](../signal/fn.rt_sigprocmask.html), otherwise they'll be handled as usual.
+#[inline]
+pub unsafe fn signalfd4<F: AsRawFd>(
+    fd: Option<&F>,
+    mask: &SigSet,
+    flags: SfdFlags,
+) -> io::Result<usize> {
+    let fd = fd.map(|fd| fd.as_raw_fd()).unwrap_or(-1);
+    let res = syscall!(
+        Syscalls::Signalfd4,
+        fd as isize,
+        mask as *const SigSet as isize,
+        size_of::<SigSet>() as isize,
+        flags.0,
+    );
+    result!(res)
+}
+
+/// How a child changed state, decoded from a `SIGCHLD`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum ChildStatus {
+    /// The child called `_exit(2)` with this status.
+    Exited(i32),
+    /// The child was killed by this signal.
+    Killed(u32),
+    /// The child was killed by this signal and dumped core.
+    Dumped(u32),
+    /// The child was stopped by this signal.
+    Stopped(u32),
+    /// A traced child has trapped.
+    Trapped(u32),
+    /// A stopped child was continued by `SIGCONT`.
+    Continued,
+}
+
+/// A signal read from a [`SignalFd`](struct.SignalFd.html), decoded by the kind of its sender.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum SignalInfo {
+    /// A child process changed state (`SIGCHLD` sent by the kernel).
+    Child {
+        pid: u32,
+        uid: u32,
+        status: ChildStatus,
+    },
+    /// Sent by a process with `kill(2)`, `tgkill(2)` and friends.
+    User { signo: u32, pid: u32, uid: u32 },
+    /// Sent by a process with `sigqueue(3)`, carrying a value.
+    Queued {
+        signo: u32,
+        pid: u32,
+        uid: u32,
+        value: u64,
+    },
+    /// A hardware fault (`SIGSEGV`, `SIGBUS`, `SIGILL`, `SIGFPE`, `SIGTRAP`) at `addr`.
+    Fault { signo: u32, code: i32, addr: u64 },
+    /// Anything else sent by the kernel (e.g. timers and `SIGIO`).
+    Other { signo: u32, code: i32 },
+}
+
+impl SignalInfo {
+    /// The signal number.
+    pub fn signo(&self) -> u32 {
+        match *self {
+            SignalInfo::Child { .. } => SIGCHLD,
+            SignalInfo::User { signo, .. }
+            | SignalInfo::Queued { signo, .. }
+            | SignalInfo::Fault { signo, .. }
+            | SignalInfo::Other { signo, .. } => signo,
+        }
+    }
+}
+
+impl From<&signalfd_siginfo> for SignalInfo {
+    fn from(info: &signalfd_siginfo) -> Self {
+        let signo = info.ssi_signo;
+        let code = info.ssi_code;
+        // Only `SI_USER`, `SI_TKILL` and `SI_QUEUE` carry a sender, the other non positive codes
+        // (`SI_TIMER`, `SI_MESGQ`, `SI_ASYNCIO`, `SI_SIGIO`) are queued by the kernel itself.
+        if code == SI_QUEUE {
+            return SignalInfo::Queued {
+                signo,
+                pid: info.ssi_pid,
+                uid: info.ssi_uid,
+                value: info.ssi_ptr,
+            };
+        } else if code == SI_USER as i32 || code == SI_TKILL {
+            return SignalInfo::User {
+                signo,
+                pid: info.ssi_pid,
+                uid: info.ssi_uid,
+            };
+        }
+        if code <= 0 {
+            return SignalInfo::Other { signo, code };
+        }
+        let code_u = code as u32;
+        let status = info.ssi_status;
+        match signo {
+            SIGCHLD => {
+                let status = match code_u {
+                    CLD_EXITED => ChildStatus::Exited(status),
+                    CLD_KILLED => ChildStatus::Killed(status as u32),
+                    CLD_DUMPED => ChildStatus::Dumped(status as u32),
+                    CLD_STOPPED => ChildStatus::Stopped(status as u32),
+                    CLD_TRAPPED => ChildStatus::Trapped(status as u32),
+                    CLD_CONTINUED => ChildStatus::Continued,
+                    _ => return SignalInfo::Other { signo, code },
+                };
+                SignalInfo::Child {
+                    pid: info.ssi_pid,
+                    uid: info.ssi_uid,
+                    status,
+                }
+            }
+            SIGSEGV | SIGBUS | SIGILL | SIGFPE | SIGTRAP => SignalInfo::Fault {
+                signo,
+                code,
+                addr: info.ssi_addr,
+            },
+            _ => SignalInfo::Other { signo, code },
+        }
+    }
+}
+
+/// A file descriptor to receive signals through, see `signalfd(2)`.
+/// The descriptor is closed on drop.
+#[derive(Debug)]
+pub struct SignalFd(RawFd);
+
+impl SignalFd {
+    /// Create a new `signalfd` for the signals in `mask`.
+    /// The signals should be blocked in every thread, otherwise they'll be handled as usual.
+    pub fn new(mask: &SigSet, flags: SfdFlags) -> io::Result<Self> {
+        let fd = unsafe { signalfd4::<RawFd>(None, mask, flags) }?;
+        Ok(SignalFd(fd as RawFd))
+    }
+
+    /// Replace the signals this descriptor receives.
+    pub fn set_mask(&mut self, mask: &SigSet) -> io::Result<()> {
+        unsafe { signalfd4(Some(&self.0), mask, SfdFlags::new()) }?;
+        Ok(())
+    }
+
+    /// Read the next signal, blocking unless the descriptor is non-blocking.
+    /// Returns `None` if it's non-blocking and no signal is pending.
+    pub fn read_raw(&mut self) -> io::Result<Option<signalfd_siginfo>> {
+        let mut info: MaybeUninit<signalfd_siginfo> = MaybeUninit::uninit();
+        let buf = unsafe {
+            slice::from_raw_parts_mut(info.as_mut_ptr() as *mut u8, size_of::<signalfd_siginfo>())
+        };
+        match unsafe { read(&self.0, buf) } {
+            Ok(len) => {
+                debug_assert_eq!(len, size_of::<signalfd_siginfo>());
+                Ok(Some(unsafe { info.assume_init() }))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read and decode the next signal, see [`read_raw`](#method.read_raw).
+    pub fn read_signal(&mut self) -> io::Result<Option<SignalInfo>> {
+        self.read_raw()
+            .map(|info| info.as_ref().map(SignalInfo::from))
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        unsafe {
+            close(&self.0).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::{rt_sigprocmask, SigmaskHow};
+    use crate::time::ClockId;
+    use crate::timer::{Notify, PosixTimer};
+    use crate::{_exit, gettid, kill};
+    use linux_sys::{SIGRTMIN, SI_SIGIO, SI_TIMER};
+    use std::time::Duration;
+
+    #[test]
+    fn test_signalfd_user() {
+        const SIGNAL: u32 = SIGRTMIN + 5;
+        let mut set = SigSet::empty();
+        set.insert(SIGNAL);
+        let old = unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.unwrap();
+        let mut fd = SignalFd::new(&set, SfdFlags::new().nonblock().cloexec()).unwrap();
+        assert_eq!(fd.read_signal().unwrap(), None);
+
+        assert_eq!(unsafe { libc::raise(SIGNAL as i32) }, 0);
+        let info = fd.read_signal().unwrap().unwrap();
+        assert_eq!(info.signo(), SIGNAL);
+        assert_eq!(
+            info,
+            SignalInfo::User {
+                signo: SIGNAL,
+                pid: std::process::id(),
+                uid: unsafe { libc::getuid() },
+            }
+        );
+        assert_eq!(fd.read_signal().unwrap(), None);
+        unsafe { rt_sigprocmask(SigmaskHow::SetMask, Some(&old)) }.unwrap();
+    }
+
+    #[test]
+    fn test_signalfd_timer() {
+        const SIGNAL: u32 = SIGRTMIN + 6;
+        let mut set = SigSet::empty();
+        set.insert(SIGNAL);
+        let old = unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.unwrap();
+        let mut fd = SignalFd::new(&set, SfdFlags::new().cloexec()).unwrap();
+        let notify = Notify::ThreadSignal {
+            signo: SIGNAL,
+            value: 7,
+            tid: gettid(),
+        };
+        let mut timer = PosixTimer::new(ClockId::Monotonic, notify).unwrap();
+        timer.set(Duration::from_millis(1), None).unwrap();
+
+        let info = fd.read_signal().unwrap().unwrap();
+        assert_eq!(
+            info,
+            SignalInfo::Other {
+                signo: SIGNAL,
+                code: SI_TIMER,
+            }
+        );
+        drop(timer);
+        unsafe { rt_sigprocmask(SigmaskHow::SetMask, Some(&old)) }.unwrap();
+    }
+
+    #[test]
+    fn test_signal_info_kernel_codes() {
+        let mut info: signalfd_siginfo = unsafe { std::mem::zeroed() };
+        info.ssi_signo = SIGRTMIN;
+        for &code in &[SI_TIMER, SI_SIGIO] {
+            info.ssi_code = code;
+            assert_eq!(
+                SignalInfo::from(&info),
+                SignalInfo::Other {
+                    signo: SIGRTMIN,
+                    code
+                }
+            );
+        }
+        info.ssi_code = SI_TKILL;
+        info.ssi_pid = 1;
+        assert_eq!(
+            SignalInfo::from(&info),
+            SignalInfo::User {
+                signo: SIGRTMIN,
+                pid: 1,
+                uid: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_signalfd_child() {
+        // SIGCHLD is process directed, so this has to run in a single threaded process.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let mut set = SigSet::empty();
+            set.insert(SIGCHLD);
+            let ok = (|| {
+                unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.ok()?;
+                let mut fd = SignalFd::new(&set, SfdFlags::new()).ok()?;
+                let child = unsafe { libc::fork() };
+                if child == 0 {
+                    _exit(3);
+                }
+                let exited = fd.read_signal().ok()??;
+                if exited
+                    != (SignalInfo::Child {
+                        pid: child as u32,
+                        uid: unsafe { libc::getuid() },
+                        status: ChildStatus::Exited(3),
+                    })
+                {
+                    return None;
+                }
+                unsafe { kill(std::process::id(), SIGCHLD) }.ok()?;
+                match fd.read_signal().ok()?? {
+                    SignalInfo::User { signo: SIGCHLD, .. } => Some(()),
+                    _ => None,
+                }
+            })();
+            _exit(ok.is_none() as i32);
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
@@ -1,10 +1,14 @@
 use crate::arch::Syscalls;
-use crate::{result, syscall};
+use crate::{result, result_none, syscall};
 use std::io;
+use std::mem::size_of;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
 
 use libc::{
     AF_INET, AF_INET6, AF_UNIX, SOCK_CLOEXEC, SOCK_DGRAM, SOCK_NONBLOCK, SOCK_RAW, SOCK_RDM,
-    SOCK_SEQPACKET, SOCK_STREAM,
+    SOCK_SEQPACKET, SOCK_STREAM, SOL_SOCKET, SO_ERROR,
 };
 
 use linux_sys::{IPPROTO_TCP, IPPROTO_UDP};
@@ -56,7 +60,7 @@ pub enum SockProtocol {
 
 /// Additional socket options.
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
-pub struct SockFlags(isize);
+pub struct SockFlags(pub(crate) isize);
 
 impl SockFlags {
     /// Creates new `SocketFlags`.
@@ -100,11 +104,112 @@ pub unsafe fn socket(
     result!(res)
 }
 
+// `struct sockaddr_in`, the port and address are in network byte order.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockaddrIn {
+    family: u16,
+    port: u16,
+    addr: [u8; 4],
+    zero: [u8; 8],
+}
+
+// `struct sockaddr_in6`, the port and address are in network byte order.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockaddrIn6 {
+    family: u16,
+    port: u16,
+    flowinfo: u32,
+    addr: [u8; 16],
+    scope_id: u32,
+}
+
+#[repr(C)]
+union Sockaddr {
+    v4: SockaddrIn,
+    v6: SockaddrIn6,
+}
+
+// Returns the `sockaddr` of `addr` and its length.
+fn sockaddr(addr: &SocketAddr) -> (Sockaddr, usize) {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let v4 = SockaddrIn {
+                family: AF_INET as u16,
+                port: addr.port().to_be(),
+                addr: addr.ip().octets(),
+                zero: [0; 8],
+            };
+            (Sockaddr { v4 }, size_of::<SockaddrIn>())
+        }
+        SocketAddr::V6(addr) => {
+            let v6 = SockaddrIn6 {
+                family: AF_INET6 as u16,
+                port: addr.port().to_be(),
+                flowinfo: addr.flowinfo().to_be(),
+                addr: addr.ip().octets(),
+                scope_id: addr.scope_id(),
+            };
+            (Sockaddr { v6 }, size_of::<SockaddrIn6>())
+        }
+    }
+}
+
+/// Accept a connection on the listening `socket`, and return the new connected socket.
+/// `flags` are set on the new socket, it doesn't inherit them from `socket`.
+#[inline]
+pub unsafe fn accept4<F: AsRawFd>(socket: &F, flags: SockFlags) -> io::Result<usize> {
+    // TODO: Return the peer address.
+    let res = syscall!(
+        Syscalls::Accept4,
+        socket.as_raw_fd() as isize,
+        ptr::null_mut::<u8>() as isize,
+        ptr::null_mut::<u32>() as isize,
+        flags.0,
+    );
+    result!(res)
+}
+
+/// Connect `socket` to `addr`. If `socket` is non-blocking this fails with `EINPROGRESS` while the connection
+/// is established, the socket is then writable when it's done and [`take_error`](fn.take_error.html) returns its result.
+#[inline]
+pub unsafe fn connect<F: AsRawFd>(socket: &F, addr: &SocketAddr) -> io::Result<()> {
+    let (addr, len) = sockaddr(addr);
+    let res = syscall!(
+        Syscalls::Connect,
+        socket.as_raw_fd() as isize,
+        &addr as *const Sockaddr as isize,
+        len as isize,
+    );
+    result_none!(res)
+}
+
+/// Returns and clears the pending error of `socket` (`SO_ERROR`), e.g. the result of a non-blocking `connect`.
+#[inline]
+pub unsafe fn take_error<F: AsRawFd>(socket: &F) -> io::Result<Option<io::Error>> {
+    let mut error = 0i32;
+    let mut len = size_of::<i32>() as u32;
+    let res = syscall!(
+        Syscalls::Getsockopt,
+        socket.as_raw_fd() as isize,
+        SOL_SOCKET as isize,
+        SO_ERROR as isize,
+        &mut error as *mut i32 as isize,
+        &mut len as *mut u32 as isize,
+    );
+    result_none!(res)?;
+    Ok(Some(error)
+        .filter(|&error| error != 0)
+        .map(io::Error::from_raw_os_error))
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
     use crate::close;
-    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::io::Write;
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 
     pub struct Socket {
         fd: RawFd,
@@ -158,4 +263,34 @@ pub(crate) mod tests {
         .unwrap();
         Socket::new(rawfd as RawFd);
     }
+
+    #[test]
+    fn test_connect_accept() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let flags = SockFlags::new().cloexec();
+        let client = unsafe { socket(AddressFamily::Inet, SockType::Stream, flags, None) }.unwrap();
+        let client = Socket::new(client as RawFd);
+        unsafe { connect(&client, &addr) }.unwrap();
+        assert!(unsafe { take_error(&client) }.unwrap().is_none());
+
+        let server = unsafe { accept4(&listener, flags) }.unwrap();
+        let mut server = unsafe { std::net::TcpStream::from_raw_fd(server as RawFd) };
+        assert_eq!(server.peer_addr().unwrap(), client_addr(&client));
+        server.write_all(b"hi").unwrap();
+
+        // Nothing listens on the port anymore.
+        drop(listener);
+        let client = unsafe { socket(AddressFamily::Inet, SockType::Stream, flags, None) }.unwrap();
+        let client = Socket::new(client as RawFd);
+        let err = unsafe { connect(&client, &addr) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    fn client_addr(client: &Socket) -> SocketAddr {
+        let stream = unsafe { std::net::TcpStream::from_raw_fd(client.as_raw_fd()) };
+        let addr = stream.local_addr().unwrap();
+        let _ = stream.into_raw_fd();
+        addr
+    }
 }
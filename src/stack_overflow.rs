@@ -0,0 +1,259 @@
+//! A libc free version of std's stack overflow handler.
+//!
+//! The guard page below a thread's stack turns an overflow into a `SIGSEGV`, but the handler can't run
+//! on the stack that just overflowed, so it runs on an alternate stack (see `sigaltstack(2)`).
+use crate::arch::PAGE_SIZE;
+use crate::mman::{mprotect, page_align, MapFlags, Mapping, ProtFlags};
+use crate::signal::{
+    sigaction, sigaltstack, signal, SaFlags, SigAction, SigHandler, SigInfo, SigSet,
+};
+use crate::{_exit, write, FileDescriptor};
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::os::raw::c_void;
+use std::ptr;
+
+use linux_sys::{stack_t, SIGBUS, SIGSEGV, SIGSTKSZ, SS_DISABLE};
+
+/// The default size of an [`AltStack`](struct.AltStack.html), `SIGSTKSZ` is too small for the signal frames of modern CPUs.
+pub const DEFAULT_ALTSTACK_SIZE: usize = 64 * 1024;
+
+// What a shell reports for a process that was killed by `SIGSEGV`.
+const STACK_OVERFLOW_EXIT_CODE: i32 = 128 + SIGSEGV as i32;
+
+/// An alternate signal stack with a guard page below it, unmapped on drop.
+///
+/// This is per thread, and isn't `Send` as it has to be dropped on the thread it was installed on.
+#[derive(Debug)]
+pub struct AltStack {
+    map: Mapping,
+    // Make sure we're not `Send`.
+    _not_send: PhantomData<*mut u8>,
+}
+
+impl AltStack {
+    /// Map a new stack of at least `size` bytes (rounded up to whole pages) plus a guard page.
+    pub fn new(size: usize) -> io::Result<Self> {
+        let len = page_align(size.max(SIGSTKSZ as usize)) + PAGE_SIZE;
+        let prot = ProtFlags::new().read().write();
+        let flags = MapFlags::new().private().stack();
+        let map = unsafe { Mapping::new::<i32>(len, prot, flags, None, 0) }?;
+        unsafe { mprotect(map.as_ptr(), PAGE_SIZE, ProtFlags::new()) }?;
+        Ok(AltStack {
+            map,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// The `stack_t` describing this stack, without the guard page.
+    pub fn as_stack_t(&self) -> stack_t {
+        stack_t {
+            ss_sp: unsafe { self.map.as_ptr().add(PAGE_SIZE) } as *mut c_void,
+            ss_flags: 0,
+            ss_size: (self.map.len() - PAGE_SIZE) as _,
+        }
+    }
+
+    /// Use this stack for signal handlers on the calling thread, returning the previous stack.
+    ///
+    /// This also finds the guard of the calling thread's stack for the stack overflow handler,
+    /// without `/proc` the thread's overflows are reported as any other `SIGSEGV`.
+    pub fn install(&self) -> io::Result<stack_t> {
+        if let Ok(guard) = stack_guard() {
+            GUARD.with(|cell| cell.set((guard.start, guard.end)));
+        }
+        unsafe { sigaltstack(Some(&self.as_stack_t())) }
+    }
+}
+
+impl Drop for AltStack {
+    fn drop(&mut self) {
+        unsafe {
+            // Don't leave the kernel pointing at an unmapped stack.
+            if let Ok(current) = sigaltstack(None) {
+                if current.ss_sp == self.as_stack_t().ss_sp {
+                    let disable = stack_t {
+                        ss_sp: ptr::null_mut(),
+                        ss_flags: SS_DISABLE as _,
+                        ss_size: 0,
+                    };
+                    sigaltstack(Some(&disable)).ok();
+                }
+            }
+        }
+    }
+}
+
+/// Install a `SIGSEGV`/`SIGBUS` handler that reports stack overflows, and an alternate stack for the calling thread.
+///
+/// When a fault hits the guard below the stack of the faulting thread, the handler writes "stack overflow" to stderr
+/// and exits (like std does). Other faults are handled as if the handler wasn't installed.
+/// this replaces any previous handler (e.g. std's).
+///
+/// The guard is found in `/proc/self/maps` when the [`AltStack`](struct.AltStack.html) is installed,
+/// so every other thread that should be covered has to install its own.
+pub fn install_stack_overflow_handler() -> io::Result<AltStack> {
+    let stack = AltStack::new(DEFAULT_ALTSTACK_SIZE)?;
+    stack.install()?;
+    let act = SigAction::new(
+        SigHandler::SigAction(stack_overflow_handler),
+        SigSet::empty(),
+        SaFlags::new().onstack(),
+    );
+    for &sig in &[SIGSEGV, SIGBUS] {
+        unsafe { sigaction(sig, &act) }?;
+    }
+    Ok(stack)
+}
+
+thread_local! {
+    // The guard of the thread's stack, the handler can't read `/proc` so it's found beforehand.
+    static GUARD: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+// The addresses an overflow of the calling thread's stack faults at. A thread's stack has a `PROT_NONE` guard
+// mapping right below it, while the main thread's stack grows down into the gap below it until it hits
+// `RLIMIT_STACK` or the kernel's stack guard gap.
+fn stack_guard() -> io::Result<Range<usize>> {
+    let marker = 0u8;
+    let sp = &marker as *const u8 as usize;
+    let maps = fs::read_to_string("/proc/self/maps")?;
+    let mut below = 0..0;
+    let mut below_is_guard = false;
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let range = fields.next().and_then(|range| {
+            let (start, end) = range.split_once('-')?;
+            let start = usize::from_str_radix(start, 16).ok()?;
+            let end = usize::from_str_radix(end, 16).ok()?;
+            Some(start..end)
+        });
+        let (range, perms) = match (range, fields.next()) {
+            (Some(range), Some(perms)) => (range, perms),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Malformed /proc/self/maps",
+                ))
+            }
+        };
+        if range.contains(&sp) {
+            return Ok(if below_is_guard && below.end == range.start {
+                below
+            } else {
+                below.end..range.start
+            });
+        }
+        below_is_guard = perms.starts_with("---");
+        below = range;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "The stack isn't mapped",
+    ))
+}
+
+fn is_stack_overflow(addr: usize) -> bool {
+    let (start, end) = GUARD.try_with(Cell::get).unwrap_or((0, 0));
+    (start..end).contains(&addr)
+}
+
+extern "C" fn stack_overflow_handler(signo: i32, info: *mut SigInfo, _: *mut c_void) {
+    let addr = unsafe { (*info).addr() }.map(|addr| addr as usize);
+    match addr {
+        Some(addr) if is_stack_overflow(addr) => {
+            let msg = b"stack overflow\n";
+            unsafe { write(&mut FileDescriptor(2), msg) }.ok();
+            _exit(STACK_OVERFLOW_EXIT_CODE);
+        }
+        _ => {
+            // Not an overflow, returning will fault again and now get the default action.
+            unsafe { signal(signo as u32, SigHandler::Default) }.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hint::black_box;
+    use std::io::Read;
+
+    #[allow(unconditional_recursion)]
+    fn recurse(n: u64) -> u64 {
+        let buf = black_box([n; 64]);
+        recurse(n + 1) + buf[3]
+    }
+
+    // Runs `f` in a forked child with stderr redirected to a pipe, returns the wait status and the output.
+    fn fork_with_stderr(f: fn()) -> (i32, String) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            unsafe { crate::dup3(&fds[1], &2, None) }.ok();
+            f();
+            _exit(0);
+        }
+        unsafe { libc::close(fds[1]) };
+        let mut output = String::new();
+        let mut reader =
+            unsafe { <std::fs::File as std::os::unix::io::FromRawFd>::from_raw_fd(fds[0]) };
+        reader.read_to_string(&mut output).unwrap();
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        (status, output)
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let (status, output) = fork_with_stderr(|| {
+            let _stack = install_stack_overflow_handler().unwrap();
+            recurse(0);
+        });
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), STACK_OVERFLOW_EXIT_CODE);
+        assert_eq!(output, "stack overflow\n");
+    }
+
+    #[test]
+    fn test_not_stack_overflow() {
+        let (status, output) = fork_with_stderr(|| {
+            let _stack = install_stack_overflow_handler().unwrap();
+            unsafe { ptr::write_volatile(ptr::null_mut::<u8>().add(16), 1) };
+        });
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::WTERMSIG(status), SIGSEGV as i32);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn test_stack_guard() {
+        let check = || {
+            let marker = 0u8;
+            let guard = stack_guard().unwrap();
+            assert!(!guard.is_empty());
+            assert!(guard.end <= &marker as *const u8 as usize);
+            assert!(!is_stack_overflow(guard.start));
+            AltStack::new(1).unwrap().install().unwrap();
+            assert!(is_stack_overflow(guard.start) && is_stack_overflow(guard.end - 1));
+            assert!(!is_stack_overflow(guard.end));
+        };
+        // A new thread, as the tests' threads can already have a guard from another test.
+        std::thread::spawn(check).join().unwrap();
+    }
+
+    #[test]
+    fn test_altstack_guard() {
+        let stack = AltStack::new(1).unwrap();
+        let ss = stack.as_stack_t();
+        assert_eq!(ss.ss_size as usize, SIGSTKSZ as usize);
+        assert_eq!(ss.ss_sp as usize, stack.map.as_ptr() as usize + PAGE_SIZE);
+        // The stack itself is writable.
+        unsafe { ptr::write_volatile(ss.ss_sp as *mut u8, 1) };
+    }
+}
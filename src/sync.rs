@@ -0,0 +1,505 @@
+//! Locks built on [`futex`](../futex/index.html), without libc's pthreads.
+//!
+//! Unlike `std::sync` there's no poisoning, a panic while holding a lock just unlocks it.
+use crate::futex::{futex_wait, futex_wait_bitset, futex_wake, FutexFlags, BITSET_MATCH_ANY};
+use crate::time::{clock_gettime, ClockId};
+use std::cell::UnsafeCell;
+use std::hint;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use linux_sys::ETIMEDOUT;
+
+// All the futexes here are only shared between the threads of a process.
+fn private() -> FutexFlags {
+    FutexFlags::new().private()
+}
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+// Locked, and there might be threads waiting for it.
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock, like `pthread_mutex_t`.
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Lock the mutex, blocking while another thread holds it. Locking it again on the same thread deadlocks.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_contended();
+        }
+        MutexGuard { mutex: self }
+    }
+
+    /// Lock the mutex if it isn't locked.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    #[cold]
+    fn lock_contended(&self) {
+        // Spin a bit first, the lock is usually held for a short time.
+        for _ in 0..100 {
+            match self.state.load(Ordering::Relaxed) {
+                UNLOCKED => match self.state.compare_exchange(
+                    UNLOCKED,
+                    LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(_) => continue,
+                },
+                CONTENDED => break,
+                _ => hint::spin_loop(),
+            }
+        }
+        // We don't know if there are other waiters, so it stays `CONTENDED` after we got it.
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            futex_wait(&self.state, CONTENDED, None, private()).ok();
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex_wake(&self.state, 1, private()).ok();
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug> core::fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        match self.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &"<locked>"),
+        };
+        d.finish()
+    }
+}
+
+/// The lock of a [`Mutex`](struct.Mutex.html), unlocked on drop.
+#[must_use = "the mutex is unlocked when the guard is dropped"]
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug> core::fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// A condition variable, like `pthread_cond_t` with a `CLOCK_MONOTONIC` clock.
+///
+/// Waiting can wake up spuriously, so the condition has to be checked again in a loop.
+#[derive(Debug, Default)]
+pub struct Condvar {
+    // Incremented on every notification, waiters sleep while it's the value they saw before unlocking.
+    seq: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            seq: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlock `guard`'s mutex and wait for a notification, then lock it again.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let seq = self.seq.load(Ordering::Relaxed);
+        drop(guard);
+        futex_wait(&self.seq, seq, None, private()).ok();
+        mutex.lock()
+    }
+
+    /// Like [`wait`](#method.wait), for up to `timeout` on the `Monotonic` clock, so changing the system time doesn't affect it.
+    /// Returns whether it timed out.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let deadline = clock_gettime(ClockId::Monotonic)
+            .ok()
+            .and_then(|now| now.checked_add(timeout));
+        let mutex = guard.mutex;
+        let seq = self.seq.load(Ordering::Relaxed);
+        drop(guard);
+        let res = futex_wait_bitset(&self.seq, seq, deadline, BITSET_MATCH_ANY, private());
+        let timed_out = matches!(res, Err(ref e) if e.raw_os_error() == Some(ETIMEDOUT as i32));
+        (mutex.lock(), timed_out)
+    }
+
+    /// Wake up one waiting thread.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.seq, 1, private()).ok();
+    }
+
+    /// Wake up all the waiting threads.
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.seq, u32::MAX, private()).ok();
+    }
+}
+
+// The state of a `RwLock` is the number of readers, or `WRITE_LOCKED`.
+const WRITE_LOCKED: u32 = u32::MAX;
+const MAX_READERS: u32 = WRITE_LOCKED - 1;
+
+/// A reader-writer lock, like `pthread_rwlock_t`. It isn't fair, a steady stream of readers can starve writers.
+pub struct RwLock<T: ?Sized> {
+    state: AtomicU32,
+    // The number of threads sleeping on `state`, so unlocking only wakes when there are any.
+    waiters: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            state: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Lock for reading, blocking while a writer holds the lock.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            self.wait(|state| state == WRITE_LOCKED || state == MAX_READERS);
+        }
+    }
+
+    /// Lock for reading if no writer holds the lock.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                if state < MAX_READERS {
+                    Some(state + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| RwLockReadGuard { lock: self })
+    }
+
+    /// Lock for writing, blocking while any reader or writer holds the lock.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            self.wait(|state| state != 0);
+        }
+    }
+
+    /// Lock for writing if nobody holds the lock.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state
+            .compare_exchange(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    // Sleep while `state` is one that `blocked` says we can't lock.
+    fn wait(&self, blocked: impl Fn(u32) -> bool) {
+        let state = self.state.load(Ordering::Relaxed);
+        if !blocked(state) {
+            return;
+        }
+        // Counting ourselves before the kernel checks `state` pairs with unlocking before checking `waiters`,
+        // so either the unlock sees us or the kernel sees the new state.
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        futex_wait(&self.state, state, None, private()).ok();
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn wake(&self) {
+        if self.waiters.load(Ordering::SeqCst) != 0 {
+            futex_wake(&self.state, u32::MAX, private()).ok();
+        }
+    }
+
+    fn read_unlock(&self) {
+        // Only the last reader has to wake the waiting writers, or readers waiting on `MAX_READERS`.
+        let prev = self.state.fetch_sub(1, Ordering::SeqCst);
+        if prev == 1 || prev == MAX_READERS {
+            self.wake();
+        }
+    }
+
+    fn write_unlock(&self) {
+        self.state.store(0, Ordering::SeqCst);
+        self.wake();
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + core::fmt::Debug> core::fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        match self.try_read() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &"<locked>"),
+        };
+        d.finish()
+    }
+}
+
+/// A read lock of a [`RwLock`](struct.RwLock.html), unlocked on drop.
+#[must_use = "the lock is unlocked when the guard is dropped"]
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+/// A write lock of a [`RwLock`](struct.RwLock.html), unlocked on drop.
+#[must_use = "the lock is unlocked when the guard is dropped"]
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_mutex() {
+        let mutex = Arc::new(Mutex::new(0u64));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mutex = Arc::clone(&mutex);
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        *mutex.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*mutex.lock(), 40_000);
+
+        let guard = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+        assert_eq!(format!("{:?}", mutex), "Mutex { data: \"<locked>\" }");
+        drop(guard);
+        assert_eq!(*mutex.try_lock().unwrap(), 40_000);
+    }
+
+    #[test]
+    fn test_condvar() {
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let pair = Arc::clone(&pair);
+                thread::spawn(move || {
+                    let (mutex, condvar) = &*pair;
+                    let mut ready = mutex.lock();
+                    while !*ready {
+                        ready = condvar.wait(ready);
+                    }
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(10));
+        let (mutex, condvar) = &*pair;
+        *mutex.lock() = true;
+        condvar.notify_all();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+
+        let timeout = Duration::from_millis(10);
+        let start = Instant::now();
+        let (guard, timed_out) = condvar.wait_timeout(mutex.lock(), timeout);
+        assert!(timed_out && *guard);
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn test_condvar_notify_one() {
+        let pair = Arc::new((Mutex::new(0), Condvar::new()));
+        let consumer = {
+            let pair = Arc::clone(&pair);
+            thread::spawn(move || {
+                let (mutex, condvar) = &*pair;
+                let mut value = mutex.lock();
+                while *value == 0 {
+                    let (guard, _) = condvar.wait_timeout(value, Duration::from_secs(10));
+                    value = guard;
+                }
+                *value
+            })
+        };
+        let (mutex, condvar) = &*pair;
+        *mutex.lock() = 42;
+        condvar.notify_one();
+        assert_eq!(consumer.join().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_rwlock() {
+        let lock = Arc::new(RwLock::new(Vec::new()));
+        let first = lock.read();
+        let second = lock.read();
+        assert!(lock.try_write().is_none());
+        assert_eq!(first.len() + second.len(), 0);
+
+        let writer = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || lock.write().push(1))
+        };
+        thread::sleep(Duration::from_millis(10));
+        drop(first);
+        assert!(lock.try_write().is_none());
+        drop(second);
+        writer.join().unwrap();
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        if i % 2 == 0 {
+                            lock.write().push(i);
+                        } else {
+                            let values = lock.read();
+                            assert!(values.iter().all(|&v| v % 2 == 0 || v == 1));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let values = lock.read();
+        assert!(lock.try_read().is_some() && lock.try_write().is_none());
+        assert_eq!(values.len(), 2001);
+    }
+}
@@ -0,0 +1,327 @@
+//! Clocks and sleeping, see `clock_gettime(2)` and `clock_nanosleep(2)`.
+//!
+//! Times are `Duration`s since the epoch of their clock, which is the Unix epoch for `Realtime`
+//! and an unspecified point (usually boot) for the others.
+use crate::arch::Syscalls;
+use crate::utils::{duration_from_timespec, timespec_from_duration};
+use crate::vdso;
+use crate::{result, result_none, syscall};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use linux_sys::{
+    __kernel_itimerspec, __kernel_timespec, CLOCK_BOOTTIME, CLOCK_BOOTTIME_ALARM, CLOCK_MONOTONIC,
+    CLOCK_MONOTONIC_COARSE, CLOCK_MONOTONIC_RAW, CLOCK_PROCESS_CPUTIME_ID, CLOCK_REALTIME,
+    CLOCK_REALTIME_ALARM, CLOCK_REALTIME_COARSE, CLOCK_TAI, CLOCK_THREAD_CPUTIME_ID, EINTR,
+    TIMER_ABSTIME,
+};
+
+// Dynamic clock ids, from `include/linux/posix-timers.h` which isn't exported to userspace.
+const CPUCLOCK_PERTHREAD_MASK: i32 = 4;
+const CPUCLOCK_SCHED: i32 = 2;
+const CLOCKFD: i32 = 3;
+
+/// A clock to read, sleep on or arm timers with.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum ClockId {
+    /// Wall clock time, can jump when it's set.
+    Realtime,
+    /// Like `Realtime` but faster and with a resolution of a tick.
+    RealtimeCoarse,
+    /// Like `Realtime`, but alarm timers on it wake the system from suspend (requires `CAP_WAKE_ALARM`).
+    RealtimeAlarm,
+    /// Time since an unspecified point, doesn't count suspend and is slewed by NTP.
+    Monotonic,
+    /// Like `Monotonic` but faster and with a resolution of a tick.
+    MonotonicCoarse,
+    /// Like `Monotonic` but not slewed by NTP.
+    MonotonicRaw,
+    /// Like `Monotonic` but counts time spent in suspend.
+    Boottime,
+    /// Like `Boottime`, but alarm timers on it wake the system from suspend (requires `CAP_WAKE_ALARM`).
+    BoottimeAlarm,
+    /// International Atomic Time, `Realtime` without leap seconds (only accurate if the offset was set by NTP).
+    Tai,
+    /// CPU time consumed by all the threads of the calling process.
+    ProcessCputime,
+    /// CPU time consumed by the calling thread.
+    ThreadCputime,
+    /// CPU time consumed by all the threads of process `pid`.
+    ProcessCputimeOf(u32),
+    /// CPU time consumed by thread `tid`, which has to be in the calling process.
+    ThreadCputimeOf(u32),
+    /// A dynamic clock of a device, e.g. `/dev/ptp0`, the fd has to stay open while the clock is used.
+    Fd(RawFd),
+}
+
+impl ClockId {
+    /// A dynamic clock of the device opened as `fd`.
+    pub fn from_fd<F: AsRawFd>(fd: &F) -> Self {
+        ClockId::Fd(fd.as_raw_fd())
+    }
+
+    /// The `clockid_t` of the clock.
+    pub fn raw(self) -> i32 {
+        match self {
+            ClockId::Realtime => CLOCK_REALTIME as i32,
+            ClockId::RealtimeCoarse => CLOCK_REALTIME_COARSE as i32,
+            ClockId::RealtimeAlarm => CLOCK_REALTIME_ALARM as i32,
+            ClockId::Monotonic => CLOCK_MONOTONIC as i32,
+            ClockId::MonotonicCoarse => CLOCK_MONOTONIC_COARSE as i32,
+            ClockId::MonotonicRaw => CLOCK_MONOTONIC_RAW as i32,
+            ClockId::Boottime => CLOCK_BOOTTIME as i32,
+            ClockId::BoottimeAlarm => CLOCK_BOOTTIME_ALARM as i32,
+            ClockId::Tai => CLOCK_TAI as i32,
+            ClockId::ProcessCputime => CLOCK_PROCESS_CPUTIME_ID as i32,
+            ClockId::ThreadCputime => CLOCK_THREAD_CPUTIME_ID as i32,
+            ClockId::ProcessCputimeOf(pid) => (!(pid as i32) << 3) | CPUCLOCK_SCHED,
+            ClockId::ThreadCputimeOf(tid) => {
+                (!(tid as i32) << 3) | CPUCLOCK_SCHED | CPUCLOCK_PERTHREAD_MASK
+            }
+            ClockId::Fd(fd) => (!fd << 3) | CLOCKFD,
+        }
+    }
+}
+
+/// Returns the current time of `clock`.
+///
+/// A `Realtime` clock set before 1970 can't be represented, and fails with `EOVERFLOW`.
+#[inline]
+pub fn clock_gettime(clock: ClockId) -> io::Result<Duration> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::ClockGettime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::ClockGettime;
+
+    let mut time: MaybeUninit<__kernel_timespec> = MaybeUninit::uninit();
+    let res = match vdso::functions().clock_gettime {
+        Some(clock_gettime) => unsafe { clock_gettime(clock.raw(), time.as_mut_ptr()) as isize },
+        None => unsafe { syscall!(nr, clock.raw() as isize, time.as_mut_ptr() as isize) },
+    };
+    result_none!(res)?;
+    duration_from_timespec(unsafe { time.assume_init() })
+}
+
+/// Returns the seconds since the Unix epoch, the resolution of `time(2)` is a tick.
+#[inline]
+pub fn time() -> io::Result<u64> {
+    // i686 has no 64 bit `time(2)`.
+    #[cfg(target_arch = "x86")]
+    let res = clock_gettime(ClockId::RealtimeCoarse)?.as_secs() as isize;
+    #[cfg(not(target_arch = "x86"))]
+    let res = match vdso::functions().time {
+        Some(time) => unsafe { time(std::ptr::null_mut()) },
+        None => unsafe { syscall!(Syscalls::Time, 0) },
+    };
+    result!(res)
+}
+
+/// Returns the resolution of `clock`.
+#[inline]
+pub fn clock_getres(clock: ClockId) -> io::Result<Duration> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::ClockGetresTime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::ClockGetres;
+
+    let mut res_time: MaybeUninit<__kernel_timespec> = MaybeUninit::uninit();
+    let res = unsafe { syscall!(nr, clock.raw() as isize, res_time.as_mut_ptr() as isize) };
+    result_none!(res)?;
+    duration_from_timespec(unsafe { res_time.assume_init() })
+}
+
+/// The state of an armed [`TimerFd`](../timerfd/struct.TimerFd.html) or [`PosixTimer`](../timer/struct.PosixTimer.html).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TimerState {
+    /// The time until the next expiration.
+    pub remaining: Duration,
+    /// The period of a periodic timer.
+    pub interval: Option<Duration>,
+}
+
+impl TimerState {
+    // A zero value means the timer is disarmed, and a zero interval that it's a one shot timer.
+    pub(crate) fn from_raw(spec: &__kernel_itimerspec) -> io::Result<Option<Self>> {
+        let remaining = duration_from_timespec(spec.it_value)?;
+        let interval = duration_from_timespec(spec.it_interval)?;
+        if remaining == Duration::ZERO {
+            return Ok(None);
+        }
+        Ok(Some(TimerState {
+            remaining,
+            interval: Some(interval).filter(|&interval| interval != Duration::ZERO),
+        }))
+    }
+}
+
+/// How long [`clock_nanosleep`](fn.clock_nanosleep.html) sleeps.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Sleep {
+    /// Sleep for a duration, measured by the clock.
+    For(Duration),
+    /// Sleep until the clock reaches this time (`TIMER_ABSTIME`), so restarting after `EINTR` doesn't extend the sleep.
+    Until(Duration),
+}
+
+/// Sleep on `clock`, which can't be a `ThreadCputime` clock.
+/// If a signal handler interrupts the sleep this fails with `EINTR` (`io::ErrorKind::Interrupted`),
+/// and for relative sleeps the time that was left is written to `remaining`.
+#[inline]
+pub fn clock_nanosleep(
+    clock: ClockId,
+    sleep: Sleep,
+    remaining: Option<&mut Duration>,
+) -> io::Result<()> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::ClockNanosleepTime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::ClockNanosleep;
+
+    let (flags, time) = match sleep {
+        Sleep::For(time) => (0, time),
+        Sleep::Until(time) => (TIMER_ABSTIME, time),
+    };
+    let time = timespec_from_duration(time);
+    let mut rem: MaybeUninit<__kernel_timespec> = MaybeUninit::uninit();
+    let res = unsafe {
+        syscall!(
+            nr,
+            clock.raw() as isize,
+            flags as isize,
+            &time as *const __kernel_timespec as isize,
+            rem.as_mut_ptr() as isize,
+        )
+    };
+    // The libc function returns the errno instead of setting it, the syscall returns `-errno` as usual.
+    let res = result_none!(res);
+    if let (Err(e), Some(remaining), 0) = (&res, remaining, flags) {
+        if e.raw_os_error() == Some(EINTR as i32) {
+            *remaining = duration_from_timespec(unsafe { rem.assume_init() })?;
+        }
+    }
+    res
+}
+
+/// Sleep for `time` on the `Realtime` clock, see [`clock_nanosleep`](fn.clock_nanosleep.html) for `remaining`.
+#[inline]
+pub fn nanosleep(time: Duration, remaining: Option<&mut Duration>) -> io::Result<()> {
+    // On i686 `nanosleep` only takes a 32 bit `timespec`, this is what it does anyway.
+    #[cfg(target_arch = "x86")]
+    {
+        clock_nanosleep(ClockId::Realtime, Sleep::For(time), remaining)
+    }
+    #[cfg(not(target_arch = "x86"))]
+    {
+        let time = timespec_from_duration(time);
+        let mut rem: MaybeUninit<__kernel_timespec> = MaybeUninit::uninit();
+        let res = unsafe {
+            syscall!(
+                Syscalls::Nanosleep,
+                &time as *const __kernel_timespec as isize,
+                rem.as_mut_ptr() as isize,
+            )
+        };
+        let res = result_none!(res);
+        if let (Err(e), Some(remaining)) = (&res, remaining) {
+            if e.raw_os_error() == Some(EINTR as i32) {
+                *remaining = duration_from_timespec(unsafe { rem.assume_init() })?;
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+    use crate::{getpid, gettid};
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_clock_gettime() {
+        let sys_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let now = clock_gettime(ClockId::Realtime).unwrap();
+        assert!(now >= sys_time && now - sys_time < Duration::from_secs(1));
+
+        let secs = time().unwrap();
+        assert!(secs >= sys_time.as_secs() && secs - sys_time.as_secs() <= 1);
+
+        let first = clock_gettime(ClockId::Monotonic).unwrap();
+        let second = clock_gettime(ClockId::Monotonic).unwrap();
+        assert!(second >= first);
+        assert!(clock_gettime(ClockId::Boottime).unwrap() >= first);
+
+        for clock in [
+            ClockId::RealtimeCoarse,
+            ClockId::MonotonicCoarse,
+            ClockId::MonotonicRaw,
+            ClockId::Tai,
+            ClockId::ProcessCputime,
+            ClockId::ThreadCputime,
+            ClockId::ProcessCputimeOf(getpid()),
+            ClockId::ThreadCputimeOf(gettid()),
+        ] {
+            clock_gettime(clock).unwrap();
+            assert!(clock_getres(clock).unwrap() > Duration::ZERO);
+        }
+        assert!(clock_getres(ClockId::Monotonic).unwrap() <= Duration::from_millis(1));
+
+        let err = clock_gettime(ClockId::Fd(9999)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = clock_gettime(ClockId::ProcessCputimeOf(5_000_000)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_nanosleep() {
+        let sleep = Duration::from_millis(10);
+        let start = Instant::now();
+        nanosleep(sleep, None).unwrap();
+        assert!(start.elapsed() >= sleep);
+
+        let start = Instant::now();
+        clock_nanosleep(ClockId::Monotonic, Sleep::For(sleep), None).unwrap();
+        assert!(start.elapsed() >= sleep);
+
+        let deadline = clock_gettime(ClockId::Monotonic).unwrap() + sleep;
+        clock_nanosleep(ClockId::Monotonic, Sleep::Until(deadline), None).unwrap();
+        assert!(clock_gettime(ClockId::Monotonic).unwrap() >= deadline);
+
+        // `EINVAL` on older kernels, `EOPNOTSUPP` on newer ones.
+        assert!(clock_nanosleep(ClockId::ThreadCputime, Sleep::For(sleep), None).is_err());
+    }
+
+    #[test]
+    fn test_nanosleep_interrupted() {
+        extern "C" fn handler(_: i32) {}
+        let sig = linux_sys::SIGUSR2;
+        let action = SigAction::new(
+            SigHandler::Handler(handler),
+            SigSet::empty(),
+            SaFlags::new(),
+        );
+        unsafe { sigaction(sig, &action) }.unwrap();
+
+        // The signal arrives while the child sleeps.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let mut remaining = Duration::ZERO;
+            let res = nanosleep(Duration::from_secs(10), Some(&mut remaining));
+            let ok = matches!(res, Err(ref e) if e.kind() == io::ErrorKind::Interrupted)
+                && remaining > Duration::ZERO
+                && remaining < Duration::from_secs(10);
+            crate::_exit(!ok as i32);
+        }
+        nanosleep(Duration::from_millis(50), None).unwrap();
+        assert_eq!(unsafe { libc::kill(pid, sig as i32) }, 0);
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
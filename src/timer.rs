@@ -0,0 +1,298 @@
+//! POSIX per-process timers, see `timer_create(2)`.
+use crate::arch::Syscalls;
+use crate::time::{ClockId, TimerState};
+use crate::utils::itimerspec;
+use crate::{result, result_none, static_assert, syscall};
+use std::io;
+use std::mem::{size_of, MaybeUninit};
+use std::time::Duration;
+
+use linux_sys::{
+    __kernel_itimerspec, SIGEV_MAX_SIZE, SIGEV_NONE, SIGEV_SIGNAL, SIGEV_THREAD_ID, TIMER_ABSTIME,
+};
+
+/// How a timer notifies about its expirations.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Notify {
+    /// Don't notify, the timer can only be polled with [`timer_gettime`](fn.timer_gettime.html).
+    None,
+    /// Send `signo` to the process, with `value` in its [`SigInfo`](../signal/struct.SigInfo.html#method.value).
+    Signal { signo: u32, value: usize },
+    /// Send `signo` to thread `tid`, which has to be in the calling process.
+    ThreadSignal { signo: u32, value: usize, tid: u32 },
+}
+
+// `struct sigevent`, the union after `sigev_notify` is padded to `SIGEV_MAX_SIZE`.
+// Only its first member, the thread ID of `SIGEV_THREAD_ID`, is used by the kernel.
+#[repr(C)]
+struct SigEvent {
+    value: usize,
+    signo: i32,
+    notify: i32,
+    tid: i32,
+    pad: [i32; SIGEV_PAD_SIZE],
+}
+
+const SIGEV_PAD_SIZE: usize = (SIGEV_MAX_SIZE as usize - size_of::<usize>()) / size_of::<i32>() - 3;
+
+static_assert!(size_of::<SigEvent>() == SIGEV_MAX_SIZE as usize);
+
+impl From<Notify> for SigEvent {
+    fn from(notify: Notify) -> Self {
+        let (notify, signo, value, tid) = match notify {
+            Notify::None => (SIGEV_NONE, 0, 0, 0),
+            Notify::Signal { signo, value } => (SIGEV_SIGNAL, signo, value, 0),
+            Notify::ThreadSignal { signo, value, tid } => (SIGEV_THREAD_ID, signo, value, tid),
+        };
+        SigEvent {
+            value,
+            signo: signo as i32,
+            notify: notify as i32,
+            tid: tid as i32,
+            pad: [0; SIGEV_PAD_SIZE],
+        }
+    }
+}
+
+/// How [`timer_settime`](fn.timer_settime.html) interprets the expiration time.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct TimerFlags(isize);
+
+impl TimerFlags {
+    /// Creates new `TimerFlags`, the expiration time is relative to now.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The expiration time is an absolute time of the timer's clock.
+    pub fn abstime(mut self) -> Self {
+        self.0 |= TIMER_ABSTIME as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for TimerFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TimerFlags")
+            .field("ABSTIME", &(self.0 & TIMER_ABSTIME as isize > 0))
+            .finish()
+    }
+}
+
+/// Create a new disarmed timer on `clock`, and return its ID.
+/// `None` sends `SIGALRM` to the process with the timer ID as the value.
+#[inline]
+pub unsafe fn timer_create(clock: ClockId, notify: Option<Notify>) -> io::Result<i32> {
+    let event = notify.map(SigEvent::from);
+    let mut id = 0i32;
+    let res = syscall!(
+        Syscalls::TimerCreate,
+        clock.raw() as isize,
+        event
+            .as_ref()
+            .map(|event| event as *const SigEvent)
+            .unwrap_or(std::ptr::null()) as isize,
+        &mut id as *mut i32 as isize,
+    );
+    result_none!(res)?;
+    Ok(id)
+}
+
+/// Arm (or disarm if `new.it_value` is zero) the timer `id`, and return its previous setting.
+#[inline]
+pub unsafe fn timer_settime(
+    id: i32,
+    flags: TimerFlags,
+    new: &__kernel_itimerspec,
+) -> io::Result<__kernel_itimerspec> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::TimerSettime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::TimerSettime;
+
+    let mut old: MaybeUninit<__kernel_itimerspec> = MaybeUninit::uninit();
+    let res = syscall!(
+        nr,
+        id as isize,
+        flags.0,
+        new as *const __kernel_itimerspec as isize,
+        old.as_mut_ptr() as isize,
+    );
+    result_none!(res)?;
+    Ok(old.assume_init())
+}
+
+/// Returns the time until the next expiration of the timer `id` and its interval.
+#[inline]
+pub unsafe fn timer_gettime(id: i32) -> io::Result<__kernel_itimerspec> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::TimerGettime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::TimerGettime;
+
+    let mut curr: MaybeUninit<__kernel_itimerspec> = MaybeUninit::uninit();
+    let res = syscall!(nr, id as isize, curr.as_mut_ptr() as isize);
+    result_none!(res)?;
+    Ok(curr.assume_init())
+}
+
+/// Returns the number of expirations that didn't send a signal, because the previous one was still pending.
+/// This counts until the last signal of the timer was delivered.
+#[inline]
+pub unsafe fn timer_getoverrun(id: i32) -> io::Result<u32> {
+    let res = syscall!(Syscalls::TimerGetoverrun, id as isize);
+    result!(res)
+}
+
+/// Delete the timer `id`, a pending signal of it stays pending.
+#[inline]
+pub unsafe fn timer_delete(id: i32) -> io::Result<()> {
+    let res = syscall!(Syscalls::TimerDelete, id as isize);
+    result_none!(res)
+}
+
+/// A POSIX timer, deleted on drop.
+#[derive(Debug)]
+pub struct PosixTimer {
+    id: i32,
+    clock: ClockId,
+}
+
+impl PosixTimer {
+    /// Create a new disarmed timer on `clock`, which can be any clock including CPU time ones.
+    pub fn new(clock: ClockId, notify: Notify) -> io::Result<Self> {
+        let id = unsafe { timer_create(clock, Some(notify)) }?;
+        Ok(PosixTimer { id, clock })
+    }
+
+    /// The timer ID, which is in the [`SigInfo::timer`](../signal/struct.SigInfo.html#method.timer) of its signals.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn clock(&self) -> ClockId {
+        self.clock
+    }
+
+    fn settime(
+        &mut self,
+        flags: TimerFlags,
+        value: Duration,
+        interval: Option<Duration>,
+    ) -> io::Result<Option<TimerState>> {
+        let old = unsafe { timer_settime(self.id, flags, &itimerspec(value, interval)) }?;
+        TimerState::from_raw(&old)
+    }
+
+    /// Expire after `value`, and then every `interval`. Returns the previous state.
+    /// A zero `value` disarms the timer.
+    pub fn set(
+        &mut self,
+        value: Duration,
+        interval: Option<Duration>,
+    ) -> io::Result<Option<TimerState>> {
+        self.settime(TimerFlags::new(), value, interval)
+    }
+
+    /// Expire when the timer's clock reaches `time`, and then every `interval`. Returns the previous state.
+    pub fn set_abs(
+        &mut self,
+        time: Duration,
+        interval: Option<Duration>,
+    ) -> io::Result<Option<TimerState>> {
+        self.settime(TimerFlags::new().abstime(), time, interval)
+    }
+
+    /// Disarm the timer. Returns the previous state.
+    pub fn disarm(&mut self) -> io::Result<Option<TimerState>> {
+        self.settime(TimerFlags::new(), Duration::ZERO, None)
+    }
+
+    /// Returns the state of the timer, `None` if it's disarmed.
+    pub fn get(&self) -> io::Result<Option<TimerState>> {
+        let curr = unsafe { timer_gettime(self.id) }?;
+        TimerState::from_raw(&curr)
+    }
+
+    /// Returns the overrun count of the last delivered signal, see [`timer_getoverrun`](fn.timer_getoverrun.html).
+    pub fn overrun(&self) -> io::Result<u32> {
+        unsafe { timer_getoverrun(self.id) }
+    }
+}
+
+impl Drop for PosixTimer {
+    fn drop(&mut self) {
+        unsafe {
+            timer_delete(self.id).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gettid;
+    use crate::signal::{rt_sigprocmask, rt_sigtimedwait, SigSet, SigmaskHow};
+    use crate::time::clock_gettime;
+    use linux_sys::SIGRTMIN;
+
+    const TICK: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn test_timer_none() {
+        let mut timer = PosixTimer::new(ClockId::Monotonic, Notify::None).unwrap();
+        assert_eq!(timer.get().unwrap(), None);
+        assert_eq!(timer.set(TICK * 50, None).unwrap(), None);
+        let state = timer.get().unwrap().unwrap();
+        assert!(state.remaining <= TICK * 50 && state.interval.is_none());
+
+        let deadline = clock_gettime(ClockId::Monotonic).unwrap() + TICK;
+        let old = timer.set_abs(deadline, Some(TICK)).unwrap().unwrap();
+        assert!(old.remaining > TICK);
+        std::thread::sleep(TICK * 2);
+        assert_eq!(timer.get().unwrap().unwrap().interval, Some(TICK));
+        assert!(timer.disarm().unwrap().is_some());
+        assert_eq!(timer.overrun().unwrap(), 0);
+
+        drop(timer);
+        let err = unsafe { timer_delete(i32::MAX) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_timer_process_cputime() {
+        const SIGNAL: u32 = SIGRTMIN + 6;
+        let mut set = SigSet::empty();
+        set.insert(SIGNAL);
+        // Only this thread blocks the signal, so it has to be directed at it.
+        let old = unsafe { rt_sigprocmask(SigmaskHow::Block, Some(&set)) }.unwrap();
+        let notify = Notify::ThreadSignal {
+            signo: SIGNAL,
+            value: 42,
+            tid: gettid(),
+        };
+        let mut timer = PosixTimer::new(ClockId::ProcessCputime, notify).unwrap();
+        timer.set(Duration::from_millis(5), None).unwrap();
+
+        // Burn CPU time until the signal is pending.
+        let start = clock_gettime(ClockId::ProcessCputime).unwrap();
+        let info = loop {
+            match unsafe { rt_sigtimedwait(&set, Some(Duration::ZERO)) } {
+                Ok(info) => break info,
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+            }
+            let spent = clock_gettime(ClockId::ProcessCputime).unwrap() - start;
+            assert!(spent < Duration::from_secs(5));
+        };
+        assert!(
+            clock_gettime(ClockId::ProcessCputime).unwrap() - start >= Duration::from_millis(5)
+        );
+        assert_eq!(info.signo(), SIGNAL);
+        assert_eq!(info.value(), Some(42));
+        assert_eq!(info.timer().map(|(id, _)| id), Some(timer.id()));
+        assert_eq!(timer.get().unwrap(), None);
+
+        drop(timer);
+        unsafe { rt_sigprocmask(SigmaskHow::SetMask, Some(&old)) }.unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+//! Timers that notify through a file descriptor, see `timerfd_create(2)`.
+use crate::arch::Syscalls;
+use crate::time::{clock_gettime, ClockId, TimerState};
+use crate::utils::itimerspec;
+use crate::{close, read, result, result_none, syscall};
+use std::io;
+use std::mem::{size_of, MaybeUninit};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use linux_sys::{
+    __kernel_itimerspec, TFD_CLOEXEC, TFD_NONBLOCK, TFD_TIMER_ABSTIME, TFD_TIMER_CANCEL_ON_SET,
+};
+
+/// Additional options for [`timerfd_create`](fn.timerfd_create.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct TfdFlags(isize);
+
+impl TfdFlags {
+    /// Creates new `TfdFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set non-blocking mode on the new descriptor.
+    pub fn nonblock(mut self) -> Self {
+        self.0 |= TFD_NONBLOCK as isize;
+        self
+    }
+
+    /// Set close-on-exec on the new descriptor.
+    pub fn cloexec(mut self) -> Self {
+        self.0 |= TFD_CLOEXEC as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for TfdFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TfdFlags")
+            .field("NONBLOCK", &(self.0 & TFD_NONBLOCK as isize > 0))
+            .field("CLOEXEC", &(self.0 & TFD_CLOEXEC as isize > 0))
+            .finish()
+    }
+}
+
+/// How [`timerfd_settime`](fn.timerfd_settime.html) interprets the expiration time.
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct SettimeFlags(isize);
+
+impl SettimeFlags {
+    /// Creates new `SettimeFlags`, the expiration time is relative to now.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The expiration time is an absolute time of the timer's clock.
+    pub fn abstime(mut self) -> Self {
+        self.0 |= TFD_TIMER_ABSTIME as isize;
+        self
+    }
+
+    /// With `abstime` on a `Realtime` clock, reads fail with `ECANCELED` when the clock is set (Linux 2.6.32+).
+    pub fn cancel_on_set(mut self) -> Self {
+        self.0 |= TFD_TIMER_CANCEL_ON_SET as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for SettimeFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SettimeFlags")
+            .field("ABSTIME", &(self.0 & TFD_TIMER_ABSTIME as isize > 0))
+            .field(
+                "CANCEL_ON_SET",
+                &(self.0 & TFD_TIMER_CANCEL_ON_SET as isize > 0),
+            )
+            .finish()
+    }
+}
+
+/// Create a new disarmed timer on `clock`, which can only be a `Realtime`, `Monotonic` or `Boottime` clock (or an alarm one).
+#[inline]
+pub unsafe fn timerfd_create(clock: ClockId, flags: TfdFlags) -> io::Result<usize> {
+    let res = syscall!(Syscalls::TimerfdCreate, clock.raw() as isize, flags.0);
+    result!(res)
+}
+
+/// Arm (or disarm if `new.it_value` is zero) the timer `fd`, and return its previous setting.
+#[inline]
+pub unsafe fn timerfd_settime<F: AsRawFd>(
+    fd: &F,
+    flags: SettimeFlags,
+    new: &__kernel_itimerspec,
+) -> io::Result<__kernel_itimerspec> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::TimerfdSettime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::TimerfdSettime;
+
+    let mut old: MaybeUninit<__kernel_itimerspec> = MaybeUninit::uninit();
+    let res = syscall!(
+        nr,
+        fd.as_raw_fd() as isize,
+        flags.0,
+        new as *const __kernel_itimerspec as isize,
+        old.as_mut_ptr() as isize,
+    );
+    result_none!(res)?;
+    Ok(old.assume_init())
+}
+
+/// Returns the time until the next expiration of the timer `fd` (relative, even if it was armed with an absolute time)
+/// and its interval.
+#[inline]
+pub unsafe fn timerfd_gettime<F: AsRawFd>(fd: &F) -> io::Result<__kernel_itimerspec> {
+    #[cfg(target_arch = "x86")]
+    let nr = Syscalls::TimerfdGettime64;
+    #[cfg(not(target_arch = "x86"))]
+    let nr = Syscalls::TimerfdGettime;
+
+    let mut curr: MaybeUninit<__kernel_itimerspec> = MaybeUninit::uninit();
+    let res = syscall!(nr, fd.as_raw_fd() as isize, curr.as_mut_ptr() as isize);
+    result_none!(res)?;
+    Ok(curr.assume_init())
+}
+
+/// A timer file descriptor, which is readable when the timer expired.
+/// The descriptor is closed on drop.
+#[derive(Debug)]
+pub struct TimerFd {
+    fd: RawFd,
+    clock: ClockId,
+}
+
+impl TimerFd {
+    /// Create a new disarmed timer on `clock`.
+    pub fn new(clock: ClockId, flags: TfdFlags) -> io::Result<Self> {
+        let fd = unsafe { timerfd_create(clock, flags) }?;
+        Ok(TimerFd {
+            fd: fd as RawFd,
+            clock,
+        })
+    }
+
+    pub fn clock(&self) -> ClockId {
+        self.clock
+    }
+
+    fn settime(
+        &mut self,
+        flags: SettimeFlags,
+        value: Duration,
+        interval: Option<Duration>,
+    ) -> io::Result<Option<TimerState>> {
+        let old = unsafe { timerfd_settime(&self.fd, flags, &itimerspec(value, interval)) }?;
+        TimerState::from_raw(&old)
+    }
+
+    /// Expire after `value`, and then every `interval`. Returns the previous state.
+    /// A zero `value` disarms the timer.
+    pub fn set(
+        &mut self,
+        value: Duration,
+        interval: Option<Duration>,
+    ) -> io::Result<Option<TimerState>> {
+        self.settime(SettimeFlags::new(), value, interval)
+    }
+
+    /// Expire when the timer's clock reaches `time`, and then every `interval`. Returns the previous state.
+    /// `flags` is always `abstime`, and can be `cancel_on_set`.
+    pub fn set_abs(
+        &mut self,
+        time: Duration,
+        interval: Option<Duration>,
+        flags: SettimeFlags,
+    ) -> io::Result<Option<TimerState>> {
+        self.settime(flags.abstime(), time, interval)
+    }
+
+    /// Expire at `deadline`, and then every `interval`. Returns the previous state.
+    /// A `deadline` in the past expires immediately.
+    ///
+    /// `Instant`s are `Monotonic` times, on other clocks this is converted once and so doesn't follow changes of the clock.
+    pub fn set_deadline(
+        &mut self,
+        deadline: Instant,
+        interval: Option<Duration>,
+    ) -> io::Result<Option<TimerState>> {
+        let now = clock_gettime(self.clock)?;
+        let time = now + deadline.saturating_duration_since(Instant::now());
+        self.set_abs(time, interval, SettimeFlags::new())
+    }
+
+    /// Disarm the timer. Returns the previous state.
+    pub fn disarm(&mut self) -> io::Result<Option<TimerState>> {
+        self.settime(SettimeFlags::new(), Duration::ZERO, None)
+    }
+
+    /// Returns the state of the timer, `None` if it's disarmed.
+    pub fn get(&self) -> io::Result<Option<TimerState>> {
+        let curr = unsafe { timerfd_gettime(&self.fd) }?;
+        TimerState::from_raw(&curr)
+    }
+
+    /// Wait for the timer to expire, and return the number of expirations since it was set or last read.
+    /// Returns `None` if the descriptor is non-blocking and the timer didn't expire,
+    /// and fails with `ECANCELED` if the timer was set with `cancel_on_set` and the clock was set.
+    pub fn read(&mut self) -> io::Result<Option<u64>> {
+        let mut buf = [0u8; size_of::<u64>()];
+        match unsafe { read(&self.fd, &mut buf) } {
+            Ok(len) => {
+                debug_assert_eq!(len, buf.len());
+                Ok(Some(u64::from_ne_bytes(buf)))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for TimerFd {
+    fn drop(&mut self) {
+        unsafe {
+            close(&self.fd).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn test_timerfd_oneshot() {
+        let mut timer = TimerFd::new(ClockId::Monotonic, TfdFlags::new().cloexec()).unwrap();
+        assert_eq!(timer.clock(), ClockId::Monotonic);
+        assert_eq!(timer.get().unwrap(), None);
+
+        let start = Instant::now();
+        assert_eq!(timer.set(TICK, None).unwrap(), None);
+        let state = timer.get().unwrap().unwrap();
+        assert!(state.remaining <= TICK && state.interval.is_none());
+        assert_eq!(timer.read().unwrap(), Some(1));
+        assert!(start.elapsed() >= TICK);
+        assert_eq!(timer.get().unwrap(), None);
+
+        let start = Instant::now();
+        timer.set_deadline(start + TICK, None).unwrap();
+        assert_eq!(timer.read().unwrap(), Some(1));
+        assert!(start.elapsed() >= TICK);
+    }
+
+    #[test]
+    fn test_timerfd_periodic() {
+        let mut timer = TimerFd::new(ClockId::Boottime, TfdFlags::new().nonblock()).unwrap();
+        assert_eq!(timer.read().unwrap(), None);
+        timer.set(TICK, Some(TICK)).unwrap();
+        std::thread::sleep(TICK * 3 + TICK / 2);
+        assert!(timer.read().unwrap().unwrap() >= 3);
+        assert_eq!(timer.get().unwrap().unwrap().interval, Some(TICK));
+        let old = timer.disarm().unwrap().unwrap();
+        assert_eq!(old.interval, Some(TICK));
+        assert_eq!(timer.read().unwrap(), None);
+    }
+
+    #[test]
+    fn test_timerfd_abstime() {
+        let mut timer = TimerFd::new(ClockId::Realtime, TfdFlags::new()).unwrap();
+        let flags = SettimeFlags::new().cancel_on_set();
+        let deadline = clock_gettime(ClockId::Realtime).unwrap() + TICK;
+        timer.set_abs(deadline, None, flags).unwrap();
+        assert_eq!(timer.read().unwrap(), Some(1));
+        assert!(clock_gettime(ClockId::Realtime).unwrap() >= deadline);
+
+        // Process CPU time clocks aren't supported.
+        let err = TimerFd::new(ClockId::ProcessCputime, TfdFlags::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,493 @@
+//! Handling page faults in userspace, see `userfaultfd(2)`.
+//!
+//! Accessing a registered range that isn't populated blocks the faulting thread and queues an event on the
+//! [`UserfaultFd`](struct.UserfaultFd.html), which is resolved by filling the page with [`copy`](struct.UserfaultFd.html#method.copy)
+//! or [`zeropage`](struct.UserfaultFd.html#method.zeropage). So the faults have to be handled by another thread (or process).
+use crate::arch::Syscalls;
+use crate::utils::{ior, iowr};
+use crate::{close, read, result, syscall};
+use std::io;
+use std::mem::{size_of, MaybeUninit};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::slice;
+
+use linux_sys::{
+    uffdio_api, uffdio_copy, uffdio_range, uffdio_register, uffdio_writeprotect, uffdio_zeropage,
+    _UFFDIO_API, _UFFDIO_COPY, _UFFDIO_REGISTER, _UFFDIO_UNREGISTER, _UFFDIO_WAKE,
+    _UFFDIO_WRITEPROTECT, _UFFDIO_ZEROPAGE, O_CLOEXEC, O_NONBLOCK, UFFDIO, UFFD_EVENT_FORK,
+    UFFD_EVENT_PAGEFAULT, UFFD_EVENT_REMAP, UFFD_EVENT_REMOVE, UFFD_EVENT_UNMAP,
+    UFFD_PAGEFAULT_FLAG_MINOR, UFFD_PAGEFAULT_FLAG_WP, UFFD_PAGEFAULT_FLAG_WRITE,
+    UFFD_USER_MODE_ONLY,
+};
+
+// `linux/userfaultfd.h` casts these to `__u64`, which bindgen can't evaluate.
+const UFFD_API: u64 = 0xaa;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1 << 0;
+const UFFDIO_REGISTER_MODE_WP: u64 = 1 << 1;
+const UFFDIO_REGISTER_MODE_MINOR: u64 = 1 << 2;
+const UFFDIO_COPY_MODE_DONTWAKE: u64 = 1 << 0;
+const UFFDIO_COPY_MODE_WP: u64 = 1 << 1;
+const UFFDIO_ZEROPAGE_MODE_DONTWAKE: u64 = 1 << 0;
+const UFFDIO_WRITEPROTECT_MODE_WP: u64 = 1 << 0;
+const UFFDIO_WRITEPROTECT_MODE_DONTWAKE: u64 = 1 << 1;
+
+const UFFDIO_API: u32 = iowr(UFFDIO, _UFFDIO_API, size_of::<uffdio_api>());
+const UFFDIO_REGISTER: u32 = iowr(UFFDIO, _UFFDIO_REGISTER, size_of::<uffdio_register>());
+const UFFDIO_UNREGISTER: u32 = ior(UFFDIO, _UFFDIO_UNREGISTER, size_of::<uffdio_range>());
+const UFFDIO_WAKE: u32 = ior(UFFDIO, _UFFDIO_WAKE, size_of::<uffdio_range>());
+const UFFDIO_COPY: u32 = iowr(UFFDIO, _UFFDIO_COPY, size_of::<uffdio_copy>());
+const UFFDIO_ZEROPAGE: u32 = iowr(UFFDIO, _UFFDIO_ZEROPAGE, size_of::<uffdio_zeropage>());
+const UFFDIO_WRITEPROTECT: u32 = iowr(
+    UFFDIO,
+    _UFFDIO_WRITEPROTECT,
+    size_of::<uffdio_writeprotect>(),
+);
+
+/// Options for [`userfaultfd`](fn.userfaultfd.html).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct UffdFlags(isize);
+
+impl UffdFlags {
+    /// Creates new `UffdFlags`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set close-on-exec on the new descriptor.
+    pub fn cloexec(mut self) -> Self {
+        self.0 |= O_CLOEXEC as isize;
+        self
+    }
+
+    /// Set non-blocking mode on the new descriptor.
+    pub fn nonblock(mut self) -> Self {
+        self.0 |= O_NONBLOCK as isize;
+        self
+    }
+
+    /// Only handle faults from userspace, this doesn't require privileges
+    /// even when `vm.unprivileged_userfaultfd` is 0 (Linux 5.11+).
+    pub fn user_mode_only(mut self) -> Self {
+        self.0 |= UFFD_USER_MODE_ONLY as isize;
+        self
+    }
+}
+
+impl core::fmt::Debug for UffdFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("UffdFlags")
+            .field("CLOEXEC", &(self.0 & O_CLOEXEC as isize > 0))
+            .field("NONBLOCK", &(self.0 & O_NONBLOCK as isize > 0))
+            .field(
+                "USER_MODE_ONLY",
+                &(self.0 & UFFD_USER_MODE_ONLY as isize > 0),
+            )
+            .finish()
+    }
+}
+
+/// Create a new userfaultfd object, the [`UFFDIO_API`](struct.UserfaultFd.html#method.new) handshake has to be done before using it.
+#[inline]
+pub unsafe fn userfaultfd(flags: UffdFlags) -> io::Result<usize> {
+    let res = syscall!(Syscalls::Userfaultfd, flags.0);
+    result!(res)
+}
+
+/// Which faults in a registered range are reported, see [`register`](struct.UserfaultFd.html#method.register).
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Default)]
+pub struct RegisterMode(u64);
+
+impl RegisterMode {
+    /// Creates new `RegisterMode`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Report accesses to missing pages.
+    pub fn missing(mut self) -> Self {
+        self.0 |= UFFDIO_REGISTER_MODE_MISSING;
+        self
+    }
+
+    /// Report writes to write protected pages, requires `UFFD_FEATURE_PAGEFAULT_FLAG_WP`.
+    pub fn write_protect(mut self) -> Self {
+        self.0 |= UFFDIO_REGISTER_MODE_WP;
+        self
+    }
+
+    /// Report accesses to pages that are in the page cache but not mapped yet, for shmem and hugetlbfs (Linux 5.13+).
+    pub fn minor(mut self) -> Self {
+        self.0 |= UFFDIO_REGISTER_MODE_MINOR;
+        self
+    }
+}
+
+impl core::fmt::Debug for RegisterMode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RegisterMode")
+            .field("MISSING", &(self.0 & UFFDIO_REGISTER_MODE_MISSING > 0))
+            .field("WP", &(self.0 & UFFDIO_REGISTER_MODE_WP > 0))
+            .field("MINOR", &(self.0 & UFFDIO_REGISTER_MODE_MINOR > 0))
+            .finish()
+    }
+}
+
+/// An event read from a [`UserfaultFd`](struct.UserfaultFd.html).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum UffdEvent {
+    /// A thread faulted on the page at `address`, and is blocked until the page is resolved.
+    /// The address isn't rounded down to the page with `UFFD_FEATURE_EXACT_ADDRESS` (Linux 5.18+).
+    PageFault {
+        address: u64,
+        /// It was a write access.
+        write: bool,
+        /// The page was write protected.
+        write_protect: bool,
+        /// The page was in the page cache but not mapped.
+        minor: bool,
+        /// The faulting thread, only with `UFFD_FEATURE_THREAD_ID`.
+        thread_id: u32,
+    },
+    /// The process forked, and this is the userfaultfd of the child (`UFFD_FEATURE_EVENT_FORK`).
+    Fork { uffd: RawFd },
+    /// A registered range was moved by `mremap(2)` (`UFFD_FEATURE_EVENT_REMAP`).
+    Remap { from: u64, to: u64, len: u64 },
+    /// Pages were freed by `madvise(2)` (`UFFD_FEATURE_EVENT_REMOVE`).
+    Remove { start: u64, end: u64 },
+    /// A registered range was unmapped (`UFFD_FEATURE_EVENT_UNMAP`).
+    Unmap { start: u64, end: u64 },
+    /// An event newer than this crate.
+    Unknown(u8),
+}
+
+// `struct uffd_msg`, the argument union is always 3 `u64`s.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    arg: [u64; 3],
+}
+
+impl From<&UffdMsg> for UffdEvent {
+    fn from(msg: &UffdMsg) -> Self {
+        let [a, b, c] = msg.arg;
+        match u32::from(msg.event) {
+            UFFD_EVENT_PAGEFAULT => UffdEvent::PageFault {
+                address: b,
+                write: a & UFFD_PAGEFAULT_FLAG_WRITE as u64 != 0,
+                write_protect: a & UFFD_PAGEFAULT_FLAG_WP as u64 != 0,
+                minor: a & UFFD_PAGEFAULT_FLAG_MINOR as u64 != 0,
+                // The first field of a union, so the low bits on a little endian machine.
+                thread_id: c as u32,
+            },
+            UFFD_EVENT_FORK => UffdEvent::Fork { uffd: a as RawFd },
+            UFFD_EVENT_REMAP => UffdEvent::Remap {
+                from: a,
+                to: b,
+                len: c,
+            },
+            UFFD_EVENT_REMOVE => UffdEvent::Remove { start: a, end: b },
+            UFFD_EVENT_UNMAP => UffdEvent::Unmap { start: a, end: b },
+            _ => UffdEvent::Unknown(msg.event),
+        }
+    }
+}
+
+fn range(addr: *mut u8, len: usize) -> uffdio_range {
+    uffdio_range {
+        start: addr as u64,
+        len: len as u64,
+    }
+}
+
+/// A userfaultfd object, closed on drop.
+#[derive(Debug)]
+pub struct UserfaultFd {
+    fd: RawFd,
+    features: u64,
+    ioctls: u64,
+}
+
+impl UserfaultFd {
+    /// Create a userfaultfd and do the `UFFDIO_API` handshake, asking for the `UFFD_FEATURE_*` bits in `features`.
+    /// Fails with `EINVAL` if the kernel doesn't support any of them.
+    pub fn new(flags: UffdFlags, features: u64) -> io::Result<Self> {
+        let fd = unsafe { userfaultfd(flags) }? as RawFd;
+        let mut uffd = UserfaultFd {
+            fd,
+            features: 0,
+            ioctls: 0,
+        };
+        let mut api = uffdio_api {
+            api: UFFD_API,
+            features,
+            ioctls: 0,
+        };
+        unsafe { uffd.ioctl(UFFDIO_API, &mut api) }?;
+        uffd.features = api.features;
+        uffd.ioctls = api.ioctls;
+        Ok(uffd)
+    }
+
+    /// Every `UFFD_FEATURE_*` the kernel supports.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// A bitmask of the ioctls supported on this descriptor, `1 << _UFFDIO_*`.
+    pub fn ioctls(&self) -> u64 {
+        self.ioctls
+    }
+
+    unsafe fn ioctl<T>(&self, request: u32, arg: &mut T) -> io::Result<()> {
+        let res = syscall!(
+            Syscalls::Ioctl,
+            self.fd as isize,
+            request as isize,
+            arg as *mut T as isize,
+        );
+        result!(res).map(|_: usize| ())
+    }
+
+    /// Report the faults in `addr..addr + len` (page aligned) to this descriptor,
+    /// returns a bitmask of the ioctls supported on the range.
+    pub unsafe fn register(
+        &self,
+        addr: *mut u8,
+        len: usize,
+        mode: RegisterMode,
+    ) -> io::Result<u64> {
+        let mut register = uffdio_register {
+            range: range(addr, len),
+            mode: mode.0,
+            ioctls: 0,
+        };
+        self.ioctl(UFFDIO_REGISTER, &mut register)?;
+        Ok(register.ioctls)
+    }
+
+    pub unsafe fn unregister(&self, addr: *mut u8, len: usize) -> io::Result<()> {
+        self.ioctl(UFFDIO_UNREGISTER, &mut range(addr, len))
+    }
+
+    /// Wake up the threads waiting on `addr..addr + len`, needed after resolving faults with `dontwake`.
+    pub unsafe fn wake(&self, addr: *mut u8, len: usize) -> io::Result<()> {
+        self.ioctl(UFFDIO_WAKE, &mut range(addr, len))
+    }
+
+    /// Atomically fill the missing pages at `dst` with `src` and wake the faulting threads unless `dontwake`,
+    /// `write_protect` maps them write protected. `dst` and the length of `src` must be page aligned.
+    /// Returns the amount of bytes copied, fails with `EEXIST` if a page is already mapped.
+    pub unsafe fn copy(
+        &self,
+        dst: *mut u8,
+        src: &[u8],
+        dontwake: bool,
+        write_protect: bool,
+    ) -> io::Result<usize> {
+        let mut mode = 0;
+        if dontwake {
+            mode |= UFFDIO_COPY_MODE_DONTWAKE;
+        }
+        if write_protect {
+            mode |= UFFDIO_COPY_MODE_WP;
+        }
+        let mut copy = uffdio_copy {
+            dst: dst as u64,
+            src: src.as_ptr() as u64,
+            len: src.len() as u64,
+            mode,
+            copy: 0,
+        };
+        self.ioctl(UFFDIO_COPY, &mut copy)?;
+        Ok(copy.copy as usize)
+    }
+
+    /// Map zero pages at `addr..addr + len`, like [`copy`](#method.copy) but without a source.
+    pub unsafe fn zeropage(&self, addr: *mut u8, len: usize, dontwake: bool) -> io::Result<usize> {
+        let mut zeropage = uffdio_zeropage {
+            range: range(addr, len),
+            mode: if dontwake {
+                UFFDIO_ZEROPAGE_MODE_DONTWAKE
+            } else {
+                0
+            },
+            zeropage: 0,
+        };
+        self.ioctl(UFFDIO_ZEROPAGE, &mut zeropage)?;
+        Ok(zeropage.zeropage as usize)
+    }
+
+    /// Write protect (or unprotect) `addr..addr + len`, the range must be registered with `RegisterMode::write_protect`.
+    /// Unprotecting wakes the threads that faulted on it unless `dontwake`.
+    pub unsafe fn writeprotect(
+        &self,
+        addr: *mut u8,
+        len: usize,
+        protect: bool,
+        dontwake: bool,
+    ) -> io::Result<()> {
+        let mut mode = 0;
+        if protect {
+            mode |= UFFDIO_WRITEPROTECT_MODE_WP;
+        }
+        if dontwake {
+            mode |= UFFDIO_WRITEPROTECT_MODE_DONTWAKE;
+        }
+        let mut writeprotect = uffdio_writeprotect {
+            range: range(addr, len),
+            mode,
+        };
+        self.ioctl(UFFDIO_WRITEPROTECT, &mut writeprotect)
+    }
+
+    /// Read the next event, blocking unless the descriptor is non-blocking.
+    /// Returns `None` if it's non-blocking and there are no events.
+    pub fn read_event(&self) -> io::Result<Option<UffdEvent>> {
+        let mut msg: MaybeUninit<UffdMsg> = MaybeUninit::uninit();
+        let buf =
+            unsafe { slice::from_raw_parts_mut(msg.as_mut_ptr() as *mut u8, size_of::<UffdMsg>()) };
+        match unsafe { read(&self.fd, buf) } {
+            Ok(len) => {
+                debug_assert_eq!(len, size_of::<UffdMsg>());
+                Ok(Some(UffdEvent::from(unsafe { &msg.assume_init() })))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsRawFd for UserfaultFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for UserfaultFd {
+    fn drop(&mut self) {
+        unsafe {
+            close(&self.fd).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::PAGE_SIZE;
+    use crate::mman::{Mapping, ProtFlags};
+    use linux_sys::{EINVAL, ENOSYS, EPERM, UFFD_FEATURE_PAGEFAULT_FLAG_WP};
+    use std::ptr;
+    use std::thread;
+
+    // userfaultfd can be compiled out (`ENOSYS`), limited to root by `vm.unprivileged_userfaultfd` (`EPERM`),
+    // and older than Linux 5.11 doesn't know `UFFD_USER_MODE_ONLY` or the features (`EINVAL`).
+    fn new_uffd(features: u64) -> Option<UserfaultFd> {
+        match UserfaultFd::new(UffdFlags::new().cloexec().user_mode_only(), features) {
+            Ok(uffd) => Some(uffd),
+            Err(e) => match e.raw_os_error().map(|errno| errno as u32) {
+                Some(EPERM) | Some(ENOSYS) | Some(EINVAL) => {
+                    eprintln!("Skipping userfaultfd test: {}", e);
+                    None
+                }
+                _ => panic!("userfaultfd failed: {}", e),
+            },
+        }
+    }
+
+    #[test]
+    fn test_uffd_missing() {
+        let uffd = match new_uffd(0) {
+            Some(uffd) => uffd,
+            None => return,
+        };
+        let map = Mapping::anonymous(PAGE_SIZE * 2, ProtFlags::new().read().write()).unwrap();
+        let base = map.as_ptr();
+        let ioctls =
+            unsafe { uffd.register(base, map.len(), RegisterMode::new().missing()) }.unwrap();
+        assert_ne!(ioctls & (1 << _UFFDIO_COPY), 0);
+
+        let addr = base as usize;
+        let reader = thread::spawn(move || unsafe {
+            let first = ptr::read_volatile((addr + 8) as *const u8);
+            let second = ptr::read_volatile((addr + PAGE_SIZE) as *const u8);
+            (first, second)
+        });
+
+        for _ in 0..2 {
+            let event = uffd.read_event().unwrap().unwrap();
+            let address = match event {
+                UffdEvent::PageFault {
+                    address,
+                    write: false,
+                    ..
+                } => address as usize,
+                e => panic!("Unexpected event: {:?}", e),
+            };
+            if address == addr {
+                let page = [42u8; PAGE_SIZE];
+                let res = unsafe { uffd.copy(base, &page, false, false) }.unwrap();
+                assert_eq!(res, PAGE_SIZE);
+            } else {
+                assert_eq!(address, addr + PAGE_SIZE);
+                let page = unsafe { base.add(PAGE_SIZE) };
+                let res = unsafe { uffd.zeropage(page, PAGE_SIZE, false) }.unwrap();
+                assert_eq!(res, PAGE_SIZE);
+            }
+        }
+        assert_eq!(reader.join().unwrap(), (42, 0));
+
+        let err = unsafe { uffd.zeropage(base, PAGE_SIZE, false) }.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        unsafe { uffd.unregister(base, map.len()) }.unwrap();
+    }
+
+    #[test]
+    fn test_uffd_write_protect() {
+        let uffd = match new_uffd(UFFD_FEATURE_PAGEFAULT_FLAG_WP as u64) {
+            Some(uffd) => uffd,
+            None => return,
+        };
+        let mut map = Mapping::anonymous(PAGE_SIZE, ProtFlags::new().read().write()).unwrap();
+        map.as_mut_slice().unwrap()[0] = 1;
+        let base = map.as_ptr();
+        unsafe { uffd.register(base, PAGE_SIZE, RegisterMode::new().write_protect()) }.unwrap();
+        unsafe { uffd.writeprotect(base, PAGE_SIZE, true, false) }.unwrap();
+
+        let addr = base as usize;
+        let writer = thread::spawn(move || unsafe { ptr::write_volatile(addr as *mut u8, 2) });
+        match uffd.read_event().unwrap().unwrap() {
+            UffdEvent::PageFault {
+                address,
+                write,
+                write_protect,
+                ..
+            } => {
+                assert_eq!(address as usize, addr);
+                assert!(write && write_protect);
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        // The page is still unchanged until we lift the protection.
+        assert_eq!(map.as_slice().unwrap()[0], 1);
+        unsafe { uffd.writeprotect(base, PAGE_SIZE, false, false) }.unwrap();
+        writer.join().unwrap();
+        assert_eq!(map.as_slice().unwrap()[0], 2);
+    }
+
+    #[test]
+    fn test_uffd_nonblock() {
+        let uffd = match UserfaultFd::new(UffdFlags::new().nonblock().user_mode_only(), 0) {
+            Ok(uffd) => uffd,
+            Err(_) => return,
+        };
+        assert_eq!(uffd.read_event().unwrap(), None);
+        let err = UserfaultFd::new(UffdFlags::new().user_mode_only(), 1 << 63).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
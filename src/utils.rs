@@ -1,3 +1,7 @@
+use linux_sys::{__kernel_itimerspec, __kernel_timespec, EOVERFLOW};
+use std::io;
+use std::time::Duration;
+
 #[macro_export]
 macro_rules! static_assert {
     ($test:expr) => {
@@ -27,3 +31,67 @@ macro_rules! result_none {
         result!($res).map(|r: usize| debug_assert_eq!(r, 0))
     };
 }
+
+// TODO: Should we have our own `Timespec` type? (Open question 7)
+pub(crate) fn timespec_from_duration(duration: Duration) -> __kernel_timespec {
+    __kernel_timespec {
+        tv_sec: duration.as_secs() as _,
+        tv_nsec: duration.subsec_nanos() as _,
+    }
+}
+
+// Millisecond timeouts of `poll(2)` and `epoll_wait(2)`, rounded up so short timeouts don't become busy loops.
+pub(crate) fn timeout_ms(timeout: Option<Duration>) -> i32 {
+    match timeout {
+        None => -1,
+        Some(timeout) => {
+            let ms = timeout.as_millis() + (timeout.subsec_nanos() % 1_000_000 != 0) as u128;
+            ms.min(i32::MAX as u128) as i32
+        }
+    }
+}
+
+// A zero `value` disarms the timer, a zero interval makes it a one shot timer.
+pub(crate) fn itimerspec(value: Duration, interval: Option<Duration>) -> __kernel_itimerspec {
+    __kernel_itimerspec {
+        it_interval: timespec_from_duration(interval.unwrap_or_default()),
+        it_value: timespec_from_duration(value),
+    }
+}
+
+// Negative times (e.g. a realtime clock before 1970) can't be a `Duration`.
+pub(crate) fn duration_from_timespec(time: __kernel_timespec) -> io::Result<Duration> {
+    if time.tv_sec < 0 {
+        return Err(io::Error::from_raw_os_error(EOVERFLOW as i32));
+    }
+    Ok(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+// Syscalls that return addresses (e.g. `mmap(2)`) can return values above `isize::MAX` on 32 bit,
+// only `-4095..0` are errors there. see `IS_ERR_VALUE` in the kernel.
+#[macro_export]
+macro_rules! result_ptr {
+    ($res:path) => {
+        if ($res as usize) > (-4096isize as usize) {
+            Err(::std::io::Error::from_raw_os_error(-$res as i32))
+        } else {
+            Ok($res as usize as *mut _)
+        }
+    };
+}
+
+// The `_IOC` encoding from `asm-generic/ioctl.h`, bindgen can't expand the function like macros.
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+pub(crate) const fn ioc(dir: u32, ty: u32, nr: u32, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | (ty << 8) | nr
+}
+
+pub(crate) const fn ior(ty: u32, nr: u32, size: usize) -> u32 {
+    ioc(IOC_READ, ty, nr, size)
+}
+
+pub(crate) const fn iowr(ty: u32, nr: u32, size: usize) -> u32 {
+    ioc(IOC_READ | IOC_WRITE, ty, nr, size)
+}
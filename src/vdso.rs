@@ -0,0 +1,317 @@
+//! The vDSO, a shared library the kernel maps into every process, see `vdso(7)`.
+//!
+//! It implements some syscalls in userspace (reading the time from a page the kernel keeps up to date),
+//! which is a lot faster than entering the kernel. This is a minimal ELF parser for it, based on the kernel's
+//! `tools/testing/selftests/vDSO/parse_vdso.c`.
+use std::convert::TryInto;
+use std::ffi::c_void;
+use std::fs;
+use std::mem::size_of;
+use std::os::raw::{c_int, c_ulong};
+use std::ptr;
+use std::slice;
+use std::sync::OnceLock;
+
+use linux_sys::{
+    AT_SYSINFO_EHDR, DT_HASH, DT_NULL, DT_STRTAB, DT_SYMTAB, DT_VERDEF, DT_VERSYM, EI_CLASS,
+    PT_DYNAMIC, PT_LOAD, SHN_UNDEF, STB_GLOBAL, STB_WEAK, STT_FUNC, STT_NOTYPE,
+};
+
+#[cfg(target_pointer_width = "32")]
+use linux_sys::{Elf32_Ehdr as Ehdr, Elf32_Phdr as Phdr, Elf32_Sym as Sym, ELFCLASS32 as ELFCLASS};
+#[cfg(target_pointer_width = "64")]
+use linux_sys::{Elf64_Ehdr as Ehdr, Elf64_Phdr as Phdr, Elf64_Sym as Sym, ELFCLASS64 as ELFCLASS};
+
+// `linux/elf.h` has `Elf*_Dyn` with a union, and doesn't have the symbol versioning structs at all.
+#[repr(C)]
+struct Dyn {
+    d_tag: isize,
+    d_val: usize,
+}
+
+#[repr(C)]
+struct Verdef {
+    vd_version: u16,
+    vd_flags: u16,
+    vd_ndx: u16,
+    vd_cnt: u16,
+    vd_hash: u32,
+    vd_aux: u32,
+    vd_next: u32,
+}
+
+#[repr(C)]
+struct Verdaux {
+    vda_name: u32,
+    vda_next: u32,
+}
+
+// The version definition of the file itself, not a real version.
+const VER_FLG_BASE: u16 = 1;
+
+/// A parsed vDSO image, see [`Vdso::get`](#method.get).
+#[derive(Debug)]
+pub struct Vdso {
+    // The difference between the addresses in the image and where it's mapped.
+    load_offset: usize,
+    symtab: *const Sym,
+    strtab: *const u8,
+    bucket: *const u32,
+    chain: *const u32,
+    nbucket: u32,
+    versym: *const u16,
+    verdef: *const Verdef,
+}
+
+// It only points into the vDSO, which is read only and never unmapped.
+unsafe impl Send for Vdso {}
+unsafe impl Sync for Vdso {}
+
+impl Vdso {
+    /// Returns the vDSO of the process, `None` if the kernel didn't map one (e.g. `vdso=0` on the command line).
+    pub fn get() -> Option<&'static Vdso> {
+        static VDSO: OnceLock<Option<Vdso>> = OnceLock::new();
+        VDSO.get_or_init(|| unsafe { Vdso::from_ptr(sysinfo_ehdr()? as *const u8) })
+            .as_ref()
+    }
+
+    /// Parse a vDSO image mapped at `base`, `None` if it isn't a valid image for this architecture.
+    pub unsafe fn from_ptr(base: *const u8) -> Option<Self> {
+        let ehdr = &*(base as *const Ehdr);
+        if ehdr.e_ident[..4] != *b"\x7fELF" || ehdr.e_ident[EI_CLASS as usize] != ELFCLASS as u8 {
+            return None;
+        }
+
+        let phdrs = slice::from_raw_parts(
+            base.add(ehdr.e_phoff as usize) as *const Phdr,
+            ehdr.e_phnum as usize,
+        );
+        let mut load_offset = None;
+        let mut dynamic = None;
+        for phdr in phdrs {
+            if phdr.p_type == PT_LOAD && load_offset.is_none() {
+                load_offset = Some(
+                    (base as usize + phdr.p_offset as usize).wrapping_sub(phdr.p_vaddr as usize),
+                );
+            } else if phdr.p_type == PT_DYNAMIC {
+                dynamic = Some(base.add(phdr.p_offset as usize) as *const Dyn);
+            }
+        }
+        let (load_offset, mut dynamic) = (load_offset?, dynamic?);
+
+        let mut vdso = Vdso {
+            load_offset,
+            symtab: ptr::null(),
+            strtab: ptr::null(),
+            bucket: ptr::null(),
+            chain: ptr::null(),
+            nbucket: 0,
+            versym: ptr::null(),
+            verdef: ptr::null(),
+        };
+        let mut hash: *const u32 = ptr::null();
+        while (*dynamic).d_tag != DT_NULL as isize {
+            let ptr = (*dynamic).d_val.wrapping_add(load_offset);
+            match (*dynamic).d_tag as u32 {
+                DT_STRTAB => vdso.strtab = ptr as *const u8,
+                DT_SYMTAB => vdso.symtab = ptr as *const Sym,
+                DT_HASH => hash = ptr as *const u32,
+                DT_VERSYM => vdso.versym = ptr as *const u16,
+                DT_VERDEF => vdso.verdef = ptr as *const Verdef,
+                _ => (),
+            }
+            dynamic = dynamic.add(1);
+        }
+        if vdso.strtab.is_null() || vdso.symtab.is_null() || hash.is_null() {
+            return None;
+        }
+        // Versions are only checked if both tables exist.
+        if vdso.verdef.is_null() {
+            vdso.versym = ptr::null();
+        }
+
+        // `nbucket`, `nchain`, the buckets and the chains.
+        vdso.nbucket = *hash;
+        vdso.bucket = hash.add(2);
+        vdso.chain = vdso.bucket.add(vdso.nbucket as usize);
+        if vdso.nbucket == 0 {
+            return None;
+        }
+        Some(vdso)
+    }
+
+    /// Returns the address of the function `name` with the symbol version `version`.
+    pub fn lookup(&self, version: &str, name: &str) -> Option<*const c_void> {
+        let ver_hash = elf_hash(version.as_bytes());
+        unsafe {
+            let mut index = *self
+                .bucket
+                .add((elf_hash(name.as_bytes()) % self.nbucket) as usize);
+            while index != 0 {
+                let sym = &*self.symtab.add(index as usize);
+                let (binding, kind) = (sym.st_info >> 4, sym.st_info & 0xf);
+                if (kind == STT_FUNC as u8 || kind == STT_NOTYPE as u8)
+                    && (binding == STB_GLOBAL as u8 || binding == STB_WEAK as u8)
+                    && sym.st_shndx != SHN_UNDEF as u16
+                    && self.string(sym.st_name) == name.as_bytes()
+                    && self.match_version(index, version.as_bytes(), ver_hash)
+                {
+                    return Some(
+                        (sym.st_value as usize).wrapping_add(self.load_offset) as *const c_void
+                    );
+                }
+                index = *self.chain.add(index as usize);
+            }
+        }
+        None
+    }
+
+    unsafe fn string(&self, offset: u32) -> &[u8] {
+        let start = self.strtab.add(offset as usize);
+        let mut len = 0;
+        while *start.add(len) != 0 {
+            len += 1;
+        }
+        slice::from_raw_parts(start, len)
+    }
+
+    unsafe fn match_version(&self, index: u32, version: &[u8], ver_hash: u32) -> bool {
+        if self.versym.is_null() {
+            return true;
+        }
+        // The top bit marks hidden symbols.
+        let ver = *self.versym.add(index as usize) & 0x7fff;
+        let mut def = self.verdef;
+        loop {
+            if (*def).vd_flags & VER_FLG_BASE == 0 && (*def).vd_ndx == ver {
+                break;
+            }
+            if (*def).vd_next == 0 {
+                return false;
+            }
+            def = (def as *const u8).add((*def).vd_next as usize) as *const Verdef;
+        }
+        let aux = &*((def as *const u8).add((*def).vd_aux as usize) as *const Verdaux);
+        (*def).vd_hash == ver_hash && self.string(aux.vda_name) == version
+    }
+}
+
+// The SysV ELF hash function.
+fn elf_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0u32, |h, &c| {
+        let h = (h << 4).wrapping_add(c as u32);
+        let g = h & 0xf000_0000;
+        (h ^ (g >> 24)) & !g
+    })
+}
+
+// Returns `getauxval(AT_SYSINFO_EHDR)`, from libc if it has `getauxval` or otherwise from `/proc/self/auxv`.
+fn sysinfo_ehdr() -> Option<usize> {
+    extern "C" {
+        #[linkage = "extern_weak"]
+        static getauxval: Option<unsafe extern "C" fn(c_ulong) -> c_ulong>;
+    }
+    if let Some(libc_getauxval) = unsafe { getauxval } {
+        let base = unsafe { libc_getauxval(AT_SYSINFO_EHDR as c_ulong) } as usize;
+        return if base == 0 { None } else { Some(base) };
+    }
+    let auxv = fs::read("/proc/self/auxv").ok()?;
+    auxv.chunks_exact(2 * size_of::<usize>())
+        .map(|entry| {
+            let (key, value) = entry.split_at(size_of::<usize>());
+            (
+                usize::from_ne_bytes(key.try_into().unwrap()),
+                usize::from_ne_bytes(value.try_into().unwrap()),
+            )
+        })
+        .find(|&(key, _)| key == AT_SYSINFO_EHDR as usize)
+        .map(|(_, value)| value)
+        .filter(|&value| value != 0)
+}
+
+// The signatures of `arch/x86/entry/vdso/vclock_gettime.c` and `vgetcpu.c`, errors are returned as `-errno`
+// (they fall back to the syscall). `clock_gettime` and `gettimeofday` return an `int`, so the upper half of
+// the return register is unspecified and the result has to be widened, `time` and `getcpu` return a `long`.
+pub(crate) type ClockGettime =
+    unsafe extern "C" fn(c_int, *mut linux_sys::__kernel_timespec) -> c_int;
+pub(crate) type Gettimeofday = unsafe extern "C" fn(*mut linux_sys::timeval, *mut c_void) -> c_int;
+pub(crate) type Time = unsafe extern "C" fn(*mut isize) -> isize;
+pub(crate) type Getcpu = unsafe extern "C" fn(*mut u32, *mut u32, *mut c_void) -> isize;
+
+/// The vDSO functions the crate uses, resolved once.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Functions {
+    pub(crate) clock_gettime: Option<ClockGettime>,
+    pub(crate) gettimeofday: Option<Gettimeofday>,
+    pub(crate) time: Option<Time>,
+    pub(crate) getcpu: Option<Getcpu>,
+}
+
+const VERSION: &str = "LINUX_2.6";
+// The i686 counterpart of `Syscalls::ClockGettime64`.
+#[cfg(target_arch = "x86")]
+const CLOCK_GETTIME: &str = "__vdso_clock_gettime64";
+#[cfg(not(target_arch = "x86"))]
+const CLOCK_GETTIME: &str = "__vdso_clock_gettime";
+
+pub(crate) fn functions() -> &'static Functions {
+    static FUNCTIONS: OnceLock<Functions> = OnceLock::new();
+    FUNCTIONS.get_or_init(|| {
+        let vdso = match Vdso::get() {
+            Some(vdso) => vdso,
+            None => return Functions::default(),
+        };
+        // The lookups return code addresses with exactly these signatures.
+        unsafe {
+            Functions {
+                clock_gettime: vdso
+                    .lookup(VERSION, CLOCK_GETTIME)
+                    .map(|f| std::mem::transmute::<*const c_void, ClockGettime>(f)),
+                gettimeofday: vdso
+                    .lookup(VERSION, "__vdso_gettimeofday")
+                    .map(|f| std::mem::transmute::<*const c_void, Gettimeofday>(f)),
+                // On i686 it returns a 32 bit `time_t`.
+                #[cfg(not(target_arch = "x86"))]
+                time: vdso
+                    .lookup(VERSION, "__vdso_time")
+                    .map(|f| std::mem::transmute::<*const c_void, Time>(f)),
+                #[cfg(target_arch = "x86")]
+                time: None,
+                getcpu: vdso
+                    .lookup(VERSION, "__vdso_getcpu")
+                    .map(|f| std::mem::transmute::<*const c_void, Getcpu>(f)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elf_hash() {
+        // From the System V ABI.
+        assert_eq!(elf_hash(b""), 0);
+        assert_eq!(elf_hash(b"printf"), 0x077905a6);
+        assert_eq!(elf_hash(b"LINUX_2.6"), 0x03ae75f6);
+    }
+
+    #[test]
+    fn test_vdso_lookup() {
+        let vdso = Vdso::get().expect("The kernel maps a vDSO by default");
+        assert!(vdso.lookup(VERSION, CLOCK_GETTIME).is_some());
+        assert!(vdso.lookup(VERSION, "__vdso_getcpu").is_some());
+        assert!(vdso.lookup("LINUX_1.0", CLOCK_GETTIME).is_none());
+        assert!(vdso.lookup(VERSION, "__vdso_does_not_exist").is_none());
+
+        let functions = functions();
+        assert!(functions.clock_gettime.is_some() && functions.gettimeofday.is_some());
+        assert!(functions.getcpu.is_some());
+
+        // The right magic but no class.
+        let mut image = [0u64; 16];
+        image[0] = u32::from_ne_bytes(*b"\x7fELF") as u64;
+        assert!(unsafe { Vdso::from_ptr(image.as_ptr() as *const u8) }.is_none());
+    }
+}